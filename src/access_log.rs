@@ -0,0 +1,162 @@
+//web server access log: Combined Log Format or JSON lines
+
+use rouille::Request;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(
+    Eq, PartialEq, Debug, strum::Display, strum::EnumString, Clone, Serialize, Deserialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Combined,
+    Json,
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+#[derive(Serialize)]
+pub struct AccessEntry<'a> {
+    time: String,
+    remote_addr: String,
+    user: Option<String>,
+    method: &'a str,
+    url: String,
+    route: &'a str,
+    status: u16,
+    bytes: Option<usize>,
+    duration_ms: f64,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+/// client address: first X-Forwarded-For entry if behind reverse proxy
+pub fn client_addr(req: &Request) -> String {
+    match req
+        .header("X-Forwarded-For")
+        .and_then(|x| x.split(',').next())
+        .map(|x| x.trim())
+    {
+        Some(x) if !x.is_empty() => x.to_string(),
+        _ => req.remote_addr().ip().to_string(),
+    }
+}
+
+impl<'a> AccessEntry<'a> {
+    pub fn new(
+        req: &'a Request,
+        route: &'a str,
+        status: u16,
+        bytes: Option<usize>,
+        duration: Duration,
+    ) -> Self {
+        AccessEntry {
+            time: chrono::Local::now().to_rfc3339(),
+            remote_addr: client_addr(req),
+            user: rouille::input::basic_http_auth(req).map(|x| x.login),
+            method: req.method(),
+            url: req.raw_url().to_string(),
+            route,
+            status,
+            bytes,
+            duration_ms: duration.as_secs_f64() * 1000.0,
+            referer: req.header("Referer"),
+            user_agent: req.header("User-Agent"),
+        }
+    }
+
+    /// Combined Log Format followed by route name and duration in milliseconds
+    pub fn to_combined(&self) -> String {
+        let time = chrono::DateTime::parse_from_rfc3339(&self.time)
+            .map(|t| t.format("%d/%b/%Y:%H:%M:%S %z").to_string())
+            .unwrap_or_else(|_| self.time.clone());
+        let bytes = match self.bytes {
+            Some(n) => n.to_string(),
+            None => "-".to_string(),
+        };
+        format!(
+            "{} - {} [{}] \"{} {} HTTP/1.1\" {} {} \"{}\" \"{}\" {} {:.3}",
+            self.remote_addr,
+            self.user.as_deref().unwrap_or("-"),
+            time,
+            self.method,
+            self.url,
+            self.status,
+            bytes,
+            self.referer.unwrap_or("-").replace('"', "\\\""),
+            self.user_agent.unwrap_or("-").replace('"', "\\\""),
+            self.route,
+            self.duration_ms,
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl AccessLog {
+    /// path "-" means stderr, otherwise append to file
+    pub fn open(path: &str, format: AccessLogFormat) -> Result<Self, String> {
+        let out: Box<dyn Write + Send> = if path == "-" {
+            Box::new(std::io::stderr())
+        } else {
+            let f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("{path}: {e}"))?;
+            Box::new(f)
+        };
+        Ok(AccessLog {
+            format,
+            out: Mutex::new(out),
+        })
+    }
+
+    pub fn write(&self, entry: &AccessEntry) {
+        let mut line = match self.format {
+            AccessLogFormat::Combined => entry.to_combined(),
+            AccessLogFormat::Json => entry.to_json(),
+        };
+        line.push('\n');
+        if let Ok(mut out) = self.out.lock() {
+            let _ = out.write_all(line.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_entry() {
+        let req = Request::fake_http(
+            "GET",
+            "/porcula/search?query=a",
+            vec![
+                ("X-Forwarded-For".into(), "10.0.0.1, 192.168.1.1".into()),
+                ("Authorization".into(), "Basic dXNlcjpwYXNz".into()), //user:pass
+                ("User-Agent".into(), "test \"agent\"".into()),
+            ],
+            vec![],
+        );
+        let e = AccessEntry::new(&req, "search", 200, Some(15), Duration::from_millis(3));
+        let s = e.to_combined();
+        assert!(s.starts_with("10.0.0.1 - user ["));
+        assert!(s.ends_with(
+            "] \"GET /porcula/search?query=a HTTP/1.1\" 200 15 \"-\" \"test \\\"agent\\\"\" search 3.000"
+        ));
+        let e = AccessEntry::new(&req, "search", 304, None, Duration::from_millis(3));
+        let s = e.to_json();
+        assert!(s.contains(r#""remote_addr":"10.0.0.1","user":"user","method":"GET","url":"/porcula/search?query=a","route":"search","status":304,"bytes":null,"#));
+    }
+}
@@ -108,6 +108,13 @@ lazy_static! {
                 content: include_bytes!("../static/defcover.png"),
             },
         );
+        m.insert(
+            "author-map.txt",
+            Asset {
+                content_type: "text/plain; charset=utf8",
+                content: include_bytes!("../static/author-map.txt"),
+            },
+        );
         m.insert(
             "genre-map.txt",
             Asset {
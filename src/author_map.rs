@@ -0,0 +1,283 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufRead;
+
+use crate::types::{Book, Person};
+
+#[derive(Debug, Default)]
+pub struct AuthorMap {
+    alias: HashMap<String, String>, //normalized name variant to canonical name
+}
+
+/// authors with similar last name not covered by author map
+#[derive(Debug, Serialize)]
+pub struct UnmatchedAuthors {
+    pub last_name: String,
+    pub books: usize,
+    pub names: Vec<AuthorCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthorCount {
+    pub name: String,
+    pub books: usize,
+    pub canonical: bool, //name is in author map
+}
+
+/// lowercase words of name, 'ё' as 'е', without punctuation
+fn normalize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'ё' | 'Ё' => 'е',
+            c if c.is_alphanumeric() => c,
+            _ => ' ',
+        })
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// key to group spellings and transliterations of last name: Latin consonants
+fn sound_key(last_name: &str) -> String {
+    let mut key: Vec<char> = crate::translit::transliterate(last_name)
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphabetic() && !"aeiouy".contains(*c))
+        .collect();
+    key.dedup();
+    key.into_iter().collect()
+}
+
+fn last_first_middle(p: &Person) -> String {
+    [&p.last_name, &p.first_name, &p.middle_name]
+        .iter()
+        .filter_map(|x| x.as_deref())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// "Last First" of longer name; last name of several words ends with comma
+fn short_name(name: &str) -> Option<String> {
+    let (last, rest) = match name.split_once(',') {
+        Some((last, rest)) => (normalize(last), normalize(rest)),
+        None => {
+            let name = normalize(name);
+            let (last, rest) = name.split_once(' ')?;
+            (last.to_string(), rest.to_string())
+        }
+    };
+    let (first, _middle) = rest.split_once(' ')?;
+    if last.is_empty() {
+        return None;
+    }
+    Some(format!("{last} {first}"))
+}
+
+/// "Last First Middle..." or "Last name, First Middle..."
+fn canonical_person(canonical: &str) -> Person {
+    let (last, rest) = match canonical.split_once(',') {
+        Some((last, rest)) => (last.trim(), rest),
+        None => canonical
+            .trim()
+            .split_once(char::is_whitespace)
+            .unwrap_or((canonical.trim(), "")),
+    };
+    let mut words = rest.split_whitespace();
+    let first = words.next().map(|x| x.to_string());
+    let middle = words.collect::<Vec<&str>>().join(" ");
+    Person {
+        last_name: Some(last.to_string()),
+        first_name: first,
+        middle_name: Some(middle).filter(|x| !x.is_empty()),
+        nick_name: None,
+    }
+}
+
+impl AuthorMap {
+    pub fn load(reader: &mut dyn BufRead) -> Result<Self, std::io::Error> {
+        let mut alias = HashMap::new();
+        let mut short = HashMap::<String, Option<String>>::new(); //None if short name is ambiguous
+        for line in reader.lines() {
+            let line = line?;
+            if line.starts_with('#') {
+                continue; //commented line
+            }
+            if let Some((canonical, variants)) = line.split_once('=') {
+                let canonical = canonical.trim();
+                if canonical.is_empty() {
+                    continue;
+                }
+                for name in std::iter::once(canonical).chain(variants.split('+')) {
+                    let key = normalize(name);
+                    if key.is_empty() {
+                        continue;
+                    }
+                    alias.insert(key, canonical.to_string());
+                    if let Some(key) = short_name(name) {
+                        short
+                            .entry(key)
+                            .and_modify(|x| {
+                                if x.as_deref() != Some(canonical) {
+                                    *x = None;
+                                }
+                            })
+                            .or_insert_with(|| Some(canonical.to_string()));
+                    }
+                }
+            }
+        }
+        //"Last First" matches canonical "Last First Middle" if not set explicitly and not shared by other names
+        for (key, canonical) in short {
+            if let Some(canonical) = canonical {
+                alias.entry(key).or_insert(canonical);
+            }
+        }
+        Ok(AuthorMap { alias })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alias.is_empty()
+    }
+
+    pub fn canonical(&self, name: &str) -> Option<&str> {
+        self.alias.get(&normalize(name)).map(|x| x.as_str())
+    }
+
+    /// person with canonical name, also for swapped first and last name
+    fn map_person(&self, p: &Person) -> Option<Person> {
+        let mut canonical = self.canonical(&last_first_middle(p));
+        if canonical.is_none() && p.first_name.is_some() && p.last_name.is_some() {
+            let swapped = Person {
+                first_name: p.last_name.clone(),
+                last_name: p.first_name.clone(),
+                middle_name: p.middle_name.clone(),
+                nick_name: None,
+            };
+            canonical = self.canonical(&last_first_middle(&swapped));
+        }
+        Some(Person {
+            nick_name: p.nick_name.clone(),
+            ..canonical_person(canonical?)
+        })
+    }
+
+    /// replace name variants of authors, source authors and translators with canonical names
+    pub fn apply(&self, book: &mut Book) {
+        if self.is_empty() {
+            return;
+        }
+        for persons in [&mut book.author, &mut book.src_author, &mut book.translator] {
+            for p in persons.iter_mut() {
+                if let Some(canonical) = self.map_person(p) {
+                    *p = canonical;
+                }
+            }
+        }
+    }
+
+    /// groups of indexed author names (with book counts) which look like spellings of one last name
+    pub fn unmatched(&self, names: &HashMap<String, usize>) -> Vec<UnmatchedAuthors> {
+        let mut groups = BTreeMap::<String, Vec<AuthorCount>>::new();
+        for (name, &books) in names {
+            let last_name = name.split_whitespace().next().unwrap_or_default();
+            let key = sound_key(last_name);
+            if key.is_empty() {
+                continue;
+            }
+            groups.entry(key).or_default().push(AuthorCount {
+                name: name.clone(),
+                books,
+                canonical: self
+                    .canonical(name)
+                    .map(|x| canonical_person(x).to_string())
+                    == Some(name.clone()),
+            });
+        }
+        let mut res = vec![];
+        for (_, mut names) in groups {
+            if names.len() < 2 || names.iter().all(|x| x.canonical) {
+                continue;
+            }
+            names.sort_by(|a, b| b.books.cmp(&a.books).then(a.name.cmp(&b.name)));
+            res.push(UnmatchedAuthors {
+                last_name: names[0]
+                    .name
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .into(),
+                books: names.iter().map(|x| x.books).sum(),
+                names,
+            });
+        }
+        res.sort_by(|a, b| b.books.cmp(&a.books).then(a.last_name.cmp(&b.last_name)));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn person(last: &str, first: &str) -> Person {
+        Person {
+            last_name: Some(last.into()),
+            first_name: Some(first.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_author_map() {
+        let map = "# comment\nСтругацкий Аркадий Натанович=Strugatsky Arkady+Стругацкий А.Н.\n";
+        let map = AuthorMap::load(&mut map.as_bytes()).unwrap();
+        for p in [
+            person("Стругацкий", "Аркадий"),
+            person("Strugatsky", "Arkady"),
+            person("Аркадий", "Стругацкий"),
+            person("стругацкий", "а. н."),
+        ] {
+            let p = map.map_person(&p).unwrap();
+            assert_eq!(p.to_string(), "Стругацкий Аркадий Натанович");
+        }
+        assert!(map.map_person(&person("Стругацкий", "Борис")).is_none());
+
+        let names: HashMap<String, usize> = [
+            ("Стругацкий Аркадий Натанович", 10),
+            ("Стругацкий Борис", 3),
+            ("Strugatsky Boris", 2),
+            ("Иванов Иван", 1),
+        ]
+        .iter()
+        .map(|(n, c)| (n.to_string(), *c))
+        .collect();
+        let res = map.unmatched(&names);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].last_name, "Стругацкий");
+        assert_eq!(res[0].books, 15);
+        assert_eq!(res[0].names[2].name, "Strugatsky Boris");
+        assert!(res[0].names[0].canonical && !res[0].names[1].canonical);
+    }
+
+    #[test]
+    fn test_long_names() {
+        //last name of several words, more than three words, "Last First" of two names
+        let map = "Гарсиа Маркес, Габриэль Хосе=Garcia Marquez Gabriel\n\
+            Толкин Джон Рональд Руэл=Tolkien J.R.R.\n\
+            Иванов Иван Иванович=\nИванов Иван Петрович=\n";
+        let map = AuthorMap::load(&mut map.as_bytes()).unwrap();
+        for p in [
+            person("Гарсиа Маркес", "Габриэль"),
+            person("Garcia Marquez", "Gabriel"),
+        ] {
+            let p = map.map_person(&p).unwrap();
+            assert_eq!(p.last_name.as_deref(), Some("Гарсиа Маркес"));
+            assert_eq!(p.middle_name.as_deref(), Some("Хосе"));
+        }
+        let p = map.map_person(&person("Толкин", "Джон")).unwrap();
+        assert_eq!(p.to_string(), "Толкин Джон Рональд Руэл");
+        assert!(map.map_person(&person("Иванов", "Иван")).is_none());
+    }
+}
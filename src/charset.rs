@@ -0,0 +1,73 @@
+//guess of single-byte Cyrillic encoding for archive entry names and book text
+
+use encoding_rs::{Encoding, IBM866, KOI8_R, WINDOWS_1251};
+
+const CANDIDATES: [&Encoding; 3] = [WINDOWS_1251, IBM866, KOI8_R];
+
+//letters of Russian text by frequency
+const LETTERS: &str = "оеаинтсрвлкмдпуяыьгзбчйхжшюцщэфъё";
+const FREQ: [f64; 33] = [
+    10.97, 8.45, 8.01, 7.35, 6.70, 6.26, 5.47, 4.73, 4.54, 4.40, 3.49, 3.21, 2.98, 2.81, 2.62,
+    2.01, 1.90, 1.74, 1.70, 1.65, 1.59, 1.44, 1.21, 0.97, 0.94, 0.73, 0.64, 0.48, 0.36, 0.32, 0.26,
+    0.04, 0.04,
+];
+const MIN_LETTERS_PCT: usize = 90; //of non-ASCII chars, box drawing and symbols are rare in names and books
+
+/// average frequency of decoded letters, uppercase letters weigh less; None if text does not look Russian
+fn score(encoding: &'static Encoding, raw: &[u8]) -> Option<f64> {
+    let (text, malformed) = encoding.decode_without_bom_handling(raw);
+    if malformed {
+        return None;
+    }
+    let mut total = 0;
+    let mut letters = 0;
+    let mut weight = 0.0;
+    for c in text.chars().filter(|c| !c.is_ascii()) {
+        total += 1;
+        let lower = c.to_lowercase().next().unwrap_or(c);
+        if let Some(i) = LETTERS.chars().position(|x| x == lower) {
+            letters += 1;
+            weight += FREQ[i] * if c == lower { 1.0 } else { 0.5 };
+        }
+    }
+    if total == 0 || letters * 100 < total * MIN_LETTERS_PCT {
+        return None;
+    }
+    Some(weight / total as f64)
+}
+
+/// CP1251, CP866 or KOI8-R, whichever gives most plausible Russian text
+pub fn guess_cyrillic(raw: &[u8]) -> Option<&'static Encoding> {
+    let mut res = None;
+    let mut best = 0.0;
+    for encoding in CANDIDATES {
+        if let Some(s) = score(encoding, raw) {
+            if s > best {
+                best = s;
+                res = Some(encoding);
+            }
+        }
+    }
+    res
+}
+
+#[test]
+fn test_guess_cyrillic() {
+    for text in [
+        "Толстой Лев - Война и мир.fb2",
+        "Чехов",
+        "пьеса",
+        "Ёжик в тумане",
+    ] {
+        for encoding in CANDIDATES {
+            let (raw, _, _) = encoding.encode(text);
+            assert_eq!(
+                guess_cyrillic(&raw).map(|x| x.name()),
+                Some(encoding.name()),
+                "{text}"
+            );
+        }
+    }
+    assert_eq!(guess_cyrillic(b"ascii.fb2"), None);
+    assert_eq!(guess_cyrillic("Café".as_bytes()), None);
+}
@@ -0,0 +1,327 @@
+//porcula.toml: settings for all commands, command line options override them
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::cmd::*;
+use crate::tr;
+
+pub const CONFIG_FILE: &str = "porcula.toml";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub index_dir: String,
+    pub books_dir: String,
+    pub index: IndexConfig,
+    pub server: ServerConfig,
+    pub pages: PageConfig,
+    pub cover: CoverConfig,
+    #[serde(skip)]
+    pub source: Option<PathBuf>, //loaded file
+}
+
+/// indexing options, applied only by `index` command like its command line options
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexConfig {
+    pub lang: Vec<String>,
+    pub stemmer: Option<String>,
+    pub body: Option<bool>,
+    pub xbody: Option<bool>,
+    pub annotation: Option<bool>,
+    pub cover: Option<bool>,
+    pub memory_size: Option<usize>, //MB
+    pub index_threads: Option<usize>,
+    pub read_threads: usize,
+    pub read_queue: usize,
+    pub batch_size: Option<usize>, //MB
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    pub base_url: String,
+    pub compression: bool,
+    pub compression_min_size: usize,
+    pub access_log: Option<String>,
+    pub access_log_format: AccessLogFormat,
+    pub auth: Option<AuthConfig>,
+    pub watch: Option<u64>,        //seconds between books dir checks
+    pub admin: Option<AuthConfig>, //enables /admin/ endpoints
+}
+
+/// HTTP basic authentication for all requests
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PageConfig {
+    pub query_hits: usize,
+    pub opds_entries: usize,
+    pub find_hits: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoverConfig {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            index_dir: DEFAULT_INDEX_DIR.into(),
+            books_dir: DEFAULT_BOOKS_DIR.into(),
+            index: IndexConfig::default(),
+            server: ServerConfig::default(),
+            pages: PageConfig::default(),
+            cover: CoverConfig::default(),
+            source: None,
+        }
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            lang: vec![],
+            stemmer: None,
+            body: None,
+            xbody: None,
+            annotation: None,
+            cover: None,
+            memory_size: None,
+            index_threads: None,
+            read_threads: DEFAULT_READ_THREADS,
+            read_queue: DEFAULT_READ_QUEUE,
+            batch_size: None,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: DEFAULT_LISTEN_ADDR.into(),
+            base_url: DEFAULT_BASE_URL.into(),
+            compression: true,
+            compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            access_log: None,
+            access_log_format: AccessLogFormat::Combined,
+            auth: None,
+            watch: None,
+            admin: None,
+        }
+    }
+}
+
+impl Default for PageConfig {
+    fn default() -> Self {
+        PageConfig {
+            query_hits: DEFAULT_QUERY_HITS,
+            opds_entries: DEFAULT_OPDS_ENTRIES,
+            find_hits: DEFAULT_FIND_HITS,
+        }
+    }
+}
+
+impl Default for CoverConfig {
+    fn default() -> Self {
+        CoverConfig {
+            width: COVER_IMAGE_WIDTH,
+            height: COVER_IMAGE_HEIGHT,
+        }
+    }
+}
+
+/// user config directory: $XDG_CONFIG_HOME/porcula, ~/.config/porcula or %APPDATA%\porcula
+fn user_config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(x) if !x.is_empty() => PathBuf::from(x),
+        _ => {
+            if cfg!(target_os = "windows") {
+                PathBuf::from(std::env::var_os("APPDATA")?)
+            } else {
+                Path::new(&std::env::var_os("HOME")?).join(".config")
+            }
+        }
+    };
+    Some(base.join(env!("CARGO_PKG_NAME")))
+}
+
+impl Config {
+    /// explicit --config file, or first found in working dir and user config dir, or defaults
+    pub fn load(args: &AppArgs) -> Result<Self, String> {
+        let path = match &args.config {
+            Some(x) => Some(PathBuf::from(x)),
+            None => vec![
+                Some(PathBuf::from(CONFIG_FILE)),
+                user_config_dir().map(|x| x.join(CONFIG_FILE)),
+            ]
+            .into_iter()
+            .flatten()
+            .find(|x| x.is_file()),
+        };
+        let mut res = match path {
+            Some(path) => {
+                debug!(
+                    "{} {}",
+                    tr!["Reading configuration", "Читаем конфигурацию"],
+                    path.display()
+                );
+                let mut c = Self::parse(
+                    &std::fs::read_to_string(&path)
+                        .map_err(|e| format!("{}: {e}", path.display()))?,
+                )
+                .map_err(|e| format!("{}: {e}", path.display()))?;
+                c.source = Some(path);
+                c
+            }
+            None => Config::default(),
+        };
+        res.apply_args(args);
+        res.validate()?;
+        Ok(res)
+    }
+
+    /// values which would stop indexing
+    fn validate(&self) -> Result<(), String> {
+        let c = &self.index;
+        for (name, value) in [
+            ("read_threads", c.read_threads),
+            ("read_queue", c.read_queue),
+        ] {
+            if value == 0 {
+                return Err(format!(
+                    "[index] {name}: {}",
+                    tr!["must be at least 1", "должно быть не меньше 1"]
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    /// command line options take precedence over file
+    fn apply_args(&mut self, args: &AppArgs) {
+        if let Some(x) = &args.index_dir {
+            self.index_dir.clone_from(x);
+        }
+        if let Some(x) = &args.books_dir {
+            self.books_dir.clone_from(x);
+        }
+        match &args.command {
+            Some(Command::Index(a)) => {
+                let c = &mut self.index;
+                if !a.lang.is_empty() {
+                    c.lang.clone_from(&a.lang);
+                }
+                if a.stemmer.is_some() {
+                    c.stemmer.clone_from(&a.stemmer);
+                }
+                let on = |x: &Option<OnOff>| x.as_ref().map(|x| *x == OnOff::On);
+                c.body = on(&a.body).or(c.body);
+                c.xbody = on(&a.xbody).or(c.xbody);
+                c.annotation = on(&a.annotation).or(c.annotation);
+                c.cover = on(&a.cover).or(c.cover);
+                c.memory_size = a.memory_size.or(c.memory_size);
+                c.index_threads = a.index_threads.or(c.index_threads);
+                c.read_threads = a.read_threads.unwrap_or(c.read_threads);
+                c.read_queue = a.read_queue.unwrap_or(c.read_queue);
+                c.batch_size = a.batch_size.or(c.batch_size);
+            }
+            Some(Command::Server(a)) => {
+                let c = &mut self.server;
+                if let Some(x) = &a.listen {
+                    c.listen.clone_from(x);
+                }
+                if a.no_compression {
+                    c.compression = false;
+                }
+                c.compression_min_size = a.compression_min_size.unwrap_or(c.compression_min_size);
+                if a.access_log.is_some() {
+                    c.access_log.clone_from(&a.access_log);
+                }
+                if let Some(x) = &a.access_log_format {
+                    c.access_log_format = x.clone();
+                }
+                c.watch = a.watch.or(c.watch);
+            }
+            Some(Command::Query(QueryArgs { hits: Some(x), .. }))
+            | Some(Command::Facet(FacetArgs { hits: Some(x), .. })) => {
+                self.pages.query_hits = *x;
+            }
+            _ => (),
+        }
+    }
+
+    /// effective configuration as TOML, without passwords
+    pub fn show(&self) -> String {
+        let mut c = self.clone();
+        for auth in c.server.auth.iter_mut().chain(c.server.admin.iter_mut()) {
+            auth.password = "********".into();
+        }
+        let source = match &self.source {
+            Some(x) => x.display().to_string(),
+            None => tr!["defaults", "по умолчанию"].to_string(),
+        };
+        format!(
+            "# {}: {source}\n{}",
+            tr!["Configuration", "Конфигурация"],
+            toml::to_string_pretty(&c).unwrap_or_default()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let c = Config::parse(
+            r#"
+books_dir = "/lib"
+[index]
+lang = ["ru", "en"]
+body = false
+[server]
+listen = "0.0.0.0:80"
+auth = { user = "u", password = "p" }
+[pages]
+opds_entries = 50
+"#,
+        )
+        .unwrap();
+        assert_eq!(c.index_dir, DEFAULT_INDEX_DIR);
+        assert_eq!(c.books_dir, "/lib");
+        assert_eq!(c.index.body, Some(false));
+        assert_eq!(c.index.xbody, None);
+        assert_eq!(c.server.base_url, DEFAULT_BASE_URL);
+        assert_eq!(c.pages.opds_entries, 50);
+        assert_eq!(c.pages.query_hits, DEFAULT_QUERY_HITS);
+        assert!(c.show().contains("password = \"********\""));
+        assert!(Config::parse("[server]\nlisten_addr = \"x\"").is_err());
+        assert!(c.validate().is_ok());
+        let zero = Config::parse("[index]\nread_threads = 0").unwrap();
+        assert!(zero.validate().is_err());
+
+        let args = AppArgs::parse_from(["porcula", "-b", "/books", "server", "--no-compression"]);
+        let mut c = c;
+        c.apply_args(&args);
+        assert_eq!(c.books_dir, "/books");
+        assert_eq!(c.server.listen, "0.0.0.0:80");
+        assert!(!c.server.compression);
+    }
+}
@@ -5,7 +5,13 @@ pub fn run_facet(args: &FacetArgs, app: Application) -> ProcessResult {
         Ok(x) => x,
         Err(e) => return ProcessResult::IndexError(e),
     };
-    match fts.get_facet(&args.path, None, false, false, Some(args.hits)) {
+    match fts.get_facet(
+        &args.path,
+        None,
+        false,
+        false,
+        Some(app.config.pages.query_hits),
+    ) {
         Ok(res) => {
             println!("{}", serde_json::to_string(&res).unwrap());
             ProcessResult::Ok
@@ -1,20 +1,26 @@
-use encoding_rs::Encoding;
-use log::{debug, error, info};
-use rayon::prelude::*;
+use log::{debug, error, info, warn};
 use regex::Regex;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fs::DirEntry;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
 
+use super::progress::{ArchiveCounters, BookIssue, IssueKind, Progress, SizeCounters};
 use crate::cmd::*;
+use crate::container::{self, Container, ContainerKind};
 use crate::fts::{IndexListDetails, IndexedFiles};
 use crate::tr;
-use crate::types::Book;
+use crate::types::{
+    Book, WARNING_ENCODING_DETECTED, WARNING_ENCODING_FALLBACK, WARNING_MALFORMED_CHARS,
+};
 
 type LangStats = BTreeMap<String, usize>;
 
+const MIN_PART_SIZE: usize = 500; //archive entries, smaller archives are read by single reader
+const READER_PAUSE: Duration = Duration::from_millis(50);
+
 #[derive(Default, Clone)]
 struct ProcessStats {
     error_count: usize,
@@ -74,39 +80,264 @@ struct ParsedBook {
     state: BookState,
     zipfile: String,
     filename: String,
-    warning_count: usize,
+    issues: Vec<BookIssue>,
     parsed_size: usize,
     time_to_parse: Duration,
     time_to_image: Duration,
     lang: String,
 }
 
+/// archive before it is opened
+struct ArchiveJob {
+    zip_index: usize,
+    zipfile: String,
+    path: PathBuf,
+    progress_pct: u64,
+}
+
+enum Job {
+    Open(ArchiveJob),
+    Read(ReadJob),
+}
+
+/// archives in order, parts of opened archive are read before next archives
+struct JobQueue {
+    jobs: Mutex<VecDeque<Job>>,
+    opening: AtomicUsize, //archives taken but not split yet
+}
+
+impl JobQueue {
+    fn new(archives: VecDeque<Job>) -> Self {
+        JobQueue {
+            jobs: Mutex::new(archives),
+            opening: AtomicUsize::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn next(&self) -> Option<Job> {
+        let mut jobs = self.lock();
+        let job = jobs.pop_front();
+        if let Some(Job::Open(_)) = job {
+            self.opening.fetch_add(1, Ordering::SeqCst);
+        }
+        job
+    }
+
+    /// remaining parts of archive taken by next(), none if archive is not opened
+    fn opened(&self, parts: Vec<ReadJob>) {
+        let mut jobs = self.lock();
+        for part in parts.into_iter().rev() {
+            jobs.push_front(Job::Read(part));
+        }
+        self.opening.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn is_done(&self) -> bool {
+        let jobs = self.lock();
+        jobs.is_empty() && self.opening.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// big archives with random access are read by several readers
+fn split_archive(
+    archive: ArchiveJob,
+    file_count: usize,
+    kind: ContainerKind,
+    read_threads: usize,
+) -> Vec<ReadJob> {
+    let part_size = match kind.random_access() {
+        true => (file_count / read_threads).max(MIN_PART_SIZE),
+        false => file_count.max(1), //sequential decompression
+    };
+    let progress = Arc::new(ArchiveProgress {
+        parts_left: AtomicUsize::new(file_count.div_ceil(part_size).max(1)),
+        file_count,
+        failed: AtomicBool::new(false),
+    });
+    let mut res = vec![];
+    let mut first = 0;
+    loop {
+        let last = (first + part_size).min(file_count);
+        res.push(ReadJob {
+            zip_index: archive.zip_index,
+            zipfile: archive.zipfile.clone(),
+            path: archive.path.clone(),
+            progress_pct: archive.progress_pct,
+            first,
+            last,
+            archive: progress.clone(),
+        });
+        if last >= file_count {
+            break;
+        }
+        first = last;
+    }
+    res
+}
+
+/// range of archive entries for single reader
+struct ReadJob {
+    zip_index: usize,
+    zipfile: String,
+    path: PathBuf,
+    progress_pct: u64,
+    first: usize,
+    last: usize,
+    archive: Arc<ArchiveProgress>,
+}
+
+struct ArchiveProgress {
+    parts_left: AtomicUsize, //WHOLE marker is sent by reader of last part
+    file_count: usize,
+    failed: AtomicBool, //read error, archive is not marked as WHOLE
+}
+
+/// number of active readers adapted to book queue backlog:
+/// full queue - indexing is slower than reading, empty queue - reading is slower
+struct ReaderControl {
+    active: AtomicUsize,
+    max: usize,
+}
+
+impl ReaderControl {
+    fn new(max: usize) -> Self {
+        ReaderControl {
+            active: AtomicUsize::new(max),
+            max,
+        }
+    }
+
+    fn is_active(&self, reader_id: usize) -> bool {
+        reader_id < self.active.load(Ordering::Relaxed)
+    }
+
+    fn observe(&self, backlog: usize, capacity: usize) {
+        let active = self.active.load(Ordering::Relaxed);
+        let target = if backlog * 4 >= capacity * 3 {
+            active.saturating_sub(1).max(1)
+        } else if backlog * 4 <= capacity {
+            (active + 1).min(self.max)
+        } else {
+            active
+        };
+        if target != active
+            && self
+                .active
+                .compare_exchange(active, target, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            debug!("active readers: {target}, queue: {backlog}/{capacity}");
+        }
+    }
+}
+
 #[derive(Default)]
 struct CommitStats {
     commits_count: usize,
     book_indexed: usize,
     error_count: usize,
     time_to_commit: Duration,
+    failure: Option<String>, //index is not written, reading is stopped
 }
 
-#[allow(clippy::cognitive_complexity)]
 pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
+    //exit nicely if user press Ctrl+C
+    let canceled = Arc::new(AtomicBool::new(false));
+    let c = canceled.clone();
+    ctrlc::set_handler(move || {
+        info!("Cancel indexing...");
+        c.store(true, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+    index_books(args, &app, canceled)
+}
+
+/// indexing pipeline, also used by server in watch mode
+#[allow(clippy::cognitive_complexity)]
+pub(super) fn index_books(
+    args: &IndexArgs,
+    app: &Application,
+    canceled: Arc<AtomicBool>,
+) -> ProcessResult {
+    let _lock = app.lock_writer();
     let debug = log::log_enabled!(log::Level::Debug);
     let delta = args.mode == IndexMode::Delta;
+    let mut settings = app.index_settings.clone();
+    if args.resume {
+        if delta {
+            return ProcessResult::ConfigError(
+                tr![
+                    "--resume is used only with 'index full'",
+                    "--resume используется только с 'index full'"
+                ]
+                .to_string(),
+            );
+        }
+        match &settings.full_run {
+            Some(run) => info!(
+                "{} {}: {} {}, {}: {}",
+                tr!["Resume full indexing", "Продолжаем полную индексацию"],
+                run.id,
+                run.archives_done,
+                tr!["archives done", "архивов готово"],
+                tr!["last", "последний"],
+                run.last_archive.as_deref().unwrap_or("-"),
+            ),
+            None => {
+                return ProcessResult::IndexError(
+                    tr![
+                        "No interrupted full indexing to resume",
+                        "Нет прерванной полной индексации"
+                    ]
+                    .to_string(),
+                )
+            }
+        }
+    } else if delta {
+        if let Some(run) = &settings.full_run {
+            info!(
+                "{} {}, {}",
+                tr![
+                    "Full indexing was interrupted",
+                    "Полная индексация была прервана"
+                ],
+                run.id,
+                tr![
+                    "use 'index full --resume' to continue it",
+                    "для продолжения используйте 'index full --resume'"
+                ]
+            );
+        }
+    } else if args.file.is_empty() {
+        settings.full_run = Some(FullRun::start());
+    }
+    let skip_indexed = delta || args.resume;
     let mem = {
         use systemstat::{Platform, System};
         let sys = System::new();
-        sys.memory().unwrap()
+        match sys.memory() {
+            Ok(x) => x,
+            Err(e) => {
+                return ProcessResult::IndexError(format!(
+                    "{}: {e}",
+                    tr!["Memory size is unknown", "Размер памяти неизвестен"]
+                ))
+            }
+        }
     };
     info!("Memory total: {}, free: {}", mem.total, mem.free);
-    let memory_size = match args.memory_size {
+    let memory_size = match app.config.index.memory_size {
         Some(x) => x * 1024 * 1024, //MB->bytes
         None => {
             debug!("using 1/4 of free memory as heap");
             (mem.free.0 as usize) / 4
         }
     };
-    let batch_size = match args.batch_size {
+    let batch_size = match app.config.index.batch_size {
         Some(x) => x * 1024 * 1024, //MB->bytes
         None => {
             debug!("using 1/4 of free memory as batch size");
@@ -118,6 +349,10 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
         Ok(x) => x,
         Err(e) => return ProcessResult::ConfigError(e),
     };
+    let author_map = match app.load_author_map() {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::ConfigError(e),
+    };
     let book_formats = &app.book_formats;
 
     let mut lang_set = HashSet::<String>::new();
@@ -144,42 +379,99 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
     );
     debug!(
         "read threads={} read queue={} index threads={:?} heap={} batch={}",
-        args.read_threads, args.read_queue, args.index_threads, memory_size, batch_size,
-    );
-    //save settings with index
-    debug!("store settings in {}", app.index_path.display());
-    match app.index_settings.save(&app.index_path) {
-        Ok(_) => (),
-        Err(e) => return ProcessResult::IndexError(e),
-    }
-    //open index
-    let mut book_writer = crate::fts::BookWriter::new(
-        &app.index_path,
-        &app.index_settings.stemmer,
-        args.index_threads,
+        app.config.index.read_threads,
+        app.config.index.read_queue,
+        app.config.index.index_threads,
         memory_size,
-    )
-    .unwrap();
-    debug!("merge policy: {}", book_writer.debug_merge_policy());
+        batch_size,
+    );
+    //dry run: parse all books, don't touch index
+    let mut book_writer = if args.dry_run {
+        info!(
+            "{}",
+            tr![
+                "Dry run, index is not changed",
+                "Пробный запуск, индекс не изменяется"
+            ]
+        );
+        None
+    } else {
+        //save settings with index
+        debug!("store settings in {}", app.index_path.display());
+        match settings.save(&app.index_path) {
+            Ok(_) => (),
+            Err(e) => return ProcessResult::IndexError(e),
+        }
+        //open index
+        let book_writer = match crate::fts::BookWriter::new(
+            &app.index_path,
+            &app.index_settings.stemmer,
+            app.config.index.index_threads,
+            memory_size,
+        ) {
+            Ok(x) => x,
+            Err(e) => return ProcessResult::IndexError(e.to_string()),
+        };
+        debug!("merge policy: {}", book_writer.debug_merge_policy());
+        Some(book_writer)
+    };
 
     //enforce reindex of books inside specified files
-    let indexed_books = match args.file.is_empty() && delta {
+    let indexed_books = match args.file.is_empty() && skip_indexed && !args.dry_run {
         true => {
             debug!("loading list of indexed files");
-            let book_reader = app.open_book_reader().unwrap();
-            Some(
-                book_reader
-                    .get_indexed_books(IndexListDetails::Compact)
-                    .unwrap(),
-            ) //read indexed file names as two-level hash: zipfile->{filenames}
+            let book_reader = match app.open_book_reader() {
+                Ok(x) => x,
+                Err(e) => return ProcessResult::IndexError(e),
+            };
+            //read indexed file names as two-level hash: zipfile->{filenames}
+            let mut books = match book_reader.get_indexed_books(IndexListDetails::Compact) {
+                Ok(x) => x,
+                Err(e) => return ProcessResult::IndexError(e.to_string()),
+            };
+            //nested archive is indexed by name of inner book, add its own name to skip it
+            for files in books.values_mut() {
+                if let IndexedFiles::List(files) = files {
+                    let nested: Vec<String> = files
+                        .iter()
+                        .filter_map(|x| container::split_nested(x))
+                        .map(|(outer, _)| outer.to_string())
+                        .collect();
+                    files.extend(nested);
+                }
+            }
+            Some(books)
         }
         false => None,
     };
 
-    let mut zip_files: Vec<DirEntry> = std::fs::read_dir(&app.books_path)
-        .expect("directory not readable")
-        .map(|x| x.expect("invalid file"))
-        .filter(is_zip_file)
+    let dir = match std::fs::read_dir(&app.books_path) {
+        Ok(x) => x,
+        Err(e) => {
+            return ProcessResult::IndexError(format!(
+                "{} {}: {e}",
+                tr!["Error reading directory", "Ошибка чтения каталога"],
+                app.books_path.display()
+            ))
+        }
+    };
+    let mut zip_files: Vec<DirEntry> = dir
+        .filter_map(|x| {
+            x.map_err(|e| error!("{}: {e}", app.books_path.display()))
+                .ok()
+        })
+        .filter(|x| match x.file_name().to_str() {
+            Some(_) => true,
+            None => {
+                error!(
+                    "{}: {}",
+                    tr!["invalid file name", "недопустимое имя файла"],
+                    x.file_name().to_string_lossy()
+                );
+                false
+            }
+        })
+        .filter(is_archive_file)
         .filter(|x| {
             args.file.is_empty()
                 || args
@@ -193,22 +485,71 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
         acc + entry.metadata().map(|m| m.len()).unwrap_or(0)
     });
 
-    if !delta {
-        info!("{}", tr!["deleting index...", "очищаем индекс..."]);
-        book_writer.delete_all_books().unwrap();
+    if let Some(book_writer) = &mut book_writer {
+        if !skip_indexed {
+            info!("{}", tr!["deleting index...", "очищаем индекс..."]);
+            if let Err(e) = book_writer.delete_all_books() {
+                return ProcessResult::IndexError(e.to_string());
+            }
+        } else if !args.file.is_empty() {
+            //previous version of reindexed archives
+            for entry in &zip_files {
+                let zipfile = entry.file_name().to_string_lossy().to_string();
+                debug!("delete {zipfile} from index");
+                if let Err(e) = book_writer.delete_zipfile(&zipfile) {
+                    return ProcessResult::IndexError(e.to_string());
+                }
+            }
+        }
     }
 
-    //exit nicely if user press Ctrl+C
-    let canceled = Arc::new(AtomicBool::new(false));
-    let c = canceled.clone();
-    ctrlc::set_handler(move || {
-        info!("Cancel indexing...");
-        c.store(true, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
+    let read_queue = app.config.index.read_queue;
+    let (send_book, recv_book) = crossbeam_channel::bounded::<ParsedBook>(read_queue);
 
-    let (send_book, recv_book) = crossbeam_channel::bounded::<ParsedBook>(args.read_queue);
+    //archives are opened and split into read jobs by readers, not all before reading
+    let mut archives = VecDeque::new();
+    let mut zip_processed = 0;
+    let mut zip_skipped = 0;
+    let mut zip_progress_size = 0;
+    let mut bytes_to_process = 0;
+    for (zip_index, entry) in zip_files.iter().enumerate() {
+        let zip_size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        let zip_progress_pct = zip_progress_size * 100 / zip_total_size.max(1); //all archives can be empty
+        zip_progress_size += zip_size;
+        let zipfile = entry.file_name().to_string_lossy().to_string(); //non-UTF-8 names are filtered out
+        if let Some(indexed) = &indexed_books {
+            if let Some(IndexedFiles::Whole) = indexed.get(&zipfile) {
+                info!(
+                    "[{}/{}] {} {}",
+                    zip_index + 1,
+                    zip_total_count,
+                    tr!["skip archive", "пропускаем архив"],
+                    &zipfile
+                );
+                zip_skipped += 1;
+                continue;
+            }
+        }
+        zip_processed += 1;
+        bytes_to_process += zip_size;
+        archives.push_back(Job::Open(ArchiveJob {
+            zip_index,
+            zipfile,
+            path: entry.path(),
+            progress_pct: zip_progress_pct,
+        }));
+    }
+    let jobs = JobQueue::new(archives);
+    let zip_failed = AtomicUsize::new(0); //not opened
+    let archive_failed = AtomicBool::new(false); //not opened or read error
 
+    let control = ReaderControl::new(app.config.index.read_threads);
+    let progress = Progress::new(
+        args.progress == ProgressFormat::Json,
+        args.report.is_some(),
+        bytes_to_process,
+    );
+    progress.start(zip_processed);
     //single commit-thread
     crossbeam_utils::thread::scope(|scope| {
         let tt = Instant::now();
@@ -216,9 +557,43 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
         let commit_canceled = canceled.clone();
         let opts_body = opts.body;
         let opts_xbody = opts.xbody;
+        let index_path = &app.index_path;
+        let all_files = args.file.is_empty();
+        let archive_failed = &archive_failed;
+        let progress = &progress;
         let commit_thread = scope.spawn(move |_| {
             let mut stats = CommitStats::default();
+            let mut book_writer = match book_writer {
+                Some(x) => x,
+                None => {
+                    //dry run: count books which would be indexed
+                    for entry in recv_book.iter() {
+                        if let BookState::Valid(_) = entry.state {
+                            stats.book_indexed += 1;
+                            progress.book_indexed();
+                        }
+                    }
+                    return stats;
+                }
+            };
             let mut uncommited_size = 0;
+            let mut uncommited_archives = vec![];
+            //record archives of full run which are safe in index
+            let mut save_progress = |archives: &mut Vec<String>, done: bool| {
+                if let Some(run) = &mut settings.full_run {
+                    run.archives_done += archives.len();
+                    if let Some(last) = archives.pop() {
+                        run.last_archive = Some(last);
+                    }
+                    archives.clear();
+                    if done {
+                        settings.full_run = None;
+                    }
+                    if let Err(e) = settings.save(index_path) {
+                        error!("{e}");
+                    }
+                }
+            };
             for entry in recv_book.iter() {
                 if commit_canceled.load(Ordering::SeqCst) {
                     break;
@@ -230,12 +605,14 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
                             &entry.filename,
                             *book,
                             &genre_map,
+                            &author_map,
                             opts_body,
                             opts_xbody,
                         ) {
                             Ok(_) => {
                                 stats.book_indexed += 1;
                                 uncommited_size += entry.parsed_size;
+                                progress.book_indexed();
                             }
                             Err(e) => {
                                 stats.error_count += 1;
@@ -246,14 +623,24 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
                                     tr!["indexing error", "ошибка индексации"],
                                     e
                                 );
+                                progress.issue(BookIssue {
+                                    archive: entry.zipfile,
+                                    file: entry.filename,
+                                    kind: IssueKind::Index,
+                                    message: e.to_string(),
+                                });
                                 //and continue
                             }
                         }
                     }
                     BookState::WholeZip => {
-                        book_writer
+                        if let Err(e) = book_writer
                             .mark_zipfile_as_indexed(&entry.zipfile, entry.parsed_size as u64)
-                            .unwrap();
+                        {
+                            stats.failure = Some(e.to_string());
+                            return stats;
+                        }
+                        uncommited_archives.push(entry.zipfile);
                     }
                     _ => (),
                 }
@@ -261,7 +648,11 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
                     uncommited_size = 0;
                     debug!("--------------Commit: start");
                     let ct = Instant::now();
-                    book_writer.commit().unwrap();
+                    if let Err(e) = book_writer.commit() {
+                        stats.failure = Some(e.to_string());
+                        return stats;
+                    }
+                    save_progress(&mut uncommited_archives, false);
                     stats.commits_count += 1;
                     stats.time_to_commit += ct.elapsed();
                     debug!("--------------Commit: done");
@@ -270,112 +661,143 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
             //final commit
             debug!("Final commit: start");
             let ct = Instant::now();
-            book_writer.commit().unwrap();
+            if let Err(e) = book_writer.commit() {
+                stats.failure = Some(e.to_string());
+                return stats;
+            }
+            //run is finished when all archives are processed, failed ones are left for resume
+            let failed = archive_failed.load(Ordering::SeqCst);
+            save_progress(
+                &mut uncommited_archives,
+                all_files && !failed && !commit_canceled.load(Ordering::SeqCst),
+            );
             stats.commits_count += 1;
             if !commit_canceled.load(Ordering::SeqCst) {
                 debug!("Waiting for merging threads");
-                book_writer.wait_merging_threads().unwrap();
+                if let Err(e) = book_writer.wait_merging_threads() {
+                    stats.failure = Some(e.to_string());
+                }
             }
             stats.time_to_commit += ct.elapsed();
             debug!("Final commit: done");
             stats
         });
 
-        let mut gstats = ProcessStats::default();
-        let mut zip_processed = 0;
-        let mut zip_skipped = 0;
-        let mut zip_progress_size = 0;
-        for (zip_index, entry) in zip_files.iter().enumerate() {
-            if canceled.load(Ordering::SeqCst) {
-                break;
-            }
-            let os_filename = &entry.file_name();
-            let zip_size = entry.metadata().map(|m| m.len()).unwrap_or(0);
-            let zip_progress_pct = zip_progress_size * 100 / zip_total_size;
-            let zipfile = os_filename.to_str().expect("invalid filename");
-            if let Some(indexed) = &indexed_books {
-                if let Some(IndexedFiles::Whole) = indexed.get(zipfile) {
-                    info!(
-                        "[{}/{}] {} {}",
-                        zip_index + 1,
-                        zip_total_count,
-                        tr!["skip archive", "пропускаем архив"],
-                        &zipfile
-                    );
-                    zip_skipped += 1;
-                    zip_progress_size += zip_size;
-                    continue;
-                }
-            }
-            zip_processed += 1;
-            zip_progress_size += zip_size;
-            info!(
-                "[{}/{}={}%] {} {}",
-                zip_index + 1,
-                zip_total_count,
-                zip_progress_pct,
-                tr!["read archive", "читаем архив"],
-                &zipfile
-            );
-            let reader = std::fs::File::open(entry.path()).unwrap();
-            let zip = zip::ZipArchive::new(reader).unwrap();
-            let file_count = zip.len();
-            let part_size = file_count / args.read_threads;
-            let partitions: Vec<(usize, usize)> = (0..args.read_threads)
-                .map(|i| {
-                    let first = i * part_size;
-                    let last = if i == args.read_threads - 1 {
-                        file_count
-                    } else {
-                        first + part_size
-                    };
-                    (first, last)
-                })
-                .collect();
-            let zip_stats: ProcessStats = partitions
-                .into_par_iter()
-                .map(|(first, last)| {
-                    let reader = std::fs::File::open(entry.path()).unwrap();
-                    let mut zip = zip::ZipArchive::new(reader).unwrap();
+        let readers: Vec<_> = (0..app.config.index.read_threads)
+            .map(|reader_id| {
+                let jobs = &jobs;
+                let zip_failed = &zip_failed;
+                let control = &control;
+                let canceled = &canceled;
+                let send_book = send_book.clone();
+                let indexed_books = &indexed_books;
+                let dry_run = args.dry_run;
+                let app = &app;
+                scope.spawn(move |_| {
                     let mut stats = ProcessStats::default();
                     if debug {
                         stats.langs = Some(LangStats::new());
                     }
-                    for i in first..last {
+                    let mut current: Option<(usize, Box<dyn Container>)> = None;
+                    let mut queue_closed = false; //commit thread has stopped
+                    loop {
                         if canceled.load(Ordering::SeqCst) {
                             break;
                         }
-                        let mut file = zip.by_index(i).unwrap();
-                        stats.book_total += 1;
-                        stats.packed_size += file.compressed_size() as usize;
-                        stats.unpacked_size += file.size() as usize;
-                        let filename: String = match decode_filename(file.name_raw()) {
-                            Some(s) => s,
-                            None => file.name().into(),
-                        };
-                        debug!("[{}%] {}/{}", zip_progress_pct, &zipfile, &filename);
-                        let mut process_book = true;
-                        if let Some(indexed) = &indexed_books {
-                            if let Some(IndexedFiles::List(files)) = indexed.get(zipfile) {
-                                if files.contains(&filename) {
-                                    info!("  {} {}", &filename, tr!["indexed", "индексирован"]);
-                                    stats.book_skipped += 1;
-                                    process_book = false;
+                        if !control.is_active(reader_id) {
+                            if jobs.is_done() {
+                                break;
+                            }
+                            std::thread::sleep(READER_PAUSE);
+                            continue;
+                        }
+                        let job = match jobs.next() {
+                            Some(Job::Read(x)) => x,
+                            //open archive and take its first part, other parts are for any reader
+                            Some(Job::Open(archive)) => match container::open(&archive.path) {
+                                Ok(c) => {
+                                    let mut parts = split_archive(
+                                        archive,
+                                        c.entry_count(),
+                                        c.kind(),
+                                        app.config.index.read_threads,
+                                    );
+                                    let first = parts.remove(0);
+                                    jobs.opened(parts);
+                                    current = Some((first.zip_index, c));
+                                    first
                                 }
+                                Err(e) => {
+                                    error!("{}: {e}", archive.zipfile);
+                                    zip_failed.fetch_add(1, Ordering::SeqCst);
+                                    archive_failed.store(true, Ordering::SeqCst);
+                                    jobs.opened(vec![]);
+                                    continue;
+                                }
+                            },
+                            None if jobs.is_done() => break,
+                            None => {
+                                std::thread::sleep(READER_PAUSE); //other reader is opening archive
+                                continue;
                             }
+                        };
+                        let zipfile = job.zipfile.as_str();
+                        if job.first == 0 {
+                            progress.archive_started(zipfile, job.zip_index + 1, zip_total_count);
+                            info!(
+                                "[{}/{}={}%] {} {}",
+                                job.zip_index + 1,
+                                zip_total_count,
+                                job.progress_pct,
+                                tr!["read archive", "читаем архив"],
+                                zipfile
+                            );
                         }
-                        if process_book {
+                        //reopen only when next job is from other archive
+                        let container = match &mut current {
+                            Some((i, c)) if *i == job.zip_index => c,
+                            _ => match container::open(&job.path) {
+                                Ok(c) => &mut current.insert((job.zip_index, c)).1,
+                                Err(e) => {
+                                    error!("{zipfile}: {e}");
+                                    job.archive.failed.store(true, Ordering::SeqCst);
+                                    archive_failed.store(true, Ordering::SeqCst);
+                                    continue;
+                                }
+                            },
+                        };
+                        let res = container.for_each(job.first..job.last, &mut |file, data| {
+                            if canceled.load(Ordering::SeqCst) {
+                                return Ok(false);
+                            }
+                            stats.book_total += 1;
+                            stats.packed_size += file.packed_size as usize;
+                            progress.add_bytes(file.packed_size);
+                            stats.unpacked_size += file.size as usize;
+                            let filename = &file.name;
+                            debug!("[{}%] {}/{}", job.progress_pct, zipfile, filename);
+                            if let Some(indexed) = indexed_books {
+                                if let Some(IndexedFiles::List(files)) = indexed.get(zipfile) {
+                                    if files.contains(filename) {
+                                        info!("  {} {}", filename, tr!["indexed", "индексирован"]);
+                                        stats.book_skipped += 1;
+                                        progress.book_skipped();
+                                        return Ok(true);
+                                    }
+                                }
+                            }
                             let zt = Instant::now();
-                            let mut data = Vec::with_capacity(file.size() as usize);
-                            file.read_to_end(&mut data).unwrap();
+                            let mut buf = Vec::with_capacity(file.size as usize);
+                            data.read_to_end(&mut buf)?;
                             stats.time_to_unzip += zt.elapsed();
-                            let parsed_book = process_file(
+                            let mut parsed_book = process_file(
                                 zipfile,
-                                &filename,
-                                data.as_ref(),
+                                filename,
+                                buf.as_ref(),
                                 lang_filter,
                                 book_formats,
                                 opts,
+                                &app.config.cover,
                             );
                             stats.parsed_size += parsed_book.parsed_size;
                             stats.time_to_parse += parsed_book.time_to_parse;
@@ -383,41 +805,115 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
                             if let Some(ref mut lang_stats) = stats.langs {
                                 *(lang_stats.entry(parsed_book.lang.clone()).or_default()) += 1;
                             }
+                            for issue in std::mem::take(&mut parsed_book.issues) {
+                                if issue.is_warning() {
+                                    stats.warning_count += 1;
+                                }
+                                if dry_run && !issue.is_error() {
+                                    warn!(
+                                        "{}/{} -> {}: {}",
+                                        issue.archive, issue.file, issue.kind, issue.message
+                                    );
+                                } else if issue.kind == IssueKind::Language {
+                                    continue; //too many to report
+                                }
+                                progress.issue(issue);
+                            }
                             match parsed_book.state {
                                 BookState::Invalid => stats.error_count += 1,
-                                BookState::Ignored => stats.book_ignored += 1,
+                                BookState::Ignored => {
+                                    stats.book_ignored += 1;
+                                    progress.book_ignored();
+                                }
                                 BookState::Valid(_) => {
-                                    send_book.send(parsed_book).unwrap_or_else(|e| {
-                                        if !canceled.load(Ordering::SeqCst) {
-                                            panic!("Error queueing book to index: {}", e);
-                                        }
-                                    })
+                                    if send_book.send(parsed_book).is_err() {
+                                        queue_closed = true;
+                                        return Ok(false);
+                                    }
+                                    control.observe(send_book.len(), read_queue);
                                 }
                                 _ => (),
                             }
+                            Ok(true)
+                        });
+                        if let Err(e) = res {
+                            error!("{zipfile}: {e}");
+                            job.archive.failed.store(true, Ordering::SeqCst);
+                            archive_failed.store(true, Ordering::SeqCst);
+                            current = None;
                         }
+                        if queue_closed {
+                            break;
+                        }
+                        //last finished part marks whole archive
+                        if job.archive.parts_left.fetch_sub(1, Ordering::SeqCst) == 1
+                            && !canceled.load(Ordering::SeqCst)
+                            && !job.archive.failed.load(Ordering::SeqCst)
+                        {
+                            let marker = ParsedBook {
+                                state: BookState::WholeZip,
+                                zipfile: zipfile.to_string(),
+                                parsed_size: job.archive.file_count,
+                                ..Default::default()
+                            };
+                            if send_book.send(marker).is_err() {
+                                queue_closed = true;
+                                break;
+                            }
+                            progress.archive_finished(zipfile, job.archive.file_count);
+                        }
+                    }
+                    if queue_closed && !canceled.load(Ordering::SeqCst) {
+                        error!(
+                            "{}",
+                            tr![
+                                "Indexing stopped, reading is canceled",
+                                "Индексация остановлена, чтение прервано"
+                            ]
+                        );
                     }
                     stats
                 })
-                .reduce(ProcessStats::default, |a, b| a + b);
-            if !canceled.load(Ordering::SeqCst) {
-                send_book
-                    .send(ParsedBook {
-                        state: BookState::WholeZip,
-                        zipfile: zipfile.to_string(),
-                        parsed_size: zip_stats.book_total,
+            })
+            .collect();
+        let gstats = readers
+            .into_iter()
+            .map(|r| {
+                r.join().unwrap_or_else(|_| {
+                    error!("{}", tr!["Reader thread panicked", "Сбой потока чтения"]);
+                    ProcessStats {
+                        error_count: 1,
                         ..Default::default()
-                    })
-                    .unwrap();
-            }
-            gstats = gstats + zip_stats;
-        }
+                    }
+                })
+            })
+            .fold(ProcessStats::default(), |a, b| a + b);
         drop(send_book);
-        let cstats = commit_thread.join().unwrap();
+        let cstats = match commit_thread.join() {
+            Ok(x) => x,
+            Err(_) => CommitStats {
+                failure: Some(tr!["Commit thread panicked", "Сбой потока записи"].to_string()),
+                ..Default::default()
+            },
+        };
+        if let Some(e) = &cstats.failure {
+            error!("{}: {e}", tr!["Index is not written", "Индекс не записан"]);
+        }
 
+        let zip_failed = zip_failed.load(Ordering::SeqCst);
+        let zip_processed = zip_processed - zip_failed;
+        let zip_skipped = zip_skipped + zip_failed;
         let total = tt.elapsed().as_millis() + 1;
         let canceled = canceled.load(Ordering::SeqCst);
-        if canceled {
+        if args.dry_run {
+            info!(
+                "----{}----",
+                tr![
+                    "DRY RUN DONE, books below are not indexed",
+                    "ПРОБНЫЙ ЗАПУСК ЗАВЕРШЁН, книги не индексированы"
+                ]
+            );
+        } else if canceled {
             info!(
                 "----{}----",
                 tr!["INDEXING CANCELED", "ИНДЕКСАЦИЯ ПРЕРВАНА"]
@@ -440,7 +936,11 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
             "{}: {} {}, {} {}, {} {}",
             tr!["Books", "Книг"],
             cstats.book_indexed,
-            tr!["added", "добавлено"],
+            if args.dry_run {
+                tr!["to add", "к добавлению"]
+            } else {
+                tr!["added", "добавлено"]
+            },
             gstats.book_skipped,
             tr!["skipped", "пропущено"],
             gstats.book_ignored,
@@ -474,9 +974,9 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
         );
         if debug {
             debug!("Commits: {}", cstats.commits_count);
-            let ue = gstats.time_to_unzip.as_millis() / args.read_threads as u128;
-            let pe = gstats.time_to_parse.as_millis() / args.read_threads as u128;
-            let ie = gstats.time_to_image.as_millis() / args.read_threads as u128;
+            let ue = gstats.time_to_unzip.as_millis() / app.config.index.read_threads as u128;
+            let pe = gstats.time_to_parse.as_millis() / app.config.index.read_threads as u128;
+            let ie = gstats.time_to_image.as_millis() / app.config.index.read_threads as u128;
             let ce = cstats.time_to_commit.as_millis();
             debug!(
                 "unpacking {}%, parse {}%, image resize {}%, commit {}%",
@@ -492,9 +992,36 @@ pub fn run_index(args: &IndexArgs, app: Application) -> ProcessResult {
                 debug!("\t{}\t{}", lang, count);
             }
         }
+        progress.finish(canceled);
+        if let Some(path) = &args.report {
+            let report = progress.report(
+                canceled,
+                ArchiveCounters {
+                    found: zip_total_count,
+                    processed: zip_processed,
+                    skipped: zip_skipped,
+                },
+                SizeCounters {
+                    archive: zip_total_size,
+                    read: gstats.packed_size as u64,
+                    unpacked: gstats.unpacked_size as u64,
+                    indexed: gstats.parsed_size as u64,
+                },
+            );
+            let json = serde_json::to_string_pretty(&report).unwrap_or_default();
+            if let Err(e) = std::fs::write(path, json) {
+                return ProcessResult::IndexError(format!(
+                    "{} {path}: {e}",
+                    tr!["Error saving file", "Ошибка сохранения файла"]
+                ));
+            }
+        }
+        match cstats.failure {
+            Some(e) => ProcessResult::IndexError(e),
+            None => ProcessResult::Ok,
+        }
     })
-    .unwrap();
-    ProcessResult::Ok
+    .unwrap_or_else(|_| ProcessResult::IndexError("thread panicked".to_string()))
 }
 
 // extract number from string and left-pad it
@@ -508,6 +1035,52 @@ fn get_numeric_sort_key(filename: &str) -> String {
     }
 }
 
+#[test]
+fn test_reader_control() {
+    let c = ReaderControl::new(3);
+    assert!(c.is_active(2));
+    c.observe(64, 64); //queue is full
+    c.observe(50, 64);
+    assert!(!c.is_active(1));
+    c.observe(64, 64);
+    assert!(c.is_active(0)); //at least one reader
+    c.observe(30, 64); //no change
+    assert!(!c.is_active(1));
+    c.observe(0, 64);
+    assert!(c.is_active(1));
+}
+
+#[test]
+fn test_job_queue() {
+    let archive = |zip_index| ArchiveJob {
+        zip_index,
+        zipfile: format!("{zip_index}.zip"),
+        path: PathBuf::from(format!("{zip_index}.zip")),
+        progress_pct: 0,
+    };
+    let q = JobQueue::new(VecDeque::from([
+        Job::Open(archive(0)),
+        Job::Open(archive(1)),
+    ]));
+    let a = match q.next() {
+        Some(Job::Open(a)) => a,
+        _ => panic!("archive expected"),
+    };
+    let mut parts = split_archive(a, MIN_PART_SIZE * 3, ContainerKind::Zip, 3);
+    assert_eq!(parts.len(), 3);
+    assert_eq!(parts[2].last, MIN_PART_SIZE * 3);
+    parts.remove(0);
+    assert!(!q.is_done()); //archive is being opened
+    q.opened(parts);
+    assert!(matches!(q.next(), Some(Job::Read(x)) if x.first == MIN_PART_SIZE));
+    q.next();
+    assert!(matches!(q.next(), Some(Job::Open(x)) if x.zip_index == 1));
+    q.opened(vec![]); //not opened
+    assert!(q.is_done());
+    let parts = split_archive(archive(2), 0, ContainerKind::TarGz, 3);
+    assert_eq!(parts.len(), 1);
+}
+
 #[test]
 fn test_get_numeric_sort_key() {
     assert_eq!(get_numeric_sort_key("ab123cd45ef"), "000000123");
@@ -525,21 +1098,9 @@ fn format_duration(ms: u128) -> String {
     format!("{h:02}:{m:02}:{s:02}")
 }
 
-fn decode_filename(raw_filename: &[u8]) -> Option<String> {
-    let (charset, confidence, _language) = chardet::detect(raw_filename);
-    if confidence > 0.8 {
-        let enc_label = chardet::charset2encoding(&charset);
-        if let Some(encoding) = Encoding::for_label(enc_label.as_bytes()) {
-            let (utf8, _enc, _malformed) = encoding.decode(raw_filename);
-            return Some(utf8.to_string());
-        }
-    }
-    None
-}
-
-fn is_zip_file(entry: &DirEntry) -> bool {
+fn is_archive_file(entry: &DirEntry) -> bool {
     entry.metadata().map(|e| e.is_file()).unwrap_or(false)
-        && file_extension(entry.file_name().to_str().unwrap_or("")) == ".zip"
+        && ContainerKind::from_filename(entry.file_name().to_str().unwrap_or("")).is_some()
 }
 
 fn process_file<F>(
@@ -549,6 +1110,7 @@ fn process_file<F>(
     lang_filter: F,
     book_formats: &BookFormats,
     opts: &ParseOpts,
+    cover: &CoverConfig,
 ) -> ParsedBook
 where
     F: Fn(&str) -> bool,
@@ -558,6 +1120,42 @@ where
         filename: filename.to_string(),
         ..Default::default()
     };
+    let issue = |kind, message| BookIssue {
+        archive: zipfile.to_string(),
+        file: filename.to_string(),
+        kind,
+        message,
+    };
+    //book.fb2.zip -> book.fb2.zip/book.fb2, single level only
+    if container::is_nested_archive(filename) && !filename.contains('/') {
+        return match container::unpack_nested(data) {
+            Ok(Some((name, inner))) if !container::is_nested_archive(&name) => process_file(
+                zipfile,
+                &format!("{filename}/{name}"),
+                &inner,
+                lang_filter,
+                book_formats,
+                opts,
+                cover,
+            ),
+            Ok(_) => {
+                debug!("{zipfile}/{filename} -> not a single book archive");
+                res
+            }
+            Err(e) => {
+                error!(
+                    "{}/{} -> {} {}",
+                    zipfile,
+                    filename,
+                    tr!["parse error", "ошибка разбора"],
+                    e
+                );
+                res.state = BookState::Invalid;
+                res.issues.push(issue(IssueKind::Parse, e.to_string()));
+                res
+            }
+        };
+    }
     let ext = file_extension(filename);
     if let Some(book_format) = book_formats.get(&ext.as_ref()) {
         //filter eBook by extension
@@ -567,18 +1165,25 @@ where
         res.time_to_parse = pt.elapsed();
         match parsed_book {
             Ok(mut b) => {
-                res.warning_count += b.warning.len();
+                for w in &b.warning {
+                    let kind = if w.starts_with(WARNING_MALFORMED_CHARS)
+                        || w.starts_with(WARNING_ENCODING_FALLBACK)
+                        || w.starts_with(WARNING_ENCODING_DETECTED)
+                    {
+                        IssueKind::Encoding
+                    } else {
+                        IssueKind::Book
+                    };
+                    res.issues.push(issue(kind, w.clone()));
+                }
                 debug!("  {}/{} -> {}", zipfile, filename, &b);
                 let lang = if !b.lang.is_empty() { &b.lang[0] } else { "" };
                 res.lang = lang.into();
                 if lang_filter(lang) {
                     if let Some(img) = b.cover_image {
                         let it = Instant::now();
-                        match crate::img_resizer::resize(
-                            img.as_slice(),
-                            COVER_IMAGE_WIDTH,
-                            COVER_IMAGE_HEIGHT,
-                        ) {
+                        match crate::img_resizer::resize(img.as_slice(), cover.width, cover.height)
+                        {
                             Ok(resized) => b.cover_image = Some(resized),
                             Err(e) => {
                                 error!(
@@ -588,7 +1193,7 @@ where
                                     tr!["image resize error", "ошибка изображения"],
                                     e
                                 );
-                                res.warning_count += 1;
+                                res.issues.push(issue(IssueKind::Image, e.to_string()));
                                 b.cover_image = None;
                             }
                         }
@@ -601,6 +1206,8 @@ where
                     res.state = BookState::Valid(Box::new(b));
                 } else {
                     res.state = BookState::Ignored;
+                    res.issues
+                        .push(issue(IssueKind::Language, lang.to_string()));
                     debug!(
                         "{}/{} -> {} {}",
                         zipfile,
@@ -619,6 +1226,7 @@ where
                     tr!["parse error", "ошибка разбора"],
                     e
                 );
+                res.issues.push(issue(IssueKind::Parse, e.to_string()));
                 //and continue
             }
         }
@@ -0,0 +1,126 @@
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+
+use crate::cmd::*;
+use crate::fts::{BookWriter, SCHEMA_VERSION};
+use crate::tr;
+
+const MIGRATE_MEMORY_SIZE: usize = 200_000_000;
+
+/// sibling directory: index -> index.<suffix>
+fn sibling_dir(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{name}.{suffix}"))
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> ProcessResult {
+    ProcessResult::IndexError(format!("{}: {e}", path.display()))
+}
+
+pub fn run_migrate(args: &MigrateArgs, app: Application) -> ProcessResult {
+    let old_version = app.index_settings.schema_version;
+    if old_version == SCHEMA_VERSION && !args.force {
+        info!(
+            "{}: {SCHEMA_VERSION}",
+            tr!["Index schema is up to date", "Схема индекса актуальна"]
+        );
+        return ProcessResult::Ok;
+    }
+    let opts = &app.index_settings.options;
+    if (opts.body || opts.xbody) && !args.drop_text {
+        return ProcessResult::ConfigError(
+            tr![
+                "Book text is not stored in index and can't be copied: rebuild index with 'index full', or migrate with --drop-text to turn off search in book text",
+                "Текст книг не хранится в индексе и не может быть скопирован: пересоздайте индекс командой 'index full' или преобразуйте с --drop-text, отключив поиск по тексту"
+            ]
+            .to_string(),
+        );
+    }
+    let new_path = sibling_dir(&app.index_path, "migrating");
+    let backup_path = sibling_dir(&app.index_path, &format!("v{old_version}"));
+    if backup_path.exists() {
+        return ProcessResult::IndexError(format!(
+            "{}: {}",
+            tr![
+                "Backup directory already exists",
+                "Каталог для резервной копии уже существует"
+            ],
+            backup_path.display()
+        ));
+    }
+    //leftover of interrupted migration
+    if new_path.exists() {
+        if let Err(e) = std::fs::remove_dir_all(&new_path) {
+            return io_error(&new_path, e);
+        }
+    }
+    if let Err(e) = std::fs::create_dir(&new_path) {
+        return io_error(&new_path, e);
+    }
+    info!(
+        "{} {} -> {}: {}",
+        tr!["Migrating index schema", "Преобразуем схему индекса"],
+        old_version,
+        SCHEMA_VERSION,
+        new_path.display()
+    );
+
+    let genre_map = match app.load_genre_map() {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::ConfigError(e),
+    };
+    let mut book_writer = match BookWriter::new(
+        &new_path,
+        &app.index_settings.stemmer,
+        app.config.index.index_threads,
+        MIGRATE_MEMORY_SIZE,
+    ) {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::IndexError(e.to_string()),
+    };
+    let stats = match book_writer.copy_from(&app.index_path, &genre_map) {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::IndexError(e.to_string()),
+    };
+    if let Err(e) = book_writer.commit() {
+        return ProcessResult::IndexError(e.to_string());
+    }
+    if let Err(e) = book_writer.wait_merging_threads() {
+        return ProcessResult::IndexError(e.to_string());
+    }
+    let mut settings = app.index_settings.clone();
+    settings.schema_version = SCHEMA_VERSION;
+    //text is lost, 'index full' turns search in it on again
+    settings.options.body = false;
+    settings.options.xbody = false;
+    if let Err(e) = settings.save(&new_path) {
+        return ProcessResult::IndexError(e);
+    }
+
+    //swap directories, keep old index as backup
+    if let Err(e) = std::fs::rename(&app.index_path, &backup_path) {
+        return io_error(&app.index_path, e);
+    }
+    if let Err(e) = std::fs::rename(&new_path, &app.index_path) {
+        return io_error(&new_path, e);
+    }
+    info!(
+        "{}: {}, {}: {}, {}: {}",
+        tr!["Books copied", "Скопировано книг"],
+        stats.books,
+        tr!["archive markers", "отметок архивов"],
+        stats.markers,
+        tr!["old index saved in", "старый индекс сохранён в"],
+        backup_path.display()
+    );
+    if args.drop_text {
+        warn!(
+            "{}",
+            tr![
+                "Search in book text is turned off, 'index full' restores it",
+                "Поиск по тексту книг отключён, 'index full' восстановит его"
+            ]
+        );
+    }
+    ProcessResult::Ok
+}
@@ -2,31 +2,55 @@ use clap::{Args, Parser, Subcommand};
 use log::{debug, error};
 use serde::{Deserialize, Serialize};
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::process::{ExitCode, Termination};
+use std::sync::{Mutex, MutexGuard};
 
+mod config;
 mod facet;
 mod index;
+mod migrate;
+mod progress;
 mod query;
+mod remove;
 mod server;
+mod stats;
+mod verify;
+mod watch;
+pub use self::config::*;
 pub use self::facet::run_facet;
 pub use self::index::run_index;
+pub use self::migrate::run_migrate;
 pub use self::query::run_query;
+pub use self::remove::run_remove;
 pub use self::server::run_server;
+pub use self::stats::run_stats;
+pub use self::verify::run_verify;
+pub use self::watch::spawn_watcher;
 
+pub use crate::access_log::AccessLogFormat;
 use crate::assets;
-use crate::fts::BookReader;
+use crate::author_map::AuthorMap;
+use crate::fts::{BookReader, SCHEMA_VERSION};
 use crate::genre_map::GenreMap;
 use crate::types::BookFormats;
 
 pub const INDEX_SETTINGS_FILE: &str = "porcula_index_settings.json";
+pub const DEFAULT_INDEX_DIR: &str = "index";
+pub const DEFAULT_BOOKS_DIR: &str = "books";
 pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8083";
 pub const DEFAULT_QUERY_HITS: usize = 20;
+pub const DEFAULT_OPDS_ENTRIES: usize = 20;
+pub const DEFAULT_FIND_HITS: usize = 100;
+pub const DEFAULT_READ_THREADS: usize = 1;
+pub const DEFAULT_READ_QUEUE: usize = 64;
 pub const DEFAULT_LANGUAGE: &str = "ru";
 pub const DEFAULT_BASE_URL: &str = "/porcula";
 pub const DEFAULT_ASSETS_DIR: &str = "static";
+pub const DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
 pub const GENRE_MAP_FILENAME: &str = "genre-map.txt";
+pub const AUTHOR_MAP_FILENAME: &str = "author-map.txt";
 
 pub const COVER_IMAGE_WIDTH: u32 = 96;
 pub const COVER_IMAGE_HEIGHT: u32 = 144;
@@ -108,6 +132,17 @@ impl Termination for ProcessResult {
     }
 }
 
+impl ProcessResult {
+    pub fn into_result(self) -> Result<(), String> {
+        match self {
+            ProcessResult::Ok => Ok(()),
+            ProcessResult::ConfigError(e)
+            | ProcessResult::IndexError(e)
+            | ProcessResult::QueryError(e) => Err(e),
+        }
+    }
+}
+
 pub fn parse_args() -> AppArgs {
     AppArgs::parse()
 }
@@ -124,12 +159,15 @@ pub struct AppArgs {
         "Вывод отладочной информации"
     ))]
     pub debug: bool,
-    #[clap(short, long, default_value_t = String::from("index"), help=tr!("Index directory, read/write",
-    "Каталог для индекса, чтение и запись"))]
-    pub index_dir: String,
-    #[clap(short, long, default_value_t = String::from("books"), help=tr!("Books directory, read only",
-    "Каталог с книгами, только чтение"))]
-    pub books_dir: String,
+    #[clap(short, long, help=tr!("Configuration file [default: porcula.toml in current or user config directory]",
+    "Файл конфигурации [по умолчанию porcula.toml в текущем каталоге или в каталоге настроек пользователя]"), value_name = "FILE")]
+    pub config: Option<String>,
+    #[clap(short, long, help=tr!("Index directory, read/write [default: index]",
+    "Каталог для индекса, чтение и запись [по умолчанию: index]"))]
+    pub index_dir: Option<String>,
+    #[clap(short, long, help=tr!("Books directory, read only [default: books]",
+    "Каталог с книгами, только чтение [по умолчанию: books]"))]
+    pub books_dir: Option<String>,
     #[clap(subcommand)]
     pub command: Option<Command>,
 }
@@ -144,6 +182,48 @@ pub enum Command {
     Query(QueryArgs),
     #[clap(about=tr!("Run single facet query, print result as JSON and exit", "Выполнить фасетный запрос, результат в формате JSON"))]
     Facet(FacetArgs),
+    #[clap(about=tr!("Print index statistics as JSON", "Статистика индекса в формате JSON"))]
+    Stats,
+    #[clap(about=tr!("Check index consistency with books", "Проверить соответствие индекса и книг"))]
+    Verify(VerifyArgs),
+    #[clap(about=tr!("Remove archives or books from index", "Удалить архивы или книги из индекса"))]
+    Remove(RemoveArgs),
+    #[clap(about=tr!("Convert index to current schema without reindexing", "Преобразовать индекс в текущую схему без переиндексации"))]
+    Migrate(MigrateArgs),
+    #[clap(about=tr!("Configuration file", "Файл конфигурации"))]
+    Config(ConfigArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RemoveArgs {
+    #[clap(required = true, help=tr!("Archive or book in archive", "Архив или книга в архиве"), value_name = "ZIP | ZIP/FILE")]
+    pub target: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct MigrateArgs {
+    #[clap(long, help=tr!("Rebuild even if schema version is current", "Пересоздать, даже если версия схемы текущая"))]
+    pub force: bool,
+    #[clap(long, help=tr!("Allow to lose search in book text, it is not stored in index", "Разрешить потерю поиска по тексту книг, текст не хранится в индексе"))]
+    pub drop_text: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    #[clap(long, help=tr!("Delete inconsistent archives from index", "Удалить из индекса архивы с ошибками"))]
+    pub repair: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ConfigArgs {
+    #[clap(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    #[clap(about=tr!("Print effective configuration", "Показать действующую конфигурацию"))]
+    Show,
 }
 
 #[derive(Eq, PartialEq, Debug, strum::Display, strum::EnumString, Clone)]
@@ -153,6 +233,13 @@ pub enum IndexMode {
     Delta,
 }
 
+#[derive(Eq, PartialEq, Debug, strum::Display, strum::EnumString, Clone)]
+#[strum(serialize_all = "lowercase")]
+pub enum ProgressFormat {
+    Text,
+    Json,
+}
+
 #[derive(Eq, PartialEq, Debug, strum::Display, strum::EnumString, Clone)]
 #[strum(serialize_all = "lowercase")]
 pub enum OnOff {
@@ -165,6 +252,9 @@ pub struct IndexArgs {
     #[clap(default_value = "delta", help = tr!("Index mode: full or incremental",
     "Режим индексирования: полный или добавление"), value_name="full|delta")]
     pub mode: IndexMode,
+    #[clap(long, help=tr!("Continue interrupted full indexing with its settings",
+    "Продолжить прерванную полную индексацию с её настройками"))]
+    pub resume: bool,
     #[clap(short, long, help=tr!("Archive file name to reindex",
     "Имя отдельного архива для переиндексации"))]
     pub file: Vec<String>,
@@ -179,10 +269,10 @@ pub struct IndexArgs {
     pub memory_size: Option<usize>,
     #[clap(short, long, help=tr!("Number of indexing workers", "Число потоков индексирования"))]
     pub index_threads: Option<usize>,
-    #[clap(short, long, default_value_t = 1, help=tr!("Number of read workers", "Число потоков чтения"))]
-    pub read_threads: usize,
-    #[clap(short='q', long, default_value_t=64, help=tr!("Length of read queue", "Длина очереди чтения"))]
-    pub read_queue: usize,
+    #[clap(short, long, help=tr!("Number of read workers [default: 1]", "Число потоков чтения [по умолчанию: 1]"))]
+    pub read_threads: Option<usize>,
+    #[clap(short='q', long, help=tr!("Length of read queue [default: 64]", "Длина очереди чтения [по умолчанию: 64]"))]
+    pub read_queue: Option<usize>,
     #[clap(short='B', long, help=tr!("Batch size between commits","Размер данных между сохранениями"), value_name="MB")]
     pub batch_size: Option<usize>,
     #[clap(long, help=tr!("Index book's body", "Индексировать текст книги (без учёта склонения)"), value_name="on|off")]
@@ -193,27 +283,61 @@ pub struct IndexArgs {
     pub annotation: Option<OnOff>,
     #[clap(long, help=tr!("Extract book's cover image", "Извлекать обложку книги"), value_name="on|off")]
     pub cover: Option<OnOff>,
+    #[clap(long, help=tr!("Parse books and report problems without changing index", "Разобрать книги и показать ошибки, не изменяя индекс"))]
+    pub dry_run: bool,
+    #[clap(long, default_value = "text", help=tr!("Print progress events as JSON lines to stdout", "Выводить ход индексации в stdout в формате JSON"), value_name="text|json")]
+    pub progress: ProgressFormat,
+    #[clap(long, help=tr!("Save final statistics with all errors and warnings", "Сохранить итоговую статистику со всеми ошибками и предупреждениями"), value_name="FILE")]
+    pub report: Option<String>,
 }
 
-#[derive(Args, Debug)]
-pub struct ServerArgs {
-    #[clap(short, long, default_value = DEFAULT_LISTEN_ADDR, help=tr!("Listen address", "Адрес сервера"), value_name = "ADDRESS:PORT")]
-    pub listen: String,
-}
-impl Default for ServerArgs {
-    fn default() -> Self {
-        ServerArgs {
-            listen: DEFAULT_LISTEN_ADDR.into(),
+impl IndexArgs {
+    /// incremental indexing of selected archives, default options
+    pub fn delta(file: Vec<String>) -> Self {
+        IndexArgs {
+            mode: IndexMode::Delta,
+            resume: false,
+            file,
+            lang: vec![],
+            stemmer: None,
+            memory_size: None,
+            index_threads: None,
+            read_threads: None,
+            read_queue: None,
+            batch_size: None,
+            body: None,
+            xbody: None,
+            annotation: None,
+            cover: None,
+            dry_run: false,
+            progress: ProgressFormat::Text,
+            report: None,
         }
     }
 }
 
+#[derive(Args, Debug)]
+pub struct ServerArgs {
+    #[clap(short, long, help=tr!("Listen address [default: 127.0.0.1:8083]", "Адрес сервера [по умолчанию: 127.0.0.1:8083]"), value_name = "ADDRESS:PORT")]
+    pub listen: Option<String>,
+    #[clap(long, help=tr!("Do not compress responses", "Не сжимать ответы сервера"))]
+    pub no_compression: bool,
+    #[clap(long, help=tr!("Compress responses not smaller than [default: 1024]", "Сжимать ответы размером от [по умолчанию: 1024]"), value_name = "BYTES")]
+    pub compression_min_size: Option<usize>,
+    #[clap(long, help=tr!("Write access log to file or stderr", "Журнал запросов в файл или stderr"), value_name = "FILE|-")]
+    pub access_log: Option<String>,
+    #[clap(long, help=tr!("Access log format [default: combined]", "Формат журнала запросов [по умолчанию: combined]"), value_name = "combined|json")]
+    pub access_log_format: Option<AccessLogFormat>,
+    #[clap(long, num_args = 0..=1, default_missing_value = "60", help=tr!("Index new and modified archives, check books dir every N seconds [default: 60]", "Индексировать новые и изменённые архивы, проверять каталог с книгами каждые N секунд [по умолчанию: 60]"), value_name = "SECONDS")]
+    pub watch: Option<u64>,
+}
+
 #[derive(Args, Debug)]
 pub struct QueryArgs {
     #[clap(help=tr!("Query text", "Текст запроса"))]
     pub query: String,
-    #[clap(short = 'H', long, default_value_t = DEFAULT_QUERY_HITS, help=tr!("Limit results to N top hits", "Ограничить число найденных книг"))]
-    pub hits: usize,
+    #[clap(short = 'H', long, help=tr!("Limit results to N top hits [default: 20]", "Ограничить число найденных книг [по умолчанию: 20]"))]
+    pub hits: Option<usize>,
     #[clap(short = 'x', long, help=tr!("Search in stemmed fields", "Поиск по всем формам слова"))]
     pub stem: bool,
     #[clap(short = 'o', long="or", help=tr!("Logical OR by default", "Логическое ИЛИ по умолчанию"))]
@@ -224,8 +348,8 @@ pub struct QueryArgs {
 pub struct FacetArgs {
     #[clap(help=tr!("Facet path, i.e. '/author/K' or '/genre/fiction/sf'","Путь по категориям, например '/author/K' или '/genre/fiction/sf'"))]
     pub path: String,
-    #[clap(short = 'H', long, default_value_t = DEFAULT_QUERY_HITS, help=tr!("Limit results to N top hits", "Ограничить число найденных книг"))]
-    pub hits: usize,
+    #[clap(short = 'H', long, help=tr!("Limit results to N top hits [default: 20]", "Ограничить число найденных книг [по умолчанию: 20]"))]
+    pub hits: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -242,6 +366,36 @@ pub struct IndexSettings {
     pub stemmer: String,
     pub books_dir: String,
     pub options: ParseOpts,
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_run: Option<FullRun>, //unfinished full indexing
+}
+
+/// progress of full indexing, saved on every commit
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FullRun {
+    pub id: String,
+    pub started: String,
+    pub archives_done: usize, //archives marked as indexed
+    pub last_archive: Option<String>,
+}
+
+impl FullRun {
+    pub fn start() -> Self {
+        let now = chrono::Local::now();
+        FullRun {
+            id: now.format("%Y%m%d-%H%M%S").to_string(),
+            started: now.to_rfc3339(),
+            archives_done: 0,
+            last_archive: None,
+        }
+    }
+}
+
+//settings saved before schema versioning
+fn legacy_schema_version() -> u32 {
+    1
 }
 
 //for web-app
@@ -256,13 +410,15 @@ pub struct Application {
     pub index_path: PathBuf,
     pub books_path: PathBuf,
     pub book_formats: BookFormats,
+    pub config: Config,
     pub debug: bool,
+    pub writer_lock: Mutex<()>, //one BookWriter at a time: watcher and admin requests
 }
 
 impl IndexSettings {
-    // load or create settings stored with index
-    pub fn load(args: &AppArgs) -> Result<Self, String> {
-        let index_path = Path::new(&args.index_dir).to_path_buf();
+    // load or create settings stored with index, apply [index] configuration when indexing
+    pub fn load(config: &Config, indexing: bool) -> Result<Self, String> {
+        let index_path = Path::new(&config.index_dir).to_path_buf();
         let filename = index_path.join(INDEX_SETTINGS_FILE);
         let mut res: IndexSettings = if let Ok(f) = std::fs::File::open(&filename) {
             debug!(
@@ -289,34 +445,29 @@ impl IndexSettings {
             IndexSettings {
                 langs: vec![DEFAULT_LANGUAGE.to_string()],
                 stemmer: "off".to_string(),
-                books_dir: args.books_dir.clone(),
+                books_dir: config.books_dir.clone(),
                 options: ParseOpts {
                     body: true,
                     xbody: true,
                     annotation: true,
                     cover: true,
                 },
+                schema_version: SCHEMA_VERSION,
+                full_run: None,
             }
         };
-        if let Some(Command::Index(args)) = &args.command {
-            if !args.lang.is_empty() {
-                res.langs.clone_from(&args.lang);
+        if indexing {
+            let c = &config.index;
+            if !c.lang.is_empty() {
+                res.langs.clone_from(&c.lang);
             }
-            if let Some(stemmer) = &args.stemmer {
+            if let Some(stemmer) = &c.stemmer {
                 res.stemmer.clone_from(stemmer);
             }
-            if let Some(x) = &args.body {
-                res.options.body = *x == OnOff::On;
-            }
-            if let Some(x) = &args.xbody {
-                res.options.xbody = *x == OnOff::On;
-            }
-            if let Some(x) = &args.annotation {
-                res.options.annotation = *x == OnOff::On;
-            }
-            if let Some(x) = &args.cover {
-                res.options.cover = *x == OnOff::On;
-            }
+            res.options.body = c.body.unwrap_or(res.options.body);
+            res.options.xbody = c.xbody.unwrap_or(res.options.xbody);
+            res.options.annotation = c.annotation.unwrap_or(res.options.annotation);
+            res.options.cover = c.cover.unwrap_or(res.options.cover);
         }
         assert!(
             !res.langs.is_empty(),
@@ -330,6 +481,23 @@ impl IndexSettings {
         Ok(res)
     }
 
+    pub fn check_schema_version(&self) -> Result<(), String> {
+        if self.schema_version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        Err(format!(
+            "{}: {}, {}: {}\n{}",
+            tr!["Index schema version", "Версия схемы индекса"],
+            self.schema_version,
+            tr!["expected", "ожидается"],
+            SCHEMA_VERSION,
+            tr![
+                "Run 'migrate' command or rebuild index with 'index full' in empty directory",
+                "Запустите команду 'migrate' или пересоздайте индекс командой 'index full' в пустом каталоге"
+            ],
+        ))
+    }
+
     pub fn save(&self, index_path: &Path) -> Result<(), String> {
         let filename = index_path.join(INDEX_SETTINGS_FILE);
         let mut f = std::fs::File::create(&filename).unwrap();
@@ -347,6 +515,11 @@ impl IndexSettings {
 }
 
 impl Application {
+    /// held while index is written
+    pub fn lock_writer(&self) -> MutexGuard<'_, ()> {
+        self.writer_lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
     pub fn open_book_reader(&self) -> Result<BookReader, String> {
         assert!(
             !self.index_settings.langs.is_empty(),
@@ -369,25 +542,31 @@ impl Application {
         }
     }
 
-    pub fn load_genre_map(&self) -> Result<GenreMap, String> {
-        let genre_map_path = Path::new(DEFAULT_ASSETS_DIR).join(GENRE_MAP_FILENAME);
-        if genre_map_path.exists() {
+    /// map file from assets directory, or compiled-in asset
+    fn open_map_file(filename: &str) -> Result<Box<dyn BufRead>, String> {
+        let path = Path::new(DEFAULT_ASSETS_DIR).join(filename);
+        if path.exists() {
             //load file
-            let file = match std::fs::File::open(genre_map_path) {
-                Ok(f) => f,
-                Err(e) => return Err(e.to_string()),
-            };
-            let mut buf = BufReader::new(file);
-            GenreMap::load(&mut buf).map_err(|e| e.to_string())
+            let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+            Ok(Box::new(BufReader::new(file)))
         } else {
             //load static asset
-            let data = assets::get(GENRE_MAP_FILENAME)
-                .expect("Genre map not found")
+            let data = assets::get(filename)
+                .ok_or_else(|| format!("{filename} not found"))?
                 .content;
-            let mut buf = BufReader::new(data);
-            GenreMap::load(&mut buf).map_err(|e| e.to_string())
+            Ok(Box::new(BufReader::new(data)))
         }
     }
+
+    pub fn load_genre_map(&self) -> Result<GenreMap, String> {
+        let mut buf = Self::open_map_file(GENRE_MAP_FILENAME)?;
+        GenreMap::load(&mut buf).map_err(|e| e.to_string())
+    }
+
+    pub fn load_author_map(&self) -> Result<AuthorMap, String> {
+        let mut buf = Self::open_map_file(AUTHOR_MAP_FILENAME)?;
+        AuthorMap::load(&mut buf).map_err(|e| e.to_string())
+    }
 }
 
 pub fn file_extension(s: &str) -> String {
@@ -0,0 +1,342 @@
+//machine-readable indexing progress (JSON lines on stdout) and final report
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum IssueKind {
+    Parse,    //book skipped
+    Index,    //book skipped
+    Image,    //cover skipped
+    Book,     //parser warning
+    Encoding, //text decoded with replacements or with fallback encoding
+    Language, //book ignored by language filter
+}
+
+/// parse or index problem of single book
+#[derive(Serialize, Clone, Debug)]
+pub struct BookIssue {
+    pub archive: String,
+    pub file: String,
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+impl BookIssue {
+    pub fn is_error(&self) -> bool {
+        matches!(self.kind, IssueKind::Parse | IssueKind::Index)
+    }
+
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self.kind,
+            IssueKind::Image | IssueKind::Book | IssueKind::Encoding
+        )
+    }
+}
+
+#[derive(Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct BookCounters {
+    pub indexed: usize,
+    pub skipped: usize, //already in index
+    pub ignored: usize, //by language
+    pub failed: usize,
+    pub warnings: usize,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ProgressEvent<'a> {
+    Start {
+        archives: usize,
+        bytes: u64,
+    },
+    ArchiveStarted {
+        archive: &'a str,
+        number: usize,
+        total: usize,
+    },
+    ArchiveFinished {
+        archive: &'a str,
+        entries: usize,
+    },
+    Books {
+        #[serde(flatten)]
+        books: BookCounters,
+        bytes: u64,
+        bytes_total: u64,
+        eta_secs: Option<u64>,
+    },
+    Done {
+        canceled: bool,
+        #[serde(flatten)]
+        books: BookCounters,
+        duration_secs: f64,
+    },
+}
+
+#[derive(Serialize)]
+pub struct ArchiveCounters {
+    pub found: usize,
+    pub processed: usize,
+    pub skipped: usize,
+}
+
+#[derive(Serialize)]
+pub struct SizeCounters {
+    pub archive: u64,
+    pub read: u64,
+    pub unpacked: u64,
+    pub indexed: u64,
+}
+
+/// --report file content
+#[derive(Serialize)]
+pub struct IndexReport {
+    pub started: String,
+    pub duration_secs: f64,
+    pub canceled: bool,
+    pub archives: ArchiveCounters,
+    pub books: BookCounters,
+    pub size: SizeCounters,
+    pub archive_errors: BTreeMap<String, usize>, //archive -> failed books
+    pub errors: Vec<BookIssue>,
+    pub warnings: Vec<BookIssue>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignored: Vec<BookIssue>, //by language, dry run only
+}
+
+/// shared by reader and commit threads
+pub struct Progress {
+    json: bool,
+    collect_issues: bool,
+    started: Instant,
+    started_at: String,
+    bytes_total: u64,
+    bytes_done: AtomicU64,
+    indexed: AtomicUsize,
+    skipped: AtomicUsize,
+    ignored: AtomicUsize,
+    failed: AtomicUsize,
+    warnings: AtomicUsize,
+    issues: Mutex<Vec<BookIssue>>,
+    last_emit: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new(json: bool, collect_issues: bool, bytes_total: u64) -> Self {
+        Progress {
+            json,
+            collect_issues,
+            started: Instant::now(),
+            started_at: chrono::Local::now().to_rfc3339(),
+            bytes_total,
+            bytes_done: AtomicU64::new(0),
+            indexed: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            ignored: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            warnings: AtomicUsize::new(0),
+            issues: Mutex::new(vec![]),
+            last_emit: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn emit(&self, event: &ProgressEvent) {
+        if !self.json {
+            return;
+        }
+        if let Ok(line) = serde_json::to_string(event) {
+            let mut out = std::io::stdout().lock();
+            let _ = writeln!(out, "{line}");
+            let _ = out.flush();
+        }
+    }
+
+    pub fn counters(&self) -> BookCounters {
+        BookCounters {
+            indexed: self.indexed.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            ignored: self.ignored.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            warnings: self.warnings.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn start(&self, archives: usize) {
+        self.emit(&ProgressEvent::Start {
+            archives,
+            bytes: self.bytes_total,
+        });
+    }
+
+    pub fn archive_started(&self, archive: &str, number: usize, total: usize) {
+        self.emit(&ProgressEvent::ArchiveStarted {
+            archive,
+            number,
+            total,
+        });
+    }
+
+    pub fn archive_finished(&self, archive: &str, entries: usize) {
+        self.emit(&ProgressEvent::ArchiveFinished { archive, entries });
+        self.emit_books(true);
+    }
+
+    /// compressed bytes read from archives, for ETA
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        self.emit_books(false);
+    }
+
+    pub fn book_indexed(&self) {
+        self.indexed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn book_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn book_ignored(&self) {
+        self.ignored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn issue(&self, issue: BookIssue) {
+        if issue.is_error() {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        } else if issue.is_warning() {
+            self.warnings.fetch_add(1, Ordering::Relaxed);
+        }
+        if self.collect_issues {
+            if let Ok(mut issues) = self.issues.lock() {
+                issues.push(issue);
+            }
+        }
+    }
+
+    fn emit_books(&self, force: bool) {
+        if !self.json {
+            return;
+        }
+        if let Ok(mut last) = self.last_emit.lock() {
+            if !force && last.elapsed() < PROGRESS_INTERVAL {
+                return;
+            }
+            *last = Instant::now();
+        }
+        let bytes = self.bytes_done.load(Ordering::Relaxed);
+        let eta_secs = if bytes > 0 {
+            let elapsed = self.started.elapsed().as_secs_f64();
+            Some((elapsed * self.bytes_total.saturating_sub(bytes) as f64 / bytes as f64) as u64)
+        } else {
+            None
+        };
+        self.emit(&ProgressEvent::Books {
+            books: self.counters(),
+            bytes,
+            bytes_total: self.bytes_total,
+            eta_secs,
+        });
+    }
+
+    pub fn finish(&self, canceled: bool) {
+        self.emit(&ProgressEvent::Done {
+            canceled,
+            books: self.counters(),
+            duration_secs: self.started.elapsed().as_secs_f64(),
+        });
+    }
+
+    pub fn report(
+        &self,
+        canceled: bool,
+        archives: ArchiveCounters,
+        size: SizeCounters,
+    ) -> IndexReport {
+        let issues = self.issues.lock().map(|x| x.clone()).unwrap_or_default();
+        let (errors, rest): (Vec<BookIssue>, Vec<BookIssue>) =
+            issues.into_iter().partition(|x| x.is_error());
+        let (warnings, ignored): (Vec<BookIssue>, Vec<BookIssue>) =
+            rest.into_iter().partition(|x| x.is_warning());
+        let mut archive_errors = BTreeMap::new();
+        for e in &errors {
+            *archive_errors.entry(e.archive.clone()).or_default() += 1;
+        }
+        IndexReport {
+            started: self.started_at.clone(),
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            canceled,
+            archives,
+            books: self.counters(),
+            size,
+            archive_errors,
+            errors,
+            warnings,
+            ignored,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_report() {
+        let p = Progress::new(false, true, 100);
+        p.book_indexed();
+        p.book_ignored();
+        for (kind, file) in [(IssueKind::Parse, "1.fb2"), (IssueKind::Book, "2.fb2")] {
+            p.issue(BookIssue {
+                archive: "a.zip".into(),
+                file: file.into(),
+                kind,
+                message: "x".into(),
+            });
+        }
+        let r = p.report(
+            false,
+            ArchiveCounters {
+                found: 1,
+                processed: 1,
+                skipped: 0,
+            },
+            SizeCounters {
+                archive: 100,
+                read: 100,
+                unpacked: 200,
+                indexed: 50,
+            },
+        );
+        assert_eq!(
+            r.books,
+            BookCounters {
+                indexed: 1,
+                skipped: 0,
+                ignored: 1,
+                failed: 1,
+                warnings: 1,
+            }
+        );
+        assert_eq!(r.archive_errors["a.zip"], 1);
+        assert_eq!(r.errors[0].file, "1.fb2");
+        assert_eq!(r.warnings[0].kind, IssueKind::Book);
+        let event = serde_json::to_string(&ProgressEvent::ArchiveFinished {
+            archive: "a.zip",
+            entries: 2,
+        })
+        .unwrap();
+        assert_eq!(
+            event,
+            r#"{"event":"archive_finished","archive":"a.zip","entries":2}"#
+        );
+    }
+}
@@ -10,7 +10,7 @@ pub fn run_query(args: &QueryArgs, app: Application) -> ProcessResult {
         args.stem,
         args.disjunction,
         crate::fts::OrderBy::Default,
-        args.hits,
+        app.config.pages.query_hits,
         0,
     ) {
         Ok(res) => {
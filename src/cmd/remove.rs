@@ -0,0 +1,84 @@
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::cmd::*;
+use crate::fts::BookWriter;
+use crate::tr;
+
+const REMOVE_MEMORY_SIZE: usize = 50_000_000;
+
+#[derive(Serialize, Debug)]
+pub struct Removed {
+    pub target: String,
+    pub books: u64, //0 if target was not indexed
+}
+
+/// delete archives ("a.zip", with WHOLE marker) or single books ("a.zip/1.fb2") from index
+pub(super) fn remove_from_index(
+    app: &Application,
+    targets: &[String],
+) -> Result<Vec<Removed>, String> {
+    let _lock = app.lock_writer();
+    let counts = app
+        .open_book_reader()?
+        .get_file_counts()
+        .map_err(|e| e.to_string())?;
+    let mut book_writer = BookWriter::new(
+        &app.index_path,
+        &app.index_settings.stemmer,
+        Some(1),
+        REMOVE_MEMORY_SIZE,
+    )
+    .map_err(|e| e.to_string())?;
+    let mut res = vec![];
+    for target in targets {
+        let books = match target.split_once('/') {
+            Some((zipfile, filename)) => {
+                book_writer
+                    .delete_book(zipfile, filename)
+                    .map_err(|e| e.to_string())?;
+                counts
+                    .get(zipfile)
+                    .and_then(|x| x.get(filename))
+                    .copied()
+                    .unwrap_or(0)
+            }
+            None => {
+                book_writer
+                    .delete_zipfile(target)
+                    .map_err(|e| e.to_string())?;
+                counts.get(target).map(|x| x.values().sum()).unwrap_or(0)
+            }
+        };
+        res.push(Removed {
+            target: target.clone(),
+            books,
+        });
+    }
+    book_writer.commit().map_err(|e| e.to_string())?;
+    Ok(res)
+}
+
+pub fn run_remove(args: &RemoveArgs, app: Application) -> ProcessResult {
+    let removed = match remove_from_index(&app, &args.target) {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::IndexError(e),
+    };
+    for r in removed {
+        if r.books > 0 {
+            info!(
+                "{}: {}: {}",
+                r.target,
+                tr!["books removed", "удалено книг"],
+                r.books
+            );
+        } else {
+            warn!(
+                "{}: {}",
+                r.target,
+                tr!["not found in index", "не найдено в индексе"]
+            );
+        }
+    }
+    ProcessResult::Ok
+}
@@ -1,24 +1,36 @@
 use atom_syndication::{
     Category, ContentBuilder, Entry, EntryBuilder, FeedBuilder, LinkBuilder, Person,
 };
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use rouille::{Request, Response};
+use rouille::{Request, Response, ResponseBody};
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::io::prelude::*;
-use std::path::Path;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
+use super::index::index_books;
+use super::remove::remove_from_index;
+use crate::access_log::{AccessEntry, AccessLog};
 use crate::cmd::*;
+use crate::container;
+use crate::metrics::{route_label, IndexGauges, METRICS};
 use crate::sort::LocalString;
 use crate::tr;
+use crate::types::{name_with_initials, BookFormat};
 
 const CACHE_IMMUTABLE: u64 = 31_536_000;
 const CACHE_STATIC_ASSET: u64 = 86_400;
-const OPDS_PAGE_ENTRIES: usize = 20;
+const OPDS_CATALOG_MIME_TYPE: &str = "application/atom+xml;profile=opds-catalog";
+const OPENSEARCH_MIME_TYPE: &str = "application/opensearchdescription+xml";
 
-pub fn run_server(args: &ServerArgs, app: Application) -> ProcessResult {
+pub fn run_server(app: Application) -> ProcessResult {
     let fts = match app.open_book_reader() {
         Ok(x) => x,
         Err(e) => return ProcessResult::IndexError(e),
@@ -44,90 +56,337 @@ pub fn run_server(args: &ServerArgs, app: Application) -> ProcessResult {
         &app.index_settings.stemmer
     );
     info!("{:?}", &app.index_settings.options);
+    let args = app.config.server.clone();
     info!(
-        "{}: http://{}/porcula/home.html",
+        "{}: http://{}{}/home.html",
         tr!["Application", "Приложение"],
-        &args.listen
+        &args.listen,
+        &args.base_url
     );
     let genre_map = match app.load_genre_map() {
         Ok(x) => x,
         Err(e) => return ProcessResult::ConfigError(e),
     };
+    let author_map = match app.load_author_map() {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::ConfigError(e),
+    };
 
-    #[allow(clippy::cognitive_complexity, clippy::manual_strip)]
+    let compression_min_size = if args.compression {
+        Some(args.compression_min_size)
+    } else {
+        None
+    };
+    let access_log = match &args.access_log {
+        Some(path) => match AccessLog::open(path, args.access_log_format.clone()) {
+            Ok(x) => Some(x),
+            Err(e) => return ProcessResult::ConfigError(e),
+        },
+        None => None,
+    };
+    let app = Arc::new(app);
+    if let Some(secs) = args.watch {
+        spawn_watcher(app.clone(), Duration::from_secs(secs.max(1)));
+    }
     rouille::start_server(&args.listen, move |req| {
-        debug!("req {}", req.raw_url());
-        let mut req = req;
-        let req_no_prefix;
+        let started = Instant::now();
+        let res = route(req, &app, &fts, &genre_map, &author_map);
+        let mut res = match compression_min_size {
+            Some(min_size) => compress(req, res, min_size),
+            None => res,
+        };
+        let url = req.url();
+        let path = url
+            .strip_prefix(&app.config.server.base_url)
+            .unwrap_or(&url);
+        let route = route_label(path);
+        let elapsed = started.elapsed();
+        METRICS.observe_request(route, res.status_code, elapsed);
+        if let Some(log) = &access_log {
+            let size = response_size(&mut res);
+            log.write(&AccessEntry::new(
+                req,
+                route,
+                res.status_code,
+                size,
+                elapsed,
+            ));
+        }
+        res
+    });
+}
+
+#[allow(clippy::cognitive_complexity, clippy::manual_strip)]
+fn route(
+    req: &Request,
+    app: &Arc<Application>,
+    fts: &BookReader,
+    genre_map: &GenreMap,
+    author_map: &AuthorMap,
+) -> Response {
+    debug!("req {}", req.raw_url());
+    let mut req = req;
+    let req_no_prefix;
 
-        // map: /home.html -> home.html -> ./static/home.html
-        // map: /porcula/home.html -> home.html -> ./static/home.html
-        if let Some(r) = req.remove_prefix(DEFAULT_BASE_URL) {
+    // map: /home.html -> home.html -> ./static/home.html
+    // map: /porcula/home.html -> home.html -> ./static/home.html
+    let base_url = &app.config.server.base_url;
+    if !base_url.is_empty() {
+        if let Some(r) = req.remove_prefix(base_url) {
             req_no_prefix = r;
             req = &req_no_prefix;
         }
-        let res = rouille::match_assets(req, DEFAULT_ASSETS_DIR);
-        if res.is_success() {
+    }
+    //admin endpoints use own credentials and are disabled by default
+    let url = req.url();
+    let auth = if url.starts_with("/admin/") {
+        match &app.config.server.admin {
+            Some(x) => Some(x),
+            None => return Response::empty_404(),
+        }
+    } else if url == "/health" || url == "/metrics" {
+        None //probes and scrapers usually have no credentials
+    } else {
+        app.config.server.auth.as_ref()
+    };
+    if let Some(auth) = auth {
+        if !is_authorized(req, auth) {
+            return Response::basic_http_auth_login_required(env!("CARGO_PKG_NAME"));
+        }
+    }
+    let res = rouille::match_assets(req, DEFAULT_ASSETS_DIR);
+    if res.is_success() {
+        return res;
+    }
+    // match included asset
+    let url = &req.url();
+    let mut maybe_file = url.split('/').skip(1); //skip root /
+    if let Some(filename) = maybe_file.next() {
+        if let Some(asset) = assets::get(filename) {
+            let res = Response::from_data(asset.content_type, asset.content)
+                .with_public_cache(CACHE_STATIC_ASSET);
             return res;
         }
-        // match included asset
-        let url = &req.url();
-        let mut maybe_file = url.split('/').skip(1); //skip root /
-        if let Some(filename) = maybe_file.next() {
-            if let Some(asset) = assets::get(filename) {
-                let res = Response::from_data(asset.content_type, asset.content)
-                    .with_public_cache(CACHE_STATIC_ASSET);
-                return res;
-            }
+    }
+
+    if let Some(res) = route_nested_book(req, app, fts) {
+        return res;
+    }
+    router!(req,
+        (GET) (/about) => { Response::json::<AppInfo>(&APP_INFO) },
+        (GET) (/index/info) => { handler_index_info(req, app, fts) },
+        (GET) (/index/stats) => { respond(req, handler_index_stats(fts)) },
+        (GET) (/metrics) => { handler_metrics(fts) },
+        (GET) (/health) => { handler_health(app, fts) },
+        (GET) (/search) => { handler_search(req, app, fts) },
+        (GET) (/facet) => { handler_facet(req, app, fts) },
+        (GET) (/genre/translation) => { Response::json(&genre_map.translation) },
+        (GET) (/authors/unmatched) => { respond(req, handler_authors_unmatched(author_map, fts)) },
+        (GET) (/book/{zipfile: String}/{filename: String}/render) => { respond(req, handler_render(req, fts, app, &zipfile, &filename)) },
+        (GET) (/book/{zipfile: String}/{filename: String}/find) => { respond(req, handler_find(req, fts, app, &zipfile, &filename)) },
+        (GET) (/book/{zipfile: String}/{filename: String}/image/{id: String}) => { respond(req, handler_image(req, app, &zipfile, &filename, &id)) },
+        (GET) (/book/{zipfile: String}/{filename: String}/zip) => { respond(req, handler_file_zip(req, app, &zipfile, &filename)) },
+        (GET) (/book/{zipfile: String}/{filename: String}) => { respond(req, handler_file(req, app, &zipfile, &filename)) },
+        (GET) (/book/{zipfile: String}/{filename: String}/{_saveas: String}) => { respond(req, handler_file(req, app, &zipfile, &filename)) },
+        (GET) (/opensearch) => { respond(req, handler_opensearch_xml(req, app)) },
+        (GET) (/file_list) => { handler_file_list(req, fts) },
+        (GET) (/opds) => { respond(req, opds_root(req, app, fts)) },
+        (GET) (/opds/search/{query: String}) => { respond(req, opds_search_where(req, app, &query)) },
+        (GET) (/opds/search/{query: String}/) => { respond(req, opds_search_where(req, app, &query)) },
+        (GET) (/opds/search/{field: String}/{query: String}/{page: usize}) => {
+            let query = format!("{field}:{query}");
+            let order = match field.as_str() {
+                "sequence" => "sequence",
+                _ => "default"
+            };
+            respond(req, opds_search_books(req, app, &query, order, page, &genre_map.translation, fts))
+        },
+        (GET) (/opds/author) => { respond(req, opds_facet(req, app, "author", None, "Авторы", None, fts)) },
+        (GET) (/opds/author/{prefix: String}) => { respond(req, opds_facet(req, app, "author", Some(&prefix), "Авторы", None, fts)) },
+        (GET) (/opds/author/{prefix: String}/{name: String}) => {
+            let prefix = format!("{prefix}/{name}");
+            respond(req, opds_facet(req, app, "author", Some(&prefix), "Авторы", None, fts))
+        },
+        (GET) (/opds/author/{prefix: String}/{name: String}/{page: usize}) => {
+            let query = format!("facet:/author/{prefix}/{name}");
+            respond(req, opds_search_books(req, app, &query, "title", page, &genre_map.translation, fts))
+        },
+        (GET) (/opds/author/{prefix: String}/{name: String}/{full_name: String}/{page: usize}) => {
+            let query = format!("facet:\"/author/{prefix}/{name}/{full_name}\"");
+            respond(req, opds_search_books(req, app, &query, "title", page, &genre_map.translation, fts))
+        },
+        (GET) (/opds/genre) => { respond(req, opds_facet(req, app, "genre", None, "Жанры", Some(&genre_map.translation), fts)) },
+        (GET) (/opds/genre/{prefix: String}) => { respond(req, opds_facet(req, app, "genre", Some(&prefix), "Жанры", Some(&genre_map.translation), fts)) },
+        (GET) (/opds/genre/{cat: String}/{code: String}/{page: usize}) => {
+            let query = format!("facet:/genre/{cat}/{code}");
+            respond(req, opds_search_books(req, app, &query, "title", page, &genre_map.translation, fts))
+        },
+        (POST) (/admin/remove) => { respond(req, handler_admin_remove(req, app)) },
+        (POST) (/admin/reindex) => { respond(req, handler_admin_reindex(req, app)) },
+        (GET) (/admin/reindex/{id: u64}) => { respond(req, handler_admin_reindex_status(id)) },
+        _ =>  Response::empty_404() ,
+    )
+}
+
+/// /book/{zipfile}/{nested.zip}/{filename}/..., handlers get "nested.zip/filename" as file name
+fn route_nested_book(req: &Request, app: &Application, fts: &BookReader) -> Option<Response> {
+    if req.method() != "GET" {
+        return None;
+    }
+    let url = req.url();
+    let parts: Vec<&str> = url.trim_start_matches('/').split('/').collect();
+    let (zipfile, filename, action) = match parts.as_slice() {
+        ["book", zipfile, nested, name, action @ ..] if container::is_nested_archive(nested) => {
+            (*zipfile, format!("{nested}/{name}"), action)
+        }
+        _ => return None,
+    };
+    let res = match action {
+        ["render"] => handler_render(req, fts, app, zipfile, &filename),
+        ["find"] => handler_find(req, fts, app, zipfile, &filename),
+        ["image", id] => handler_image(req, app, zipfile, &filename, id),
+        ["zip"] => handler_file_zip(req, app, zipfile, &filename),
+        [] | [_] => handler_file(req, app, zipfile, &filename), //optional name to save as
+        _ => return Some(Response::empty_404()),
+    };
+    Some(respond(req, res))
+}
+
+/// body size, if known in advance
+fn response_size(res: &mut Response) -> Option<usize> {
+    let body = std::mem::replace(&mut res.data, ResponseBody::empty());
+    let (reader, size) = body.into_reader_and_size();
+    res.data = match size {
+        Some(size) => ResponseBody::from_reader_and_size(reader, size),
+        None => ResponseBody::from_reader(reader),
+    };
+    size
+}
+
+fn is_authorized(req: &Request, auth: &AuthConfig) -> bool {
+    match rouille::input::basic_http_auth(req) {
+        Some(x) => {
+            //check both to not reveal which one is wrong by response time
+            let login = constant_time_eq(x.login.as_bytes(), auth.user.as_bytes());
+            let password = constant_time_eq(x.password.as_bytes(), auth.password.as_bytes());
+            login & password
         }
+        None => false,
+    }
+}
 
-        router!(req,
-            (GET) (/about) => { Response::json::<AppInfo>(&APP_INFO) },
-            (GET) (/index/info) => { handler_index_info(req, &app, &fts) },
-            (GET) (/search) => { handler_search(req, &fts) },
-            (GET) (/facet) => { handler_facet(req, &fts) },
-            (GET) (/genre/translation) => { Response::json(&genre_map.translation) },
-            (GET) (/book/{zipfile: String}/{filename: String}/render) => { handler_render(req, &fts, &app, &zipfile, &filename) },
-            (GET) (/book/{zipfile: String}/{filename: String}) => { handler_file(req, &app, &zipfile, &filename) },
-            (GET) (/book/{zipfile: String}/{filename: String}/{_saveas: String}) => { handler_file(req, &app, &zipfile, &filename) },
-            (GET) (/opensearch) => { handler_opensearch_xml(req) },
-            (GET) (/file_list) => { handler_file_list(req, &fts) },
-            (GET) (/opds) => { opds_root(req, &fts) },
-            (GET) (/opds/search/{query: String}) => { opds_search_where(req, &query) },
-            (GET) (/opds/search/{query: String}/) => { opds_search_where(req, &query) },
-            (GET) (/opds/search/{field: String}/{query: String}/{page: usize}) => {
-                let query = format!("{field}:{query}");
-                let order = match field.as_str() {
-                    "sequence" => "sequence",
-                    _ => "default"
-                };
-                opds_search_books(req, &query, order, page, &genre_map.translation, &fts)
-            },
-            (GET) (/opds/author) => { opds_facet(req, "author", None, "Авторы", None, &fts) },
-            (GET) (/opds/author/{prefix: String}) => { opds_facet(req, "author", Some(&prefix), "Авторы", None, &fts) },
-            (GET) (/opds/author/{prefix: String}/{name: String}/{page: usize}) => {
-                let query = format!("facet:/author/{prefix}/{name}");
-                opds_search_books(req, &query, "title", page, &genre_map.translation, &fts)
-            },
-            (GET) (/opds/genre) => { opds_facet(req, "genre", None, "Жанры", Some(&genre_map.translation), &fts) },
-            (GET) (/opds/genre/{prefix: String}) => { opds_facet(req, "genre", Some(&prefix), "Жанры", Some(&genre_map.translation), &fts) },
-            (GET) (/opds/genre/{cat: String}/{code: String}/{page: usize}) => {
-                let query = format!("facet:/genre/{cat}/{code}");
-                opds_search_books(req, &query, "title", page, &genre_map.translation, &fts)
-            },
-            _ =>  Response::empty_404() ,
-        )
-    });
+/// comparison time depends only on length of expected value
+fn constant_time_eq(given: &[u8], expected: &[u8]) -> bool {
+    let mut diff = (given.len() != expected.len()) as u8;
+    for (i, x) in expected.iter().enumerate() {
+        diff |= x ^ given.get(i).copied().unwrap_or_default();
+    }
+    diff == 0
+}
+
+/// gzip or brotli encoding of text response, if client accepts it and response is large enough
+fn compress(req: &Request, mut res: Response, min_size: usize) -> Response {
+    if res.status_code != 200 {
+        return res; //do not touch partial content and empty responses
+    }
+    let size = response_size(&mut res);
+    if size.is_some_and(|x| x < min_size) {
+        return res;
+    }
+    let mut res = rouille::content_encoding::apply(req, res);
+    if res
+        .headers
+        .iter()
+        .any(|(k, _)| k.eq_ignore_ascii_case("Content-Encoding"))
+    {
+        res.headers.push(("Vary".into(), "Accept-Encoding".into()));
+        //compressed content is not byte-identical to original one
+        for (k, v) in res.headers.iter_mut() {
+            if k.eq_ignore_ascii_case("ETag") && !v.starts_with("W/") {
+                *v = format!("W/{v}").into();
+            }
+        }
+    }
+    res
 }
 
 fn urlenc(s: &str) -> String {
     utf8_percent_encode(s, NON_ALPHANUMERIC).to_string()
 }
 
-fn root_url(req: &Request) -> String {
-    let host = match req.header("X-Forwarded-Host") {
+/// error of request handler, mapped to HTTP status
+#[derive(Debug)]
+enum HandlerError {
+    BadRequest(String),
+    NotFound(String),
+    Internal(String),
+}
+
+type HandlerResult = Result<Response, HandlerError>;
+
+impl HandlerError {
+    fn status_code(&self) -> u16 {
+        match self {
+            HandlerError::BadRequest(_) => 400,
+            HandlerError::NotFound(_) => 404,
+            HandlerError::Internal(_) => 500,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            HandlerError::BadRequest(x) | HandlerError::NotFound(x) | HandlerError::Internal(x) => {
+                x
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorInfo<'a> {
+    status: u16,
+    error: &'a str,
+}
+
+/// handler result -> response; error is reported as HTML page to browser, as JSON to others
+fn respond(req: &Request, res: HandlerResult) -> Response {
+    let e = match res {
+        Ok(x) => return x,
+        Err(e) => e,
+    };
+    let status = e.status_code();
+    if status >= 500 {
+        warn!("{} {}: {}", req.method(), req.raw_url(), e.message());
+    } else {
+        debug!("{} {}: {}", req.method(), req.raw_url(), e.message());
+    }
+    let want_html = req
+        .header("Accept")
+        .is_some_and(|x| x.contains("text/html"));
+    let res = if want_html {
+        Response::html(format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\" /><title>{status}</title></head><body><h1>{status}</h1><p>{}</p></body></html>",
+            quick_xml::escape::escape(e.message())
+        ))
+    } else {
+        Response::json(&ErrorInfo {
+            status,
+            error: e.message(),
+        })
+    };
+    res.with_status_code(status).with_no_cache()
+}
+
+fn root_url(req: &Request) -> Result<String, HandlerError> {
+    let host = match req
+        .header("X-Forwarded-Host")
+        .or_else(|| req.header("Host"))
+    {
         Some(s) => s,
-        None => req.header("Host").expect("Unknown server host"),
+        None => {
+            let msg = tr!["Unknown server host", "Не указан адрес сервера"];
+            return Err(HandlerError::BadRequest(msg.to_string()));
+        }
     };
     let proto = match req.header("X-Forwarded-Proto") {
         Some(s) => s,
@@ -139,12 +398,26 @@ fn root_url(req: &Request) -> String {
             }
         }
     };
-    format!("{proto}://{host}")
+    Ok(format!("{proto}://{host}"))
 }
 
 // Request -> ("http://server:port", "/prefix/path")
-fn split_request_url(req: &Request) -> (String, String) {
-    (root_url(req), format!("/porcula{}", req.url()))
+fn split_request_url(req: &Request, base: &str) -> Result<(String, String), HandlerError> {
+    Ok((root_url(req)?, format!("{base}{}", req.url())))
+}
+
+fn get_book_format<'a>(
+    app: &'a Application,
+    filename: &str,
+) -> Result<&'a (dyn BookFormat + Send + Sync), HandlerError> {
+    let ext = file_extension(filename);
+    match app.book_formats.get(ext.as_str()) {
+        Some(x) => Ok(x.as_ref()),
+        None => Err(HandlerError::NotFound(format!(
+            "{}: {filename}",
+            tr!["Unknown book format", "Неизвестный формат книги"]
+        ))),
+    }
 }
 
 fn handler_index_info(_req: &Request, app: &Application, fts: &BookReader) -> Response {
@@ -160,7 +433,75 @@ fn handler_index_info(_req: &Request, app: &Application, fts: &BookReader) -> Re
     }
 }
 
-fn handler_search(req: &Request, fts: &BookReader) -> Response {
+fn handler_index_stats(fts: &BookReader) -> HandlerResult {
+    match fts.get_stats() {
+        Ok(stats) => Ok(Response::json(&stats).with_no_cache()),
+        Err(e) => Err(HandlerError::Internal(e.to_string())),
+    }
+}
+
+/// candidates for author map: indexed name variants of same last name
+fn handler_authors_unmatched(author_map: &AuthorMap, fts: &BookReader) -> HandlerResult {
+    let counts = fts
+        .get_author_counts()
+        .map_err(|e| HandlerError::Internal(e.to_string()))?;
+    Ok(Response::json(&author_map.unmatched(&counts)).with_no_cache())
+}
+
+fn handler_metrics(fts: &BookReader) -> Response {
+    let index = IndexGauges {
+        doc_count: fts.count_all().ok(),
+        segment_count: fts.segment_count(),
+        last_reload: fts
+            .last_reload()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+    };
+    Response::from_data("text/plain; version=0.0.4", METRICS.render(&index)).with_no_cache()
+}
+
+#[derive(Serialize)]
+struct HealthInfo {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// index directory and its meta file must be readable, and index must answer queries
+fn check_health(app: &Application, fts: &BookReader) -> Result<(), String> {
+    std::fs::read_dir(&app.index_path).map_err(|e| format!("{}: {e}", app.index_path.display()))?;
+    let meta = app.index_path.join("meta.json");
+    std::fs::File::open(&meta).map_err(|e| format!("{}: {e}", meta.display()))?;
+    fts.count_all().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn handler_health(app: &Application, fts: &BookReader) -> Response {
+    let (status_code, info) = match check_health(app, fts) {
+        Ok(()) => (
+            200,
+            HealthInfo {
+                status: "ok",
+                error: None,
+            },
+        ),
+        Err(e) => {
+            warn!("health check: {e}");
+            (
+                503,
+                HealthInfo {
+                    status: "error",
+                    error: Some(e),
+                },
+            )
+        }
+    };
+    Response::json(&info)
+        .with_status_code(status_code)
+        .with_no_cache()
+}
+
+fn handler_search(req: &Request, app: &Application, fts: &BookReader) -> Response {
     match req.get_param("query") {
         Some(query) => {
             let stemming = req.get_param("stemming").unwrap_or_default() == "1";
@@ -169,7 +510,7 @@ fn handler_search(req: &Request, fts: &BookReader) -> Response {
                 .get_param("page_size")
                 .unwrap_or_default()
                 .parse()
-                .unwrap_or(DEFAULT_QUERY_HITS);
+                .unwrap_or(app.config.pages.query_hits);
             let page: usize = req
                 .get_param("page")
                 .unwrap_or_default()
@@ -180,7 +521,10 @@ fn handler_search(req: &Request, fts: &BookReader) -> Response {
                 Some(s) => crate::fts::OrderBy::from_str(&s).unwrap_or_default(),
                 None => crate::fts::OrderBy::default(),
             };
-            match fts.search_as_json(&query, stemming, disjunction, orderby, limit, offset) {
+            let started = Instant::now();
+            let found = fts.search_as_json(&query, stemming, disjunction, orderby, limit, offset);
+            METRICS.observe_search(started.elapsed());
+            match found {
                 Ok(json) => Response::from_data("application/json", json).with_no_cache(),
                 Err(e) => Response::text(e.to_string()).with_status_code(500),
             }
@@ -189,10 +533,10 @@ fn handler_search(req: &Request, fts: &BookReader) -> Response {
     }
 }
 
-fn handler_facet(req: &Request, fts: &BookReader) -> Response {
+fn handler_facet(req: &Request, app: &Application, fts: &BookReader) -> Response {
     let hits: Option<usize> = req
         .get_param("hits")
-        .map(|x| x.parse().unwrap_or(DEFAULT_QUERY_HITS));
+        .map(|x| x.parse().unwrap_or(app.config.pages.query_hits));
     let req_query = req.get_param("query");
     let opt_query = match req_query {
         Some(ref s) if !s.is_empty() => Some(s.as_str()),
@@ -218,95 +562,655 @@ fn handler_file_list(_req: &Request, fts: &BookReader) -> Response {
 }
 
 fn handler_render(
-    _req: &Request,
+    req: &Request,
     _fts: &BookReader,
     app: &Application,
     zipfile: &str,
     filename: &str,
-) -> Response {
-    let ext = file_extension(filename);
-    match app.book_formats.get(&ext.as_ref()) {
-        Some(book_format) => {
-            let raw = read_zipped_file(&app.books_path, zipfile, filename);
-            let (title, content) = book_format.render_to_html(&raw).unwrap(); //result is Vec<u8> but valid UTF-8
-            const TEMPLATE: &str = "render.html";
-            const TEMPLATE_SIZE: usize = 1000; //approximate
-            let template = Path::new(DEFAULT_ASSETS_DIR).join(TEMPLATE);
-            let mut html = String::with_capacity(content.len() + title.len() + TEMPLATE_SIZE);
-            let mut buf = String::new();
-            //read template from static file or load internal asset
-            let tmpl: &str = if let Ok(mut f) = std::fs::File::open(template) {
-                f.read_to_string(&mut buf).unwrap();
-                &buf
-            } else {
-                let raw = assets::get(TEMPLATE)
-                    .expect("render template not found")
-                    .content;
-                str::from_utf8(raw).unwrap()
-            };
-            let mut start = 0;
-            let substr = "{title}";
-            if let Some(found) = tmpl.find(substr) {
-                html.push_str(&tmpl[start..found]);
-                html.push_str(&title);
-                start = found + substr.len();
+) -> HandlerResult {
+    let book_format = get_book_format(app, filename)?;
+    let variant = format!("render?{}", req.raw_query_string());
+    let v = entry_validators(&app.books_path, zipfile, filename, &variant);
+    if let Some(v) = v.as_ref().filter(|v| is_not_modified(req, v)) {
+        return Ok(not_modified_response(v));
+    }
+    let raw = read_zipped_file(&app.books_path, zipfile, filename)?;
+    //?chapter=N -> one chapter of book with table of contents and images as separate URLs
+    let (title, content, toc) = match req.get_param("chapter") {
+        Some(chapter) => {
+            let chapter: usize = chapter.parse().map_err(|_| {
+                HandlerError::BadRequest(format!(
+                    "{}: {chapter}",
+                    tr!["Invalid chapter", "Неверный номер главы"]
+                ))
+            })?;
+            let mut book = book_format
+                .render_chapters(&raw, "image/")
+                .map_err(HandlerError::Internal)?;
+            if chapter >= book.content.len() {
+                return Err(HandlerError::NotFound(format!(
+                    "{}: {chapter}",
+                    tr!["Chapter not found", "Глава не найдена"]
+                )));
             }
-            let substr = "{content}";
-            if let Some(found) = tmpl.find(substr) {
-                html.push_str(&tmpl[start..found]);
-                html.push_str(&content);
-                start = found + substr.len();
+            let find = req
+                .get_param("find")
+                .map(|f| format!("&find={}", utf8_percent_encode(&f, NON_ALPHANUMERIC)))
+                .unwrap_or_default();
+            let nav = chapter_nav(chapter, book.content.len(), &find);
+            let content = format!("{nav}{}{nav}", book.content.swap_remove(chapter));
+            let mut toc = String::from("<ul class=\"chapters\">");
+            for (i, t) in book.toc.iter().enumerate() {
+                let t = if t.is_empty() && i == 0 {
+                    &book.title
+                } else {
+                    t
+                };
+                let t = if t.is_empty() {
+                    (i + 1).to_string()
+                } else {
+                    quick_xml::escape::escape(t).into_owned()
+                };
+                let class = if i == chapter {
+                    " class=\"current\""
+                } else {
+                    ""
+                };
+                toc.push_str(&format!(
+                    "<li{class}><a href=\"?chapter={i}{find}\">{t}</a></li>"
+                ));
             }
-            html.push_str(&tmpl[start..]);
-            Response::from_data("text/html", html).with_public_cache(CACHE_IMMUTABLE)
+            toc.push_str("</ul>");
+            (book.title, content, toc)
         }
-        None => Response::empty_404(),
+        None => {
+            let (title, content) = book_format
+                .render_to_html(&raw)
+                .map_err(HandlerError::Internal)?;
+            (title, content, String::new())
+        }
+    };
+    const TEMPLATE: &str = "render.html";
+    const TEMPLATE_SIZE: usize = 1000; //approximate
+    let template = Path::new(DEFAULT_ASSETS_DIR).join(TEMPLATE);
+    let mut html = String::with_capacity(content.len() + toc.len() + title.len() + TEMPLATE_SIZE);
+    let mut buf = String::new();
+    //read template from static file or load internal asset
+    let tmpl: &str = if let Ok(mut f) = std::fs::File::open(template) {
+        f.read_to_string(&mut buf)
+            .map_err(|e| HandlerError::Internal(format!("{TEMPLATE}: {e}")))?;
+        &buf
+    } else {
+        match assets::get(TEMPLATE).map(|x| str::from_utf8(x.content)) {
+            Some(Ok(x)) => x,
+            _ => {
+                let msg = tr!["Render template not found", "Не найден шаблон книги"];
+                return Err(HandlerError::Internal(format!("{msg}: {TEMPLATE}")));
+            }
+        }
+    };
+    //relative links of template for book in nested archive or directory
+    let depth = filename.matches('/').count();
+    let tmpl = match depth {
+        0 => std::borrow::Cow::Borrowed(tmpl),
+        _ => tmpl.replace("../../../", &"../".repeat(3 + depth)).into(),
+    };
+    //placeholders in order of appearance in template
+    let mut start = 0;
+    for (substr, value) in [
+        ("{title}", &title),
+        ("{content}", &content),
+        ("{toc}", &toc),
+    ] {
+        if let Some(found) = tmpl[start..].find(substr) {
+            html.push_str(&tmpl[start..start + found]);
+            html.push_str(value);
+            start += found + substr.len();
+        }
+    }
+    html.push_str(&tmpl[start..]);
+    let res = Response::from_data("text/html", html).with_public_cache(CACHE_IMMUTABLE);
+    Ok(match &v {
+        Some(v) => with_validators(res, v),
+        None => res,
+    })
+}
+
+/// links to previous and next chapters
+fn chapter_nav(chapter: usize, count: usize, find: &str) -> String {
+    let mut nav = String::from("<div class=\"chapter_nav\">");
+    if chapter > 0 {
+        let prev = chapter - 1;
+        nav.push_str(&format!(
+            "<a class=\"prev\" href=\"?chapter={prev}{find}\">&lt;&lt;</a>"
+        ));
+    }
+    nav.push_str(&format!("<span>{} / {count}</span>", chapter + 1));
+    if chapter + 1 < count {
+        let next = chapter + 1;
+        nav.push_str(&format!(
+            "<a class=\"next\" href=\"?chapter={next}{find}\">&gt;&gt;</a>"
+        ));
     }
+    nav.push_str("</div>");
+    nav
 }
 
-fn handler_file(_req: &Request, app: &Application, zipfile: &str, filename: &str) -> Response {
-    match app.book_formats.get(&file_extension(filename).as_ref()) {
-        Some(book_format) => {
-            let content = read_zipped_file(&app.books_path, zipfile, filename);
-            Response::from_data(book_format.content_type(), content)
-                .with_public_cache(CACHE_IMMUTABLE)
+fn handler_image(
+    req: &Request,
+    app: &Application,
+    zipfile: &str,
+    filename: &str,
+    id: &str,
+) -> HandlerResult {
+    let book_format = get_book_format(app, filename)?;
+    let variant = format!("image/{id}");
+    let v = entry_validators(&app.books_path, zipfile, filename, &variant);
+    if let Some(v) = v.as_ref().filter(|v| is_not_modified(req, v)) {
+        return Ok(not_modified_response(v));
+    }
+    let raw = read_zipped_file(&app.books_path, zipfile, filename)?;
+    match book_format.image(&raw, id) {
+        Some((content_type, data)) => {
+            let content_type = if content_type.is_empty() {
+                "application/octet-stream".to_string()
+            } else {
+                content_type
+            };
+            let res = Response::from_data(content_type, data).with_public_cache(CACHE_IMMUTABLE);
+            Ok(match &v {
+                Some(v) => with_validators(res, v),
+                None => res,
+            })
         }
-        None => Response::empty_404(),
+        None => Err(HandlerError::NotFound(format!(
+            "{}: {id}",
+            tr!["Image not found", "Картинка не найдена"]
+        ))),
     }
 }
 
-fn handler_opensearch_xml(req: &Request) -> Response {
+#[derive(Serialize)]
+struct FoundInBook {
+    total: usize,
+    matches: Vec<crate::fts::FoundText>,
+}
+
+fn handler_find(
+    req: &Request,
+    fts: &BookReader,
+    app: &Application,
+    zipfile: &str,
+    filename: &str,
+) -> HandlerResult {
+    let query = match req.get_param("q") {
+        Some(q) if !q.trim().is_empty() => q,
+        _ => {
+            let msg = tr!["Empty query", "Пустой запрос"];
+            return Err(HandlerError::BadRequest(msg.to_string()));
+        }
+    };
+    let stemming = req.get_param("stemming").unwrap_or_default() != "0";
+    let hits: usize = req
+        .get_param("hits")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(app.config.pages.find_hits);
+    let book_format = get_book_format(app, filename)?;
+    let raw = read_zipped_file(&app.books_path, zipfile, filename)?;
+    let blocks = book_format
+        .text_blocks(&raw)
+        .map_err(HandlerError::Internal)?;
+    let (total, matches) = fts
+        .find_in_text(&blocks, &query, stemming, hits)
+        .map_err(|e| HandlerError::Internal(e.to_string()))?;
+    Ok(Response::json(&FoundInBook { total, matches }).with_no_cache())
+}
+
+fn handler_file(req: &Request, app: &Application, zipfile: &str, filename: &str) -> HandlerResult {
+    let book_format = get_book_format(app, filename)?;
+    let v = entry_validators(&app.books_path, zipfile, filename, "");
+    if let Some(v) = v.as_ref().filter(|v| is_not_modified(req, v)) {
+        return Ok(not_modified_response(v));
+    }
+    let content = read_zipped_file(&app.books_path, zipfile, filename)?;
+    let content_type = book_format.content_type().to_string();
+    Ok(ranged_response(req, v.as_ref(), content_type, content))
+}
+
+/// book recompressed into single-entry zip, named "Author - Title (Sequence N).fb2"
+fn handler_file_zip(
+    req: &Request,
+    app: &Application,
+    zipfile: &str,
+    filename: &str,
+) -> HandlerResult {
+    let book_format = get_book_format(app, filename)?;
+    let v = entry_validators(&app.books_path, zipfile, filename, "zip");
+    if let Some(v) = v.as_ref().filter(|v| is_not_modified(req, v)) {
+        return Ok(not_modified_response(v));
+    }
+    let content = read_zipped_file(&app.books_path, zipfile, filename)?;
+    let name = match book_format.parse(&content, false, false, false) {
+        Ok(book) => book_file_name(&book, book_format.file_extension()),
+        Err(_) => filename.to_string(),
+    };
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    //fixed timestamp: same content for every request, so ranges of it can be resumed
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .last_modified_time(zip::DateTime::default());
+    let cursor = zip
+        .start_file(name.as_str(), options)
+        .and_then(|_| Ok(zip.write_all(&content)?))
+        .and_then(|_| zip.finish())
+        .map_err(|e| HandlerError::Internal(e.to_string()))?;
+    let content_type = format!("{}+zip", book_format.content_type());
+    Ok(
+        ranged_response(req, v.as_ref(), content_type, cursor.into_inner())
+            .with_additional_header("Content-Disposition", content_disposition(&name, ".zip")),
+    )
+}
+
+/// characters allowed in RFC 5987 ext-value without encoding
+const RFC5987_ATTR_CHAR: &percent_encoding::AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+const MAX_FILE_NAME_CHARS: usize = 120;
+
+/// "Author - Title (Sequence N).ext" without characters forbidden in file names
+fn book_file_name(book: &crate::types::Book, ext: &str) -> String {
+    let mut name = String::new();
+    if let Some(author) = book.author.first() {
+        name.push_str(&author.to_string());
+        name.push_str(" - ");
+    }
+    name.push_str(book.title.first().map(|x| x.as_str()).unwrap_or_default());
+    if let Some(sequence) = book.sequence.first() {
+        match book.seqnum.first() {
+            Some(n) if *n > 0 => name.push_str(&format!(" ({sequence} {n})")),
+            _ => name.push_str(&format!(" ({sequence})")),
+        }
+    }
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_FILE_NAME_CHARS)
+        .collect();
+    format!("{}{ext}", name.trim())
+}
+
+/// attachment with UTF-8 name (RFC 5987) and transliterated ASCII name for old clients
+fn content_disposition(name: &str, suffix: &str) -> String {
+    let ascii = crate::translit::transliterate(name).replace('"', "'");
+    format!(
+        "attachment; filename=\"{ascii}{suffix}\"; filename*=UTF-8''{}{suffix}",
+        utf8_percent_encode(name, RFC5987_ATTR_CHAR)
+    )
+}
+
+fn handler_opensearch_xml(req: &Request, app: &Application) -> HandlerResult {
+    let root = root_url(req)?;
+    let base = &app.config.server.base_url;
+    //"any" language -> "*" as defined by OpenSearch spec
+    let langs = app
+        .index_settings
+        .langs
+        .iter()
+        .map(|x| match x.to_lowercase().as_str() {
+            "any" => "*".to_string(),
+            x => x.to_string(),
+        })
+        .map(|x| format!("      <Language>{x}</Language>\n"))
+        .collect::<String>();
     let content = format!(
         r#"<?xml version="1.0" encoding="UTF-8"?>
     <OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
       <ShortName>Porcula</ShortName>
       <Description>Library search</Description>
-      <Url type="text/html" template="{}/porcula/home.html?query={{searchTerms}}"/>  
-      <Language>ru-RU</Language>
-      <OutputEncoding>UTF-8</OutputEncoding>
+      <Url type="text/html" template="{root}{base}/home.html?query={{searchTerms}}"/>
+      <Url type="{OPDS_CATALOG_MIME_TYPE}" template="{root}{base}/opds/search/{{searchTerms}}"/>
+{langs}      <OutputEncoding>UTF-8</OutputEncoding>
       <InputEncoding>UTF-8</InputEncoding>
-    </OpenSearchDescription>"#,
-        root_url(req)
+    </OpenSearchDescription>"#
     );
-    Response::from_data("application/xml", content)
+    Ok(Response::from_data(OPENSEARCH_MIME_TYPE, content))
+}
+
+#[derive(Deserialize)]
+struct AdminRequest {
+    files: Vec<String>,
+}
+
+fn admin_files(req: &Request) -> Result<Vec<String>, HandlerError> {
+    let files = rouille::input::json_input::<AdminRequest>(req)
+        .map_err(|e| HandlerError::BadRequest(e.to_string()))?
+        .files;
+    if files.is_empty() {
+        return Err(HandlerError::BadRequest(
+            tr!["Empty file list", "Пустой список файлов"].to_string(),
+        ));
+    }
+    Ok(files)
+}
+
+/// {"files":["a.zip","b.zip/1.fb2"]}
+fn handler_admin_remove(req: &Request, app: &Application) -> HandlerResult {
+    let files = admin_files(req)?;
+    info!("admin remove: {}", files.join(", "));
+    let removed = remove_from_index(app, &files).map_err(HandlerError::Internal)?;
+    Ok(Response::json(&removed).with_no_cache())
+}
+
+const MAX_REINDEX_JOBS: usize = 100; //older finished jobs are forgotten
+
+#[derive(Clone, Copy, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Serialize)]
+struct ReindexJob {
+    id: u64,
+    files: Vec<String>,
+    state: JobState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+lazy_static! {
+    static ref REINDEX_JOBS: Mutex<BTreeMap<u64, ReindexJob>> = Mutex::new(BTreeMap::new());
+}
+
+fn reindex_jobs() -> MutexGuard<'static, BTreeMap<u64, ReindexJob>> {
+    REINDEX_JOBS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// {"files":["a.zip"]}, same as 'index --file', indexing runs in background, status is at Location
+fn handler_admin_reindex(req: &Request, app: &Arc<Application>) -> HandlerResult {
+    let files = admin_files(req)?;
+    for zipfile in &files {
+        if !archive_path(&app.books_path, zipfile)?.is_file() {
+            return Err(HandlerError::NotFound(format!(
+                "{}: {zipfile}",
+                tr!["Archive not found", "Архив не найден"]
+            )));
+        }
+    }
+    let job = {
+        let mut jobs = reindex_jobs();
+        let id = jobs.keys().next_back().map_or(1, |x| x + 1);
+        while jobs.len() >= MAX_REINDEX_JOBS {
+            match jobs.values().find(|x| x.state != JobState::Running) {
+                Some(x) => {
+                    let old = x.id;
+                    jobs.remove(&old);
+                }
+                None => break,
+            }
+        }
+        let job = ReindexJob {
+            id,
+            files: files.clone(),
+            state: JobState::Running,
+            error: None,
+        };
+        jobs.insert(id, job.clone());
+        job
+    };
+    info!("admin reindex {}: {}", job.id, files.join(", "));
+    let id = job.id;
+    let location = format!("{}/admin/reindex/{id}", app.config.server.base_url);
+    let app = app.clone();
+    //writer lock is taken by index_books, jobs wait for each other
+    std::thread::spawn(move || {
+        let canceled = Arc::new(AtomicBool::new(false));
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            index_books(&IndexArgs::delta(files), &app, canceled).into_result()
+        }))
+        .unwrap_or_else(|_| Err(tr!["indexing panicked", "сбой индексации"].to_string()));
+        if let Err(e) = &res {
+            error!("admin reindex {id}: {e}");
+        }
+        if let Some(job) = reindex_jobs().get_mut(&id) {
+            job.state = match res {
+                Ok(_) => JobState::Done,
+                Err(_) => JobState::Failed,
+            };
+            job.error = res.err();
+        }
+    });
+    Ok(Response::json(&job)
+        .with_status_code(202)
+        .with_additional_header("Location", location)
+        .with_no_cache())
+}
+
+fn handler_admin_reindex_status(id: u64) -> HandlerResult {
+    match reindex_jobs().get(&id) {
+        Some(job) => Ok(Response::json(job).with_no_cache()),
+        None => Err(HandlerError::NotFound(format!(
+            "{}: {id}",
+            tr!["Unknown job", "Неизвестное задание"]
+        ))),
+    }
+}
+
+/// path of archive inside books dir, "../" is not allowed
+fn archive_path(books_path: &Path, zipfile: &str) -> Result<PathBuf, HandlerError> {
+    let path = Path::new(zipfile);
+    if path
+        .components()
+        .all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        Ok(books_path.join(path))
+    } else {
+        Err(HandlerError::BadRequest(format!(
+            "{}: {zipfile}",
+            tr!["Invalid archive name", "Неверное имя архива"]
+        )))
+    }
 }
 
-pub fn read_zipped_file(books_path: &Path, zipfile: &str, filename: &str) -> Vec<u8> {
-    let zip_path = books_path.join(zipfile);
-    let reader = std::fs::File::open(zip_path).unwrap();
-    let buffered = std::io::BufReader::new(reader);
-    let mut zip = zip::ZipArchive::new(buffered).unwrap();
-    let mut file = zip.by_name(filename).unwrap();
-    let mut content = vec![];
-    file.read_to_end(&mut content).unwrap();
-    content
+fn read_zipped_file(
+    books_path: &Path,
+    zipfile: &str,
+    filename: &str,
+) -> Result<Vec<u8>, HandlerError> {
+    let zip_path = archive_path(books_path, zipfile)?;
+    let mut container = container::open(&zip_path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => HandlerError::NotFound(format!(
+            "{}: {zipfile}",
+            tr!["Archive not found", "Архив не найден"]
+        )),
+        _ => HandlerError::Internal(format!("{zipfile}: {e}")),
+    })?;
+    let internal = |e: std::io::Error| HandlerError::Internal(format!("{zipfile}/{filename}: {e}"));
+    let not_found = || {
+        HandlerError::NotFound(format!(
+            "{}: {zipfile}/{filename}",
+            tr!["Book not found", "Книга не найдена"]
+        ))
+    };
+    if let Some(entry) = container.find(filename).map_err(internal)? {
+        return container.read(entry.index).map_err(internal);
+    }
+    //book inside nested zip
+    let (nested, name) = container::split_nested(filename).ok_or_else(not_found)?;
+    let entry = container
+        .find(nested)
+        .map_err(internal)?
+        .ok_or_else(not_found)?;
+    let data = container.read(entry.index).map_err(internal)?;
+    match container::unpack_nested(&data).map_err(internal)? {
+        Some((inner, content)) if inner == name => Ok(content),
+        _ => Err(not_found()),
+    }
 }
 
-fn atom_mime_type() -> Option<String> {
-    Some("application/atom+xml".to_string())
+/// validators of zipped book for conditional requests
+struct EntryValidators {
+    etag: String, //strong ETag: entry crc32, size and hash of archive name and variant (and mtime without crc32)
+    last_modified: Option<String>, //archive mtime, HTTP-date
 }
+
+/// `variant` distinguishes different representations of one entry (raw file, zip, rendered HTML)
+fn entry_validators(
+    books_path: &Path,
+    zipfile: &str,
+    filename: &str,
+    variant: &str,
+) -> Option<EntryValidators> {
+    let path = archive_path(books_path, zipfile).ok()?;
+    let mtime = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    let mut container = container::open(&path).ok()?;
+    let entry = match container.find(filename).ok()? {
+        Some(x) => x,
+        None => container
+            .find(container::split_nested(filename)?.0)
+            .ok()??,
+    };
+    //stable across builds, unlike DefaultHasher
+    let mut hasher = crc32fast::Hasher::new();
+    for part in [zipfile, filename, variant, APP_INFO.version] {
+        hasher.update(part.as_bytes());
+        hasher.update(&[0]);
+    }
+    if entry.crc32.is_none() {
+        let since_epoch = mtime.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+        hasher.update(&since_epoch.unwrap_or_default().as_nanos().to_le_bytes());
+    }
+    Some(EntryValidators {
+        etag: format!(
+            "\"{:08x}-{:x}-{:08x}\"",
+            entry.crc32.unwrap_or_default(),
+            entry.size,
+            hasher.finalize()
+        ),
+        last_modified: mtime.map(|t| {
+            chrono::DateTime::<chrono::Utc>::from(t)
+                .format(HTTP_DATE_FORMAT)
+                .to_string()
+        }),
+    })
+}
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// check If-None-Match, or If-Modified-Since when there is no If-None-Match
+fn is_not_modified(req: &Request, v: &EntryValidators) -> bool {
+    let hit = if let Some(inm) = req.header("If-None-Match") {
+        inm.split(',')
+            .map(|x| x.trim())
+            .any(|x| x == "*" || x.trim_start_matches("W/") == v.etag)
+    } else {
+        match (req.header("If-Modified-Since"), &v.last_modified) {
+            (Some(since), Some(modified)) => {
+                let since = chrono::DateTime::parse_from_rfc2822(since);
+                let modified = chrono::DateTime::parse_from_rfc2822(modified);
+                matches!((since, modified), (Ok(s), Ok(m)) if m <= s)
+            }
+            _ => false,
+        }
+    };
+    METRICS.observe_cache(hit);
+    hit
+}
+
+fn with_validators(res: Response, v: &EntryValidators) -> Response {
+    let res = res.with_unique_header("ETag", v.etag.clone());
+    match &v.last_modified {
+        Some(x) => res.with_unique_header("Last-Modified", x.clone()),
+        None => res,
+    }
+}
+
+fn not_modified_response(v: &EntryValidators) -> Response {
+    with_validators(Response::text("").with_status_code(304), v).with_public_cache(CACHE_IMMUTABLE)
+}
+
+/// parse single range "bytes=first-last" | "bytes=first-" | "bytes=-suffix", None if not satisfiable
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    let (first, last) = range.split_once('-')?;
+    let (first, last) = match (first.trim(), last.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (first, "") => (first.parse().ok()?, len.checked_sub(1)?),
+        (first, last) => (
+            first.parse().ok()?,
+            last.parse::<usize>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+    if first > last || first >= len {
+        None
+    } else {
+        Some((first, last))
+    }
+}
+
+/// full content or its part requested by Range header
+fn ranged_response(
+    req: &Request,
+    v: Option<&EntryValidators>,
+    content_type: String,
+    content: Vec<u8>,
+) -> Response {
+    let range = match (req.header("Range"), req.header("If-Range"), v) {
+        //multiple ranges are not supported, send whole content
+        (Some(r), _, _) if r.contains(',') => None,
+        //If-Range with outdated validator -> whole content
+        (Some(_), Some(ir), Some(v)) if ir != v.etag && Some(ir) != v.last_modified.as_deref() => {
+            None
+        }
+        (Some(r), _, _) => Some(r),
+        _ => None,
+    };
+    let len = content.len();
+    let res = match range {
+        Some(range) => match parse_range(range, len) {
+            Some((first, last)) => Response::from_data(content_type, &content[first..=last])
+                .with_status_code(206)
+                .with_unique_header("Content-Range", format!("bytes {first}-{last}/{len}")),
+            None => Response::text("")
+                .with_status_code(416)
+                .with_unique_header("Content-Range", format!("bytes */{len}")),
+        },
+        None => Response::from_data(content_type, content),
+    };
+    let res = if res.is_success() {
+        res.with_public_cache(CACHE_IMMUTABLE)
+    } else {
+        res
+    };
+    let res = res.with_unique_header("Accept-Ranges", "bytes");
+    match v {
+        Some(v) => with_validators(res, v),
+        None => res,
+    }
+}
+
 fn atom_cat_mime_type() -> Option<String> {
-    Some("application/atom+xml;profile=opds-catalog".to_string())
+    Some(OPDS_CATALOG_MIME_TYPE.to_string())
 }
 fn atom_nav_mime_type() -> Option<String> {
     Some("application/atom+xml;profile=opds-catalog;kind=navigation".to_string())
@@ -314,13 +1218,14 @@ fn atom_nav_mime_type() -> Option<String> {
 
 fn opds_response(
     title: &str,
+    base: &str,
     root: &str,
     path: &str,
     entries: Vec<Entry>,
     prev_url: Option<String>,
     next_url: Option<String>,
 ) -> Response {
-    let abs_url = format!("{root}/porcula{path}",);
+    let abs_url = format!("{root}{path}");
     let mut ns = BTreeMap::<String, String>::new();
     ns.insert("dcterms".into(), "http://purl.org/dc/terms".into());
 
@@ -331,14 +1236,14 @@ fn opds_response(
             .mime_type(atom_nav_mime_type())
             .build(),
         LinkBuilder::default()
-            .href("/porcula/opds".to_string())
+            .href(format!("{base}/opds"))
             .rel("start".to_string())
             .mime_type(atom_nav_mime_type())
             .build(),
         LinkBuilder::default()
-            .href("/porcula/opds/search/{searchTerms}".to_string())
+            .href(format!("{base}/opensearch"))
             .rel("search".to_string())
-            .mime_type(atom_mime_type())
+            .mime_type(Some(OPENSEARCH_MIME_TYPE.to_string()))
             .build(),
     ];
     if let Some(url) = prev_url {
@@ -376,18 +1281,19 @@ fn opds_response(
     Response::from_data("application/xml", f.to_string())
 }
 
-fn opds_root(req: &Request, fts: &BookReader) -> Response {
-    let (root_url, req_path) = split_request_url(req);
+fn opds_root(req: &Request, app: &Application, fts: &BookReader) -> HandlerResult {
+    let base = &app.config.server.base_url;
+    let (root_url, req_path) = split_request_url(req, base)?;
     let book_count = fts.count_all().unwrap_or(0);
     let mut e = Vec::new();
 
     let links = vec![
         LinkBuilder::default()
-            .href(format!("{root_url}/porcula/opds/author"))
+            .href(format!("{root_url}{base}/opds/author"))
             .rel("alternate".to_string())
             .build(),
         LinkBuilder::default()
-            .href("/porcula/opds/author".to_string())
+            .href(format!("{base}/opds/author"))
             .rel("subsection".to_string())
             .mime_type(atom_nav_mime_type())
             .build(),
@@ -408,11 +1314,11 @@ fn opds_root(req: &Request, fts: &BookReader) -> Response {
 
     let links = vec![
         LinkBuilder::default()
-            .href(format!("{root_url}/porcula/opds/genre"))
+            .href(format!("{root_url}{base}/opds/genre"))
             .rel("alternate".to_string())
             .build(),
         LinkBuilder::default()
-            .href("/porcula/opds/genre".to_string())
+            .href(format!("{base}/opds/genre"))
             .rel("subsection".to_string())
             .mime_type(atom_nav_mime_type())
             .build(),
@@ -431,14 +1337,17 @@ fn opds_root(req: &Request, fts: &BookReader) -> Response {
             .build(),
     );
 
-    opds_response("Porcula", &root_url, &req_path, e, None, None)
+    Ok(opds_response(
+        "Porcula", base, &root_url, &req_path, e, None, None,
+    ))
 }
 
-fn opds_search_where(req: &Request, query: &str) -> Response {
-    let (root_url, req_path) = split_request_url(req);
+fn opds_search_where(req: &Request, app: &Application, query: &str) -> HandlerResult {
+    let base = &app.config.server.base_url;
+    let (root_url, req_path) = split_request_url(req, base)?;
     let mut e = Vec::new();
 
-    let rel_url = format!("/porcula/opds/search/title/{}/0", urlenc(query));
+    let rel_url = format!("{base}/opds/search/title/{}/0", urlenc(query));
     let abs_url = format!("{}{}", &root_url, &rel_url);
     let links = vec![
         LinkBuilder::default()
@@ -460,7 +1369,7 @@ fn opds_search_where(req: &Request, query: &str) -> Response {
             .build(),
     );
 
-    let rel_url = format!("/porcula/opds/search/author/{}/0", urlenc(query));
+    let rel_url = format!("{base}/opds/search/author/{}/0", urlenc(query));
     let abs_url = format!("{}{}", &root_url, &rel_url);
     let links = vec![
         LinkBuilder::default()
@@ -482,7 +1391,7 @@ fn opds_search_where(req: &Request, query: &str) -> Response {
             .build(),
     );
 
-    let rel_url = format!("/porcula/opds/search/body/{}/0", urlenc(query));
+    let rel_url = format!("{base}/opds/search/body/{}/0", urlenc(query));
     let abs_url = format!("{}{}", &root_url, &rel_url);
     let links = vec![
         LinkBuilder::default()
@@ -504,7 +1413,7 @@ fn opds_search_where(req: &Request, query: &str) -> Response {
             .build(),
     );
 
-    let rel_url = format!("/porcula/opds/search/sequence/{}/0", urlenc(query));
+    let rel_url = format!("{base}/opds/search/sequence/{}/0", urlenc(query));
     let abs_url = format!("{}{}", &root_url, &rel_url);
     let links = vec![
         LinkBuilder::default()
@@ -526,29 +1435,35 @@ fn opds_search_where(req: &Request, query: &str) -> Response {
             .build(),
     );
 
-    opds_response(
+    Ok(opds_response(
         tr!["Porcula - search", "Porcula - поиск"],
+        base,
         &root_url,
         &req_path,
         e,
         None,
         None,
-    )
+    ))
 }
 
 fn opds_facet(
     req: &Request,
+    app: &Application,
     facet: &str,
     prefix: Option<&str>,
     title: &str,
     translation: Option<&HashMap<String, String>>,
     fts: &BookReader,
-) -> Response {
-    let (root_url, req_path) = split_request_url(req);
+) -> HandlerResult {
+    let base = &app.config.server.base_url;
+    let (root_url, req_path) = split_request_url(req, base)?;
     let path = match prefix {
         Some(x) => format!("/{facet}/{x}"),
         None => format!("/{facet}"),
     };
+    //last level links to books: /genre/category/code, /author/letter/last name/full name
+    let levels = if facet == "author" { 3 } else { 2 };
+    let leaf = prefix.map_or(0, |x| x.split('/').count()) + 1 == levels;
     match fts.get_facet(&path, None, false, false, None) {
         Ok(data) => {
             let mut arr: Vec<(String, u64, String)> = data
@@ -560,6 +1475,7 @@ fn opds_facet(
                             Some(tr) => tr.to_owned(),
                             None => code.to_owned(),
                         },
+                        None if leaf && facet == "author" => name_with_initials(code),
                         None => code.to_owned(),
                     };
                     (path, count, title)
@@ -574,11 +1490,11 @@ fn opds_facet(
                     .map(urlenc)
                     .collect::<Vec<String>>()
                     .join("/");
-                //append page to final path, i.e. "/author/A/Abcd" -> "/author/A/Abcd/0"
-                if prefix.is_some() {
+                //append page to final path, i.e. "/genre/sf/sf_space" -> "/genre/sf/sf_space/0"
+                if leaf {
                     path.push_str("/0");
                 }
-                let rel_url = format!("/porcula/opds{}", &path);
+                let rel_url = format!("{base}/opds{}", &path);
                 let abs_url = format!("{}{}", &root_url, &rel_url);
                 let links = vec![
                     LinkBuilder::default()
@@ -605,26 +1521,30 @@ fn opds_facet(
                         .build(),
                 );
             }
-            opds_response(title, &root_url, &req_path, e, None, None)
+            Ok(opds_response(
+                title, base, &root_url, &req_path, e, None, None,
+            ))
         }
-        Err(e) => Response::text(e.to_string()).with_status_code(500),
+        Err(e) => Err(HandlerError::Internal(e.to_string())),
     }
 }
 
 fn opds_search_books(
     req: &Request,
+    app: &Application,
     query: &str,
     orderby: &str,
     page: usize,
     translation: &HashMap<String, String>,
     fts: &BookReader,
-) -> Response {
-    let (root_url, req_path) = split_request_url(req);
+) -> HandlerResult {
+    let base = &app.config.server.base_url;
+    let (root_url, req_path) = split_request_url(req, base)?;
     let orderby = crate::fts::OrderBy::from_str(orderby).unwrap_or_default();
     let stemming = true; //TODO: url parameter
     let disjunction = false; //TODO: url parameter
-    let limit = OPDS_PAGE_ENTRIES;
-    let offset = page * OPDS_PAGE_ENTRIES;
+    let limit = app.config.pages.opds_entries;
+    let offset = page * limit;
     //split path to base and page
     let mut path_parts = req_path.split('/').map(urlenc).collect::<Vec<String>>();
     let prev_url = if page == 0 || path_parts.len() < 2 {
@@ -634,7 +1554,10 @@ fn opds_search_books(
         path_parts[n] = format!("{}", page - 1);
         Some(path_parts.join("/"))
     };
-    match fts.search_as_meta(query, stemming, disjunction, orderby, limit, offset) {
+    let started = Instant::now();
+    let found = fts.search_as_meta(query, stemming, disjunction, orderby, limit, offset);
+    METRICS.observe_search(started.elapsed());
+    match found {
         Ok(data) => {
             let next_url = if data.len() < limit {
                 None
@@ -645,13 +1568,9 @@ fn opds_search_books(
             };
             let mut e = Vec::new();
             for i in data {
-                let rel_url = format!(
-                    "/porcula/book/{}/{}",
-                    urlenc(&i.zipfile),
-                    urlenc(&i.filename)
-                );
+                let rel_url = format!("{base}/book/{}/{}", urlenc(&i.zipfile), urlenc(&i.filename));
                 let cover_url = format!(
-                    "/porcula/book/{}/{}/cover",
+                    "{base}/book/{}/{}/cover",
                     urlenc(&i.zipfile),
                     urlenc(&i.filename)
                 );
@@ -666,6 +1585,11 @@ fn opds_search_books(
                         .rel("http://opds-spec.org/acquisition/open-access".to_string())
                         .mime_type(Some("application/fb2+xml".to_string()))
                         .build(),
+                    LinkBuilder::default()
+                        .href(format!("{rel_url}/zip"))
+                        .rel("http://opds-spec.org/acquisition/open-access".to_string())
+                        .mime_type(Some("application/fb2+zip".to_string()))
+                        .build(),
                     LinkBuilder::default()
                         .href(&cover_url)
                         .rel("http://opds-spec.org/image".to_string())
@@ -711,15 +1635,372 @@ fn opds_search_books(
                 );
                 e.push(b);
             }
-            opds_response(
+            Ok(opds_response(
                 tr!["Porcula - books", "Porcula - книги"],
+                base,
                 &root_url,
                 &req_path,
                 e,
                 prev_url,
                 next_url,
-            )
+            ))
         }
-        Err(e) => Response::text(e.to_string()).with_status_code(500),
+        Err(e) => Err(HandlerError::Internal(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fts::BookWriter;
+
+    const BOOK: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<FictionBook xmlns:l="http://www.w3.org/1999/xlink"><description><title-info><author><first-name>Иван</first-name><last-name>Иванов</last-name></author><book-title>Книга</book-title><lang>ru</lang></title-info></description>
+<body><section><title><p>Глава</p></title><p>Текст главы</p></section></body></FictionBook>"#;
+    const MALFORMED_BOOK: &str = "<FictionBook><body><section><p>unclosed <emphasis>text</section>";
+
+    struct TestServer {
+        _dir: tempfile::TempDir,
+        app: Arc<Application>,
+        fts: BookReader,
+        genre_map: GenreMap,
+        author_map: AuthorMap,
+    }
+
+    impl TestServer {
+        fn config(&mut self) -> &mut Config {
+            &mut Arc::get_mut(&mut self.app).expect("app is shared").config
+        }
+    }
+
+    fn test_server() -> TestServer {
+        let dir = tempfile::tempdir().unwrap();
+        let books_path = dir.path().join("books");
+        let index_path = dir.path().join("index");
+        std::fs::create_dir(&books_path).unwrap();
+        std::fs::create_dir(&index_path).unwrap();
+        let file = std::fs::File::create(books_path.join("good.zip")).unwrap();
+        let mut zip = zip::ZipWriter::new(file);
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("book.fb2", options).unwrap();
+        zip.write_all(BOOK.as_bytes()).unwrap();
+        zip.start_file("malformed.fb2", options).unwrap();
+        zip.write_all(MALFORMED_BOOK.as_bytes()).unwrap();
+        zip.finish().unwrap();
+        std::fs::write(books_path.join("corrupted.zip"), b"PK\x03\x04 not a zip").unwrap();
+        let mut writer = BookWriter::new(&index_path, "off", Some(1), 50_000_000).unwrap();
+        writer.commit().unwrap();
+        let fts = BookReader::new(&index_path, "ru").unwrap();
+        let mut book_formats: BookFormats = HashMap::new();
+        book_formats.insert(".fb2", Box::new(crate::fb2_parser::Fb2BookFormat {}));
+        let app = Application {
+            index_settings: IndexSettings {
+                langs: vec!["ru".into()],
+                stemmer: "off".into(),
+                books_dir: books_path.display().to_string(),
+                options: ParseOpts {
+                    body: true,
+                    xbody: false,
+                    annotation: true,
+                    cover: true,
+                },
+                schema_version: crate::fts::SCHEMA_VERSION,
+                full_run: None,
+            },
+            index_path,
+            books_path,
+            book_formats,
+            config: Config::default(),
+            debug: false,
+            writer_lock: Default::default(),
+        };
+        TestServer {
+            _dir: dir,
+            app: Arc::new(app),
+            fts,
+            genre_map: GenreMap::default(),
+            author_map: AuthorMap::default(),
+        }
+    }
+
+    fn get(s: &TestServer, url: &str, headers: &[(&str, &str)]) -> (u16, String) {
+        let headers = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let req = Request::fake_http("GET", url, headers, vec![]);
+        let res = route(&req, &s.app, &s.fts, &s.genre_map, &s.author_map);
+        let (mut reader, _) = res.data.into_reader_and_size();
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        (res.status_code, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[test]
+    fn test_book_responses() {
+        let s = test_server();
+        let (status, body) = get(&s, "/porcula/book/good.zip/book.fb2", &[]);
+        assert_eq!((status, body.as_str()), (200, BOOK));
+        let (status, body) = get(&s, "/porcula/book/good.zip/book.fb2/render", &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains("Текст главы"));
+        let (status, _) = get(&s, "/porcula/book/good.zip/book.fb2/zip", &[]);
+        assert_eq!(status, 200);
+    }
+
+    #[test]
+    fn test_nested_book() {
+        let s = test_server();
+        let mut inner = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        inner
+            .start_file("book.fb2", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        inner.write_all(BOOK.as_bytes()).unwrap();
+        let inner = inner.finish().unwrap().into_inner();
+        let file = std::fs::File::create(s.app.books_path.join("outer.zip")).unwrap();
+        let mut zip = zip::ZipWriter::new(file);
+        zip.start_file("book.fb2.zip", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&inner).unwrap();
+        zip.finish().unwrap();
+        let url = "/porcula/book/outer.zip/book.fb2.zip/book.fb2";
+        let (status, body) = get(&s, url, &[]);
+        assert_eq!((status, body.as_str()), (200, BOOK));
+        let (status, body) = get(&s, &format!("{url}/render"), &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains(r#"href="../../../../render.css""#));
+        let (status, _) = get(&s, "/porcula/book/outer.zip/book.fb2.zip/other.fb2", &[]);
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn test_missing_book() {
+        let s = test_server();
+        let (status, body) = get(&s, "/porcula/book/missing.zip/book.fb2", &[]);
+        assert_eq!(status, 404);
+        assert!(body.starts_with(r#"{"status":404,"error":"#));
+        let (status, _) = get(&s, "/porcula/book/good.zip/missing.fb2/render", &[]);
+        assert_eq!(status, 404);
+        let (status, _) = get(&s, "/porcula/book/good.zip/book.txt", &[]);
+        assert_eq!(status, 404);
+        let (status, _) = get(&s, "/porcula/book/good.zip/book.fb2/render?chapter=99", &[]);
+        assert_eq!(status, 404);
+        let (status, body) = get(
+            &s,
+            "/porcula/book/missing.zip/book.fb2/render",
+            &[("Accept", "text/html")],
+        );
+        assert_eq!(status, 404);
+        assert!(body.starts_with("<!DOCTYPE html>"));
+    }
+
+    #[test]
+    fn test_metrics_and_health() {
+        let s = test_server();
+        let (status, body) = get(&s, "/porcula/metrics", &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains("porcula_index_documents 0\n"));
+        assert!(body.contains("porcula_index_segments "));
+        let (status, body) = get(&s, "/porcula/index/stats", &[]);
+        assert_eq!(status, 200);
+        assert!(body.starts_with(r#"{"book_count":0,"#));
+        let (status, body) = get(&s, "/porcula/health", &[]);
+        assert_eq!((status, body.as_str()), (200, r#"{"status":"ok"}"#));
+        let moved = s.app.index_path.with_extension("moved");
+        std::fs::rename(&s.app.index_path, moved).unwrap();
+        let (status, body) = get(&s, "/porcula/health", &[]);
+        assert_eq!(status, 503);
+        assert!(body.starts_with(r#"{"status":"error","error":"#));
+    }
+
+    #[test]
+    fn test_config() {
+        let mut s = test_server();
+        s.config().server.auth = Some(AuthConfig {
+            user: "u".into(),
+            password: "p".into(),
+        });
+        s.config().server.base_url = "/lib".into();
+        let (status, _) = get(&s, "/lib/about", &[]);
+        assert_eq!(status, 401);
+        let auth = [("Authorization", "Basic dTpw")]; //u:p
+        let (status, _) = get(&s, "/lib/about", &auth);
+        assert_eq!(status, 200);
+        let (status, _) = get(&s, "/lib/about", &[("Authorization", "Basic dTpx")]); //u:q
+        assert_eq!(status, 401);
+        let (status, _) = get(&s, "/lib/health", &[]);
+        assert_eq!(status, 200);
+        let (status, body) = get(&s, "/lib/opds", &[("Host", "h"), auth[0]]);
+        assert_eq!(status, 200);
+        assert!(body.contains(r#"<link href="http://h/lib/opds" rel="self""#));
+        assert!(body.contains(r#"<link href="/lib/opensearch" rel="search""#));
+    }
+
+    fn post(s: &TestServer, url: &str, body: &str, auth: bool) -> (u16, String) {
+        let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+        if auth {
+            headers.push(("Authorization".into(), "Basic YTpi".into())); //a:b
+        }
+        let req = Request::fake_http("POST", url, headers, body.as_bytes().to_vec());
+        let res = route(&req, &s.app, &s.fts, &s.genre_map, &s.author_map);
+        let (mut reader, _) = res.data.into_reader_and_size();
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        (res.status_code, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[test]
+    fn test_admin() {
+        let mut s = test_server();
+        let good = r#"{"files":["good.zip"]}"#;
+        assert_eq!(post(&s, "/porcula/admin/reindex", good, true).0, 404);
+        s.config().server.admin = Some(AuthConfig {
+            user: "a".into(),
+            password: "b".into(),
+        });
+        assert_eq!(post(&s, "/porcula/admin/reindex", good, false).0, 401);
+        let book_count = || {
+            let counts = s.app.open_book_reader().unwrap().get_file_counts().unwrap();
+            counts.get("good.zip").map(|x| x.values().sum::<u64>())
+        };
+        //reindexing runs in background and replaces previous documents
+        let auth = [("Authorization", "Basic YTpi")];
+        for _ in 0..2 {
+            let (status, body) = post(&s, "/porcula/admin/reindex", good, true);
+            assert_eq!(status, 202);
+            let job: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(job["state"], "running");
+            let url = format!("/porcula/admin/reindex/{}", job["id"]);
+            let mut state = job["state"].clone();
+            for _ in 0..200 {
+                if state != "running" {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+                let (status, body) = get(&s, &url, &auth);
+                assert_eq!(status, 200);
+                state = serde_json::from_str::<serde_json::Value>(&body).unwrap()["state"].clone();
+            }
+            assert_eq!(state, "done");
+            assert_eq!(book_count(), Some(1));
+        }
+        assert_eq!(get(&s, "/porcula/admin/reindex/0", &auth).0, 404);
+        let missing = r#"{"files":["missing.zip"]}"#;
+        assert_eq!(post(&s, "/porcula/admin/reindex", missing, true).0, 404);
+        assert_eq!(post(&s, "/porcula/admin/remove", "{}", true).0, 400);
+        let (status, body) = post(
+            &s,
+            "/porcula/admin/remove",
+            r#"{"files":["good.zip/book.fb2"]}"#,
+            true,
+        );
+        assert_eq!(
+            (status, body.as_str()),
+            (200, r#"[{"target":"good.zip/book.fb2","books":1}]"#)
+        );
+        assert_eq!(book_count(), None);
+    }
+
+    #[test]
+    fn test_opds_authors() {
+        let mut s = test_server();
+        let canceled = Arc::new(AtomicBool::new(false));
+        index_books(&IndexArgs::delta(vec!["good.zip".into()]), &s.app, canceled)
+            .into_result()
+            .unwrap();
+        s.fts = s.app.open_book_reader().unwrap();
+        let host = [("Host", "h")];
+        let (status, body) = get(&s, &format!("/porcula/opds/author/{}", urlenc("И")), &host);
+        assert_eq!(status, 200);
+        let last_name = format!("/porcula/opds/author/{}/{}", urlenc("И"), urlenc("Иванов"));
+        assert!(body.contains(&format!(r#"href="{last_name}""#)), "{}", body);
+        let (status, body) = get(&s, &last_name, &host);
+        assert_eq!(status, 200);
+        assert!(body.contains("<title>Иванов И.</title>"), "{}", body);
+        let person = format!("{last_name}/{}/0", urlenc("Иванов Иван"));
+        assert!(body.contains(&format!(r#"href="{person}""#)), "{}", body);
+        let (status, body) = get(&s, &person, &host);
+        assert_eq!(status, 200);
+        assert!(body.contains("<title>Книга</title>"), "{}", body);
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let s = test_server();
+        let (status, _) = get(&s, "/porcula/book/..%2Fgood.zip/book.fb2", &[]);
+        assert_eq!(status, 400);
+        let (status, _) = get(&s, "/porcula/book/good.zip/book.fb2/render?chapter=x", &[]);
+        assert_eq!(status, 400);
+        let (status, _) = get(&s, "/porcula/book/good.zip/book.fb2/find", &[]);
+        assert_eq!(status, 400);
+        //no Host header
+        let (status, _) = get(&s, "/porcula/opds", &[]);
+        assert_eq!(status, 400);
+        let (status, _) = get(&s, "/porcula/opds", &[("Host", "localhost")]);
+        assert_eq!(status, 200);
+    }
+
+    #[test]
+    fn test_broken_files() {
+        let s = test_server();
+        let (status, _) = get(&s, "/porcula/book/corrupted.zip/book.fb2", &[]);
+        assert_eq!(status, 500);
+        let (status, _) = get(&s, "/porcula/book/corrupted.zip/book.fb2/render", &[]);
+        assert_eq!(status, 500);
+        //renderer skips XML errors
+        let (status, body) = get(&s, "/porcula/book/good.zip/malformed.fb2/render", &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains("unclosed"));
+        let (status, _) = get(&s, "/porcula/book/good.zip/malformed.fb2/find?q=text", &[]);
+        assert_eq!(status, 200);
+        let (status, _) = get(&s, "/porcula/book/good.zip/malformed.fb2/zip", &[]);
+        assert_eq!(status, 200);
+        //unknown entity in title is an error, not a panic
+        let file = std::fs::File::create(s.app.books_path.join("title.zip")).unwrap();
+        let mut zip = zip::ZipWriter::new(file);
+        zip.start_file("title.fb2", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"<FictionBook><description><title-info><book-title>A&nbsp;B</book-title></title-info></description><body><p>text</p></body></FictionBook>").unwrap();
+        zip.finish().unwrap();
+        let (status, _) = get(&s, "/porcula/book/title.zip/title.fb2/render", &[]);
+        assert_eq!(status, 500);
+    }
+
+    #[test]
+    fn test_cp866_entry_name() {
+        let s = test_server();
+        //archiver without UTF-8 flag: name in OEM encoding, written as ASCII and patched
+        let (name, _, _) = encoding_rs::IBM866.encode("Книга.fb2");
+        let placeholder = "x".repeat(name.len() - 4) + ".fb2";
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        zip.start_file(
+            placeholder.as_str(),
+            zip::write::SimpleFileOptions::default(),
+        )
+        .unwrap();
+        zip.write_all(BOOK.as_bytes()).unwrap();
+        let mut raw = zip.finish().unwrap().into_inner();
+        let len = placeholder.len();
+        while let Some(pos) = raw.windows(len).position(|x| x == placeholder.as_bytes()) {
+            raw[pos..pos + len].copy_from_slice(&name);
+        }
+        std::fs::write(s.app.books_path.join("cp866.zip"), raw).unwrap();
+        let url = "/porcula/book/cp866.zip/%D0%9A%D0%BD%D0%B8%D0%B3%D0%B0.fb2";
+        let (status, body) = get(&s, url, &[]);
+        assert_eq!((status, body.as_str()), (200, BOOK));
+        let (status, body) = get(&s, &format!("{url}/render"), &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains("Текст главы"));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some((0, 9)));
+        assert_eq!(parse_range("bytes=90-", 100), Some((90, 99)));
+        assert_eq!(parse_range("bytes=-10", 100), Some((90, 99)));
+        assert_eq!(parse_range("bytes=50-500", 100), Some((50, 99)));
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
     }
 }
@@ -0,0 +1,15 @@
+use crate::cmd::*;
+
+pub fn run_stats(app: Application) -> ProcessResult {
+    let fts = match app.open_book_reader() {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::IndexError(e),
+    };
+    match fts.get_stats() {
+        Ok(res) => {
+            println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            ProcessResult::Ok
+        }
+        Err(e) => ProcessResult::IndexError(e.to_string()),
+    }
+}
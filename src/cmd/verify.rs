@@ -0,0 +1,187 @@
+use log::{error, info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use crate::cmd::*;
+use crate::fts::BookWriter;
+use crate::tr;
+
+const REPAIR_MEMORY_SIZE: usize = 50_000_000;
+const MAX_LISTED_ENTRIES: usize = 3; //missing entries to show per archive
+
+/// entry names as they are stored in index
+fn archive_entries(path: &Path) -> Result<HashSet<String>, String> {
+    let mut container = crate::container::open(path).map_err(|e| e.to_string())?;
+    let names = container.names().map_err(|e| e.to_string())?;
+    Ok(names.into_iter().collect())
+}
+
+pub fn run_verify(args: &VerifyArgs, app: Application) -> ProcessResult {
+    match crate::fts::check_schema(&app.index_path) {
+        Ok(errors) if errors.is_empty() => (),
+        Ok(errors) => {
+            for e in &errors {
+                error!("{}: {e}", tr!["Schema", "Схема"]);
+            }
+            return ProcessResult::IndexError(
+                tr![
+                    "Index schema does not match, rebuild with 'index full' command",
+                    "Схема индекса не совпадает, пересоздайте индекс командой 'index full'"
+                ]
+                .to_string(),
+            );
+        }
+        Err(e) => return ProcessResult::IndexError(e.to_string()),
+    }
+    let fts = match app.open_book_reader() {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::IndexError(e),
+    };
+    let (markers, files) = match (fts.get_whole_markers(), fts.get_file_counts()) {
+        (Ok(m), Ok(f)) => (m, f),
+        (Err(e), _) | (_, Err(e)) => return ProcessResult::IndexError(e.to_string()),
+    };
+
+    let mut marker_len = BTreeMap::<String, Vec<u64>>::new();
+    for (zipfile, len) in markers {
+        marker_len.entry(zipfile).or_default().push(len);
+    }
+    let mut zipfiles: Vec<&String> = marker_len.keys().chain(files.keys()).collect();
+    zipfiles.sort();
+    zipfiles.dedup();
+
+    let mut broken = BTreeMap::<&str, Vec<String>>::new();
+    for zipfile in &zipfiles {
+        let mut problems = vec![];
+        let indexed = files.get(*zipfile);
+        let entries = match archive_entries(&app.books_path.join(zipfile)) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                problems.push(format!(
+                    "{}: {e}",
+                    tr!["archive not readable", "архив не читается"]
+                ));
+                None
+            }
+        };
+        match marker_len.get(*zipfile).map(|x| x.as_slice()) {
+            None => problems.push(
+                tr![
+                    "no WHOLE marker, archive is indexed partially",
+                    "нет отметки WHOLE, архив проиндексирован частично"
+                ]
+                .to_string(),
+            ),
+            Some([len]) => {
+                let book_count: u64 = indexed.map(|x| x.values().sum()).unwrap_or(0);
+                if book_count > *len {
+                    problems.push(format!(
+                        "{}: {book_count} > {len}",
+                        tr![
+                            "more books indexed than in WHOLE marker",
+                            "проиндексировано больше книг, чем в отметке WHOLE"
+                        ]
+                    ));
+                }
+                if let Some(entries) = &entries {
+                    if entries.len() as u64 != *len {
+                        problems.push(format!(
+                            "{}: {len} != {}",
+                            tr![
+                                "WHOLE marker does not match archive size",
+                                "отметка WHOLE не соответствует размеру архива"
+                            ],
+                            entries.len()
+                        ));
+                    }
+                }
+            }
+            Some(x) => problems.push(format!(
+                "{}: {}",
+                tr!["duplicated WHOLE marker", "повторная отметка WHOLE"],
+                x.len()
+            )),
+        }
+        if let (Some(indexed), Some(entries)) = (indexed, &entries) {
+            let missing: Vec<&str> = indexed
+                .keys()
+                .filter(|x| {
+                    !entries.contains(*x)
+                        && !crate::container::split_nested(x)
+                            .is_some_and(|(outer, _)| entries.contains(outer))
+                })
+                .map(|x| x.as_str())
+                .collect();
+            if !missing.is_empty() {
+                problems.push(format!(
+                    "{}: {} ({}...)",
+                    tr!["books not found in archive", "книги не найдены в архиве"],
+                    missing.len(),
+                    missing[..missing.len().min(MAX_LISTED_ENTRIES)].join(", ")
+                ));
+            }
+            let duplicated = indexed.values().filter(|x| **x > 1).count();
+            if duplicated > 0 {
+                problems.push(format!(
+                    "{}: {duplicated}",
+                    tr![
+                        "books indexed more than once",
+                        "книги проиндексированы повторно"
+                    ]
+                ));
+            }
+        }
+        for p in &problems {
+            warn!("{zipfile}: {p}");
+        }
+        if !problems.is_empty() {
+            broken.insert(zipfile, problems);
+        }
+    }
+    info!(
+        "{}: {}, {}: {}",
+        tr!["Archives checked", "Проверено архивов"],
+        zipfiles.len(),
+        tr!["with errors", "с ошибками"],
+        broken.len()
+    );
+    if broken.is_empty() {
+        return ProcessResult::Ok;
+    }
+    if !args.repair {
+        return ProcessResult::IndexError(
+            tr![
+                "Index is inconsistent, run 'verify --repair' to delete broken archives",
+                "Индекс повреждён, запустите 'verify --repair' для удаления испорченных архивов"
+            ]
+            .to_string(),
+        );
+    }
+
+    let mut book_writer = match BookWriter::new(
+        &app.index_path,
+        &app.index_settings.stemmer,
+        Some(1),
+        REPAIR_MEMORY_SIZE,
+    ) {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::IndexError(e.to_string()),
+    };
+    for zipfile in broken.keys() {
+        info!("{} {zipfile}", tr!["deleting archive", "удаляем архив"]);
+        if let Err(e) = book_writer.delete_zipfile(zipfile) {
+            return ProcessResult::IndexError(e.to_string());
+        }
+    }
+    if let Err(e) = book_writer.commit() {
+        return ProcessResult::IndexError(e.to_string());
+    }
+    info!(
+        "{}",
+        tr![
+            "Repair done, run 'index' command to index deleted archives again",
+            "Исправление завершено, запустите команду 'index' для повторной индексации удалённых архивов"
+        ]
+    );
+    ProcessResult::Ok
+}
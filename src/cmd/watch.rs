@@ -0,0 +1,174 @@
+//server --watch: index new and modified archives in background thread
+
+use log::{error, info};
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::index::index_books;
+use crate::cmd::*;
+use crate::container::ContainerKind;
+use crate::tr;
+
+type ArchiveState = (SystemTime, u64); //mtime, size
+
+fn scan_archives(books_path: &Path) -> HashMap<String, ArchiveState> {
+    let mut res = HashMap::new();
+    let entries = match std::fs::read_dir(books_path) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}: {e}", books_path.display());
+            return res;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if ContainerKind::from_filename(&name).is_none() {
+            continue;
+        }
+        if let Ok(meta) = entry.metadata() {
+            if meta.is_file() {
+                let mtime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                res.insert(name, (mtime, meta.len()));
+            }
+        }
+    }
+    res
+}
+
+/// archives changed since last indexing and not changed since previous poll (copying is finished)
+fn ready_archives(
+    current: &HashMap<String, ArchiveState>,
+    indexed: &HashMap<String, ArchiveState>,
+    pending: &mut HashMap<String, ArchiveState>,
+) -> Vec<String> {
+    let mut res = vec![];
+    for (name, state) in current {
+        if indexed.get(name) == Some(state) {
+            pending.remove(name);
+        } else if pending.get(name) == Some(state) {
+            res.push(name.clone());
+        } else {
+            pending.insert(name.clone(), *state);
+        }
+    }
+    res.sort();
+    res
+}
+
+/// archives modified after given time, e.g. replaced while server was stopped
+fn modified_since(archives: &HashMap<String, ArchiveState>, time: SystemTime) -> Vec<String> {
+    let mut res: Vec<String> = archives
+        .iter()
+        .filter(|(_, (mtime, _))| *mtime > time)
+        .map(|(name, _)| name.clone())
+        .collect();
+    res.sort();
+    res
+}
+
+/// index archives, log errors and panics so watcher thread keeps running
+fn run_index(args: &IndexArgs, app: &Application, canceled: &Arc<AtomicBool>) -> bool {
+    let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        index_books(args, app, canceled.clone()).into_result()
+    }))
+    .unwrap_or_else(|_| Err(tr!["indexing panicked", "сбой индексации"].to_string()));
+    match res {
+        Ok(_) => true,
+        Err(e) => {
+            error!(
+                "{}: {e}",
+                tr!["Watch indexing error", "Ошибка фоновой индексации"]
+            );
+            false
+        }
+    }
+}
+
+/// poll books directory; running BookReader reloads index after commit
+pub fn spawn_watcher(app: Arc<Application>, interval: Duration) {
+    info!(
+        "{}: {} {}",
+        tr![
+            "Watching books dir every",
+            "Отслеживаем каталог с книгами каждые"
+        ],
+        interval.as_secs(),
+        tr!["s", "с"]
+    );
+    std::thread::spawn(move || {
+        let canceled = Arc::new(AtomicBool::new(false));
+        //archives added or replaced while server was stopped, last commit time is mtime of index meta
+        let mut indexed = scan_archives(&app.books_path);
+        let index_time = std::fs::metadata(app.index_path.join("meta.json"))
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let modified = modified_since(&indexed, index_time);
+        if !modified.is_empty() {
+            info!(
+                "{}: {}",
+                tr![
+                    "Archives modified after indexing",
+                    "Архивы изменены после индексации"
+                ],
+                modified.join(", ")
+            );
+            run_index(&IndexArgs::delta(modified), &app, &canceled);
+        }
+        //reindexed archives are marked as whole and skipped here
+        run_index(&IndexArgs::delta(vec![]), &app, &canceled);
+        let mut pending = HashMap::new();
+        loop {
+            std::thread::sleep(interval);
+            let current = scan_archives(&app.books_path);
+            let ready = ready_archives(&current, &indexed, &mut pending);
+            if ready.is_empty() {
+                continue;
+            }
+            info!(
+                "{}: {}",
+                tr!["New or modified archives", "Новые или изменённые архивы"],
+                ready.join(", ")
+            );
+            //index --file replaces previous version of archive
+            if run_index(&IndexArgs::delta(ready.clone()), &app, &canceled) {
+                for name in ready {
+                    pending.remove(&name);
+                    indexed.insert(name.clone(), current[&name]);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_archives() {
+        let t = SystemTime::UNIX_EPOCH;
+        let indexed = HashMap::from([("a.zip".to_string(), (t, 1))]);
+        let mut pending = HashMap::new();
+        let mut current = indexed.clone();
+        current.insert("b.zip".into(), (t, 10)); //copying
+        assert!(ready_archives(&current, &indexed, &mut pending).is_empty());
+        current.insert("b.zip".into(), (t, 20));
+        assert!(ready_archives(&current, &indexed, &mut pending).is_empty());
+        current.insert("a.zip".into(), (t, 2)); //replaced
+        assert_eq!(
+            ready_archives(&current, &indexed, &mut pending),
+            vec!["b.zip"]
+        );
+        assert_eq!(
+            ready_archives(&current, &indexed, &mut pending),
+            vec!["a.zip", "b.zip"]
+        );
+        let later = t + Duration::from_secs(10);
+        current.insert("c.zip".into(), (later, 1));
+        assert_eq!(modified_since(&current, t), vec!["c.zip"]);
+    }
+}
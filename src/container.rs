@@ -0,0 +1,642 @@
+//book archives: zip, tar, tar.gz and 7z with common access to entries
+
+use crate::charset::guess_cyrillic;
+use encoding_rs::Encoding;
+use flate2::read::MultiGzDecoder;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+const TAR_TABLE_CACHE_SIZE: usize = 32; //archives
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
+pub enum ContainerKind {
+    #[strum(serialize = "zip")]
+    Zip,
+    #[strum(serialize = "tar")]
+    Tar,
+    #[strum(serialize = "tar.gz")]
+    TarGz,
+    #[strum(serialize = "7z")]
+    SevenZ,
+}
+
+impl ContainerKind {
+    /// by extension of archive name
+    pub fn from_filename(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if name.ends_with(".zip") {
+            Some(ContainerKind::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ContainerKind::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(ContainerKind::Tar)
+        } else if name.ends_with(".7z") {
+            Some(ContainerKind::SevenZ)
+        } else {
+            None
+        }
+    }
+
+    /// entry can be read without decompressing previous ones, so archive can be split between readers
+    pub fn random_access(self) -> bool {
+        matches!(self, ContainerKind::Zip | ContainerKind::Tar)
+    }
+}
+
+/// archive entry
+#[derive(Debug)]
+pub struct Entry {
+    pub index: usize,
+    pub name: String,
+    pub size: u64,
+    pub packed_size: u64, //estimated for tar.gz and 7z
+    pub crc32: Option<u32>,
+}
+
+/// gets entry and its content, returns false to stop reading
+pub type EntryFn<'a> = dyn FnMut(&Entry, &mut dyn Read) -> io::Result<bool> + 'a;
+
+pub trait Container: Send {
+    fn kind(&self) -> ContainerKind;
+    fn entry_count(&self) -> usize;
+    /// entry names in archive order
+    fn names(&mut self) -> io::Result<Vec<String>>;
+    /// read entries with index in range
+    fn for_each(&mut self, range: Range<usize>, f: &mut EntryFn) -> io::Result<()>;
+    fn find(&mut self, name: &str) -> io::Result<Option<Entry>>;
+
+    fn read(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        let mut res = vec![];
+        self.for_each(index..index + 1, &mut |_, data| {
+            data.read_to_end(&mut res)?;
+            Ok(false)
+        })?;
+        Ok(res)
+    }
+}
+
+pub fn open(path: &Path) -> io::Result<Box<dyn Container>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    match ContainerKind::from_filename(&name) {
+        Some(ContainerKind::Zip) => Ok(Box::new(ZipContainer::open(path)?)),
+        Some(kind @ (ContainerKind::Tar | ContainerKind::TarGz)) => {
+            Ok(Box::new(TarContainer::open(path, kind)?))
+        }
+        Some(ContainerKind::SevenZ) => Ok(Box::new(SevenZContainer::open(path)?)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown archive type: {name}"),
+        )),
+    }
+}
+
+/// book packed into its own zip: book.fb2.zip
+pub fn is_nested_archive(name: &str) -> bool {
+    name.to_lowercase().ends_with(".zip")
+}
+
+/// "inner.fb2.zip/book.fb2" -> ("inner.fb2.zip", "book.fb2")
+pub fn split_nested(filename: &str) -> Option<(&str, &str)> {
+    filename
+        .split_once('/')
+        .filter(|(outer, _)| is_nested_archive(outer))
+}
+
+/// name and content of single entry of nested zip, None for archive with several entries
+pub fn unpack_nested(data: &[u8]) -> io::Result<Option<(String, Vec<u8>)>> {
+    let mut zip = zip::ZipArchive::new(io::Cursor::new(data))?;
+    if zip.len() != 1 {
+        return Ok(None);
+    }
+    let mut file = zip.by_index(0)?;
+    let name = zip_entry_name(&file);
+    let mut content = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut content)?;
+    Ok(Some((name, content)))
+}
+
+/// entry names without UTF-8 flag are in OEM (CP866) or ANSI (CP1251) encoding of archiver
+pub fn decode_filename(raw_filename: &[u8]) -> Option<String> {
+    if let Ok(s) = std::str::from_utf8(raw_filename) {
+        return Some(s.to_string());
+    }
+    if let Some(encoding) = guess_cyrillic(raw_filename) {
+        let (s, _malformed) = encoding.decode_without_bom_handling(raw_filename);
+        return Some(s.to_string());
+    }
+    let (charset, confidence, _language) = chardet::detect(raw_filename);
+    if confidence > 0.8 {
+        let enc_label = chardet::charset2encoding(&charset);
+        if let Some(encoding) = Encoding::for_label(enc_label.as_bytes()) {
+            let (utf8, _enc, _malformed) = encoding.decode(raw_filename);
+            return Some(utf8.to_string());
+        }
+    }
+    None
+}
+
+fn estimate(size: u64, ratio: f64) -> u64 {
+    (size as f64 * ratio) as u64
+}
+
+struct ZipContainer {
+    zip: zip::ZipArchive<BufReader<File>>,
+    names: Option<HashMap<String, usize>>, //zip_entry_name -> index, built on first miss of find
+}
+
+impl ZipContainer {
+    fn open(path: &Path) -> io::Result<Self> {
+        let zip = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+        Ok(ZipContainer { zip, names: None })
+    }
+
+    fn index_for_entry_name(&mut self, name: &str) -> io::Result<Option<usize>> {
+        //UTF-8 and ASCII names are decoded same way by zip crate
+        if let Some(i) = self.zip.index_for_name(name) {
+            if zip_entry_name(&self.zip.by_index_raw(i)?) == name {
+                return Ok(Some(i));
+            }
+        }
+        if self.names.is_none() {
+            let mut names = HashMap::new();
+            for i in 0..self.zip.len() {
+                names.insert(zip_entry_name(&self.zip.by_index_raw(i)?), i);
+            }
+            self.names = Some(names);
+        }
+        Ok(self.names.as_ref().and_then(|x| x.get(name).copied()))
+    }
+}
+
+const UNICODE_PATH_FIELD: u16 = 0x7075; //Info-ZIP
+
+/// UTF-8 name from Info-ZIP Unicode Path extra field, if entry was not renamed after it was written
+fn unicode_path(file: &zip::read::ZipFile) -> Option<String> {
+    let mut extra = file.extra_data()?;
+    while extra.len() >= 4 {
+        let id = u16::from_le_bytes([extra[0], extra[1]]);
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        let data = extra.get(4..4 + len)?;
+        //version 1, CRC32 of name in header, UTF-8 name
+        if id == UNICODE_PATH_FIELD && data.len() > 5 && data[0] == 1 {
+            let crc = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            if crc == crc32fast::hash(file.name_raw()) {
+                return String::from_utf8(data[5..].to_vec()).ok();
+            }
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+fn zip_entry_name(file: &zip::read::ZipFile) -> String {
+    if let Some(name) = unicode_path(file) {
+        return name;
+    }
+    //zip crate decodes name as UTF-8 only if UTF-8 flag is set, otherwise as CP437
+    let raw = file.name_raw();
+    if !raw.is_ascii() && std::str::from_utf8(raw) == Ok(file.name()) {
+        return file.name().into();
+    }
+    decode_filename(raw).unwrap_or_else(|| file.name().into())
+}
+
+fn zip_entry(index: usize, file: &zip::read::ZipFile) -> Entry {
+    Entry {
+        index,
+        name: zip_entry_name(file),
+        size: file.size(),
+        packed_size: file.compressed_size(),
+        crc32: Some(file.crc32()),
+    }
+}
+
+impl Container for ZipContainer {
+    fn kind(&self) -> ContainerKind {
+        ContainerKind::Zip
+    }
+
+    fn entry_count(&self) -> usize {
+        self.zip.len()
+    }
+
+    fn names(&mut self) -> io::Result<Vec<String>> {
+        let mut res = vec![];
+        for i in 0..self.zip.len() {
+            res.push(zip_entry(i, &self.zip.by_index_raw(i)?).name);
+        }
+        Ok(res)
+    }
+
+    fn for_each(&mut self, range: Range<usize>, f: &mut EntryFn) -> io::Result<()> {
+        for i in range {
+            let mut file = self.zip.by_index(i)?;
+            if !f(&zip_entry(i, &file), &mut file)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// by name as it is indexed: decoded from CP866/CP1251 or taken from Unicode Path field
+    fn find(&mut self, name: &str) -> io::Result<Option<Entry>> {
+        match self.index_for_entry_name(name)? {
+            Some(i) => Ok(Some(zip_entry(i, &self.zip.by_index_raw(i)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+struct TarEntry {
+    name: String,
+    offset: u64, //of data in uncompressed stream
+    size: u64,
+}
+
+/// entry offset table, built by one pass over archive
+struct TarTable {
+    entries: Vec<TarEntry>,
+    packed_ratio: f64, //archive size / uncompressed size
+}
+
+type TarTableCache = HashMap<PathBuf, (SystemTime, u64, Arc<TarTable>)>;
+
+lazy_static! {
+    static ref TAR_TABLES: Mutex<TarTableCache> = Mutex::new(HashMap::new());
+}
+
+/// cache stays usable after panic of other thread
+fn lock_tar_tables() -> std::sync::MutexGuard<'static, TarTableCache> {
+    TAR_TABLES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn scan_tar<R: Read>(entries: tar::Entries<R>) -> io::Result<Vec<TarEntry>> {
+    let mut res = vec![];
+    for e in entries {
+        let e = e?;
+        if !e.header().entry_type().is_file() {
+            continue;
+        }
+        let raw = e.path_bytes();
+        let name = match std::str::from_utf8(&raw) {
+            Ok(s) => s.to_string(),
+            Err(_) => decode_filename(&raw).unwrap_or_else(|| String::from_utf8_lossy(&raw).into()),
+        };
+        res.push(TarEntry {
+            name: name.trim_start_matches("./").to_string(), //tar -C dir .
+            offset: e.raw_file_position(),
+            size: e.size(),
+        });
+    }
+    Ok(res)
+}
+
+/// cached until archive is modified: server reads single entries, indexer counts entries before reading
+fn tar_table(path: &Path, gz: bool) -> io::Result<Arc<TarTable>> {
+    let meta = std::fs::metadata(path)?;
+    let mtime = meta.modified()?;
+    if let Some((t, len, table)) = lock_tar_tables().get(path) {
+        if (*t, *len) == (mtime, meta.len()) {
+            return Ok(table.clone());
+        }
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let entries = if gz {
+        scan_tar(tar::Archive::new(MultiGzDecoder::new(reader)).entries()?)?
+    } else {
+        scan_tar(tar::Archive::new(reader).entries_with_seek()?)?
+    };
+    let unpacked = entries.last().map(|x| x.offset + x.size).unwrap_or(0);
+    let table = Arc::new(TarTable {
+        entries,
+        packed_ratio: meta.len() as f64 / unpacked.max(1) as f64,
+    });
+    let mut cache = lock_tar_tables();
+    if cache.len() >= TAR_TABLE_CACHE_SIZE {
+        cache.clear();
+    }
+    cache.insert(path.to_path_buf(), (mtime, meta.len(), table.clone()));
+    Ok(table)
+}
+
+type GzReader = MultiGzDecoder<BufReader<File>>;
+
+struct TarContainer {
+    path: PathBuf,
+    kind: ContainerKind,
+    table: Arc<TarTable>,
+    file: BufReader<File>,
+    gz: Option<(GzReader, u64)>, //decoder and its position, data is skipped forward
+}
+
+impl TarContainer {
+    fn open(path: &Path, kind: ContainerKind) -> io::Result<Self> {
+        Ok(TarContainer {
+            path: path.to_path_buf(),
+            kind,
+            table: tar_table(path, kind == ContainerKind::TarGz)?,
+            file: BufReader::new(File::open(path)?),
+            gz: None,
+        })
+    }
+
+    fn entry(&self, index: usize) -> Entry {
+        let e = &self.table.entries[index];
+        Entry {
+            index,
+            name: e.name.clone(),
+            size: e.size,
+            packed_size: match self.kind {
+                ContainerKind::Tar => e.size,
+                _ => estimate(e.size, self.table.packed_ratio),
+            },
+            crc32: None,
+        }
+    }
+
+    /// decoder positioned at offset, restarted from beginning for backward move
+    fn gz_at(&mut self, offset: u64) -> io::Result<GzReader> {
+        let (mut gz, pos) = match self.gz.take() {
+            Some((gz, pos)) if pos <= offset => (gz, pos),
+            _ => (
+                MultiGzDecoder::new(BufReader::new(File::open(&self.path)?)),
+                0,
+            ),
+        };
+        io::copy(&mut gz.by_ref().take(offset - pos), &mut io::sink())?;
+        Ok(gz)
+    }
+}
+
+impl Container for TarContainer {
+    fn kind(&self) -> ContainerKind {
+        self.kind
+    }
+
+    fn entry_count(&self) -> usize {
+        self.table.entries.len()
+    }
+
+    fn names(&mut self) -> io::Result<Vec<String>> {
+        Ok(self.table.entries.iter().map(|x| x.name.clone()).collect())
+    }
+
+    fn for_each(&mut self, range: Range<usize>, f: &mut EntryFn) -> io::Result<()> {
+        for i in range.start..range.end.min(self.entry_count()) {
+            let entry = self.entry(i);
+            let offset = self.table.entries[i].offset;
+            let go_on = if self.kind == ContainerKind::Tar {
+                self.file.seek(SeekFrom::Start(offset))?;
+                f(&entry, &mut self.file.by_ref().take(entry.size))?
+            } else {
+                let mut gz = self.gz_at(offset)?;
+                let mut data = gz.by_ref().take(entry.size);
+                let go_on = f(&entry, &mut data)?;
+                io::copy(&mut data, &mut io::sink())?;
+                self.gz = Some((gz, offset + entry.size));
+                go_on
+            };
+            if !go_on {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn find(&mut self, name: &str) -> io::Result<Option<Entry>> {
+        let index = self.table.entries.iter().position(|x| x.name == name);
+        Ok(index.map(|i| self.entry(i)))
+    }
+}
+
+struct SevenZContainer {
+    file: File,
+    archive: sevenz_rust::Archive,
+    entries: Vec<usize>,          //archive file index, directories are skipped
+    position: Vec<Option<usize>>, //archive file index -> entry index
+    packed_ratio: f64,
+}
+
+fn sevenz_error(e: sevenz_rust::Error) -> io::Error {
+    match e {
+        sevenz_rust::Error::Io(e, _) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+impl SevenZContainer {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let archive = sevenz_rust::Archive::read(&mut file, len, &[]).map_err(sevenz_error)?;
+        let entries: Vec<usize> = (0..archive.files.len())
+            .filter(|i| !archive.files[*i].is_directory)
+            .collect();
+        let mut position = vec![None; archive.files.len()];
+        for (i, file_index) in entries.iter().enumerate() {
+            position[*file_index] = Some(i);
+        }
+        let unpacked: u64 = archive.files.iter().map(|x| x.size).sum();
+        Ok(SevenZContainer {
+            file,
+            archive,
+            entries,
+            position,
+            packed_ratio: len as f64 / unpacked.max(1) as f64,
+        })
+    }
+
+    fn entry(&self, index: usize) -> Entry {
+        let file = &self.archive.files[self.entries[index]];
+        Entry {
+            index,
+            name: file.name.clone(),
+            size: file.size,
+            packed_size: estimate(file.size, self.packed_ratio),
+            crc32: file.has_crc.then_some(file.crc as u32),
+        }
+    }
+}
+
+impl Container for SevenZContainer {
+    fn kind(&self) -> ContainerKind {
+        ContainerKind::SevenZ
+    }
+
+    fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn names(&mut self) -> io::Result<Vec<String>> {
+        Ok((0..self.entries.len())
+            .map(|i| self.entry(i).name)
+            .collect())
+    }
+
+    /// solid block is decoded from its start, so blocks are read in whole
+    fn for_each(&mut self, range: Range<usize>, f: &mut EntryFn) -> io::Result<()> {
+        let range = range.start..range.end.min(self.entry_count());
+        let stream_map = &self.archive.stream_map;
+        let mut blocks = BTreeSet::new();
+        let mut empty = vec![]; //entries without data
+        for i in range.clone() {
+            match stream_map.file_folder_index[self.entries[i]] {
+                Some(block) => {
+                    blocks.insert(block);
+                }
+                None => empty.push(i),
+            }
+        }
+        for block in blocks {
+            let first = stream_map.folder_first_file_index[block];
+            let mut file_index = first;
+            let mut stopped = false;
+            let decoder = sevenz_rust::BlockDecoder::new(block, &self.archive, &[], &mut self.file);
+            let position = &self.position;
+            let packed_ratio = self.packed_ratio;
+            decoder
+                .for_each_entries(&mut |file, data| {
+                    let index = position[file_index];
+                    file_index += 1;
+                    if let Some(index) = index.filter(|x| range.contains(x)) {
+                        let entry = Entry {
+                            index,
+                            name: file.name.clone(),
+                            size: file.size,
+                            packed_size: estimate(file.size, packed_ratio),
+                            crc32: file.has_crc.then_some(file.crc as u32),
+                        };
+                        if !f(&entry, data).map_err(sevenz_rust::Error::io)? {
+                            stopped = true;
+                            return Ok(false);
+                        }
+                    }
+                    io::copy(data, &mut io::sink()).map_err(sevenz_rust::Error::io)?;
+                    Ok(true)
+                })
+                .map_err(sevenz_error)?;
+            if stopped {
+                return Ok(());
+            }
+        }
+        for i in empty {
+            if !f(&self.entry(i), &mut io::empty())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn find(&mut self, name: &str) -> io::Result<Option<Entry>> {
+        let index =
+            (0..self.entries.len()).find(|i| self.archive.files[self.entries[*i]].name == name);
+        Ok(index.map(|i| self.entry(i)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const BOOKS: [(&str, &str); 3] = [("1.fb2", "first"), ("2.fb2", ""), ("3.fb2", "third")];
+
+    fn read_all(path: &Path) -> Vec<(usize, String, String)> {
+        let mut c = open(path).unwrap();
+        assert_eq!(c.entry_count(), BOOKS.len());
+        let mut res = vec![];
+        let n = c.entry_count();
+        c.for_each(0..n, &mut |entry, data| {
+            let mut s = String::new();
+            data.read_to_string(&mut s)?;
+            res.push((entry.index, entry.name.clone(), s));
+            Ok(true)
+        })
+        .unwrap();
+        res.sort();
+        //random access
+        let e = c.find("3.fb2").unwrap().unwrap();
+        assert_eq!(c.read(e.index).unwrap(), b"third");
+        let e = c.find("1.fb2").unwrap().unwrap();
+        assert_eq!(c.read(e.index).unwrap(), b"first");
+        assert!(c.find("4.fb2").unwrap().is_none());
+        res
+    }
+
+    #[test]
+    fn test_entry_names() {
+        let (cp866, _, _) = encoding_rs::IBM866.encode("Война и мир.fb2");
+        assert_eq!(decode_filename(&cp866).as_deref(), Some("Война и мир.fb2"));
+        //Unicode Path field with CRC of name in header
+        let mut data = vec![1];
+        data.extend(crc32fast::hash(b"1.fb2").to_le_bytes());
+        data.extend("Книга.fb2".as_bytes());
+        let mut options = zip::write::FullFileOptions::default();
+        options.add_extra_data(0x7074, &data, true).unwrap(); //0x7075 is reserved in zip crate
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        zip.start_file("1.fb2", options).unwrap();
+        zip.write_all(b"x").unwrap();
+        let mut raw = zip.finish().unwrap().into_inner();
+        let field = [0x74, 0x70, data.len() as u8, 0, 1];
+        let pos = raw.windows(5).position(|x| x == field).unwrap();
+        raw[pos] = 0x75;
+        let mut zip = zip::ZipArchive::new(io::Cursor::new(raw)).unwrap();
+        assert_eq!(zip_entry_name(&zip.by_index_raw(0).unwrap()), "Книга.fb2");
+    }
+
+    #[test]
+    fn test_containers() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        let mut tar = tar::Builder::new(vec![]);
+        for (name, content) in BOOKS {
+            std::fs::write(src.join(name), content).unwrap();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        let tar = tar.into_inner().unwrap();
+        std::fs::write(dir.path().join("a.tar"), &tar).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(&tar).unwrap();
+        std::fs::write(dir.path().join("a.tar.gz"), gz.finish().unwrap()).unwrap();
+        let mut zip = zip::ZipWriter::new(File::create(dir.path().join("a.zip")).unwrap());
+        for (name, content) in BOOKS {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        sevenz_rust::compress_to_path(&src, dir.path().join("a.7z")).unwrap();
+
+        let expected: Vec<_> = BOOKS
+            .iter()
+            .enumerate()
+            .map(|(i, (name, content))| (i, name.to_string(), content.to_string()))
+            .collect();
+        for name in ["a.zip", "a.tar", "a.tar.gz"] {
+            assert_eq!(read_all(&dir.path().join(name)), expected, "{name}");
+        }
+        //7z stores empty file without data after others
+        let mut res = read_all(&dir.path().join("a.7z"));
+        res.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            res.iter().map(|x| (&x.1, &x.2)).collect::<Vec<_>>(),
+            expected.iter().map(|x| (&x.1, &x.2)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            ContainerKind::from_filename("x.TGZ"),
+            Some(ContainerKind::TarGz)
+        );
+        assert!(ContainerKind::from_filename("x.rar").is_none());
+    }
+}
@@ -1,14 +1,18 @@
+use crate::charset;
 use crate::types::*;
 use base64::{engine::general_purpose::STANDARD_NO_PAD as base64engine, Engine};
 use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use quick_xml::events::attributes::{Attribute, Attributes};
 use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::name::QName;
 use quick_xml::Reader;
 use std::borrow::{Borrow, Cow};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str;
 
+const DETECT_SAMPLE_SIZE: usize = 65536; //enough for description and first chapter
+
 pub struct Fb2BookFormat;
 
 enum ParentNode {
@@ -83,10 +87,25 @@ impl BookFormat for Fb2BookFormat {
         with_cover: bool,
     ) -> Result<Book, ParserError> {
         let mut warning = Vec::<String>::new();
-        let encoding = detect_xml_encoding(raw);
+        let (encoding, by_content) = detect_xml_encoding(raw);
+        let declared = declared_encoding(raw);
+        if by_content {
+            warning.push(format!(
+                "{WARNING_ENCODING_DETECTED}: {} (declared: {})",
+                encoding.name(),
+                declared.map_or("-".into(), String::from_utf8_lossy)
+            ));
+        } else if let Some(name) = declared {
+            if Encoding::for_label(name).is_none() {
+                warning.push(format!(
+                    "{WARNING_ENCODING_FALLBACK}: {}",
+                    String::from_utf8_lossy(name)
+                ));
+            }
+        }
         let (xml_str, _enc, malformed) = encoding.decode(raw);
         if malformed {
-            warning.push("malformed characters replaced".to_string());
+            warning.push(WARNING_MALFORMED_CHARS.to_string());
         }
         let mut xml = quick_xml::Reader::from_str(xml_str.as_ref());
         xml.config_mut().trim_text(true);
@@ -507,292 +526,764 @@ impl BookFormat for Fb2BookFormat {
         })
     }
 
-    #[allow(clippy::cognitive_complexity, clippy::single_match)]
     fn render_to_html(&self, raw: &[u8]) -> RenderResult {
-        let encoding = detect_xml_encoding(raw);
+        let mut book = render_book(raw, None, false)?;
+        Ok((book.title, book.content.pop().unwrap_or_default()))
+    }
+
+    fn render_chapters(&self, raw: &[u8], image_url: &str) -> ChaptersResult {
+        render_book(raw, Some(image_url), true)
+    }
+
+    fn image(&self, raw: &[u8], id: &str) -> ImageResult {
+        let (encoding, _) = detect_xml_encoding(raw);
         let (xml_str, _enc, _malformed) = encoding.decode(raw);
         let mut xml = quick_xml::Reader::from_str(xml_str.as_ref());
-        xml.config_mut().expand_empty_elements = true; //for compatibility with HTML4 <tag/> -> <tag></tag>
-        let mut res = Vec::<Event>::new(); //generaged sequence of xhtml events
-        let mut mode = XMode::Start;
-        let mut in_book_title: bool = false;
-        let mut title = String::new();
-        let mut img = HashMap::<Vec<u8>, (Vec<u8>, Vec<u8>)>::new(); //image-id -> (content-type,base64-data)
-        let mut description_start: usize = 0;
-        let mut description_end: usize = 0;
-
-        //phase 1: collect XML events from [ title-info (annotation+cover), bodies, binaries ]
-        //map XML tag to HTML tag
-        //extract book title
+        let mut content_type: Option<String> = None; //inside requested <binary>
         loop {
-            mode = match xml.read_event() {
-                Err(_) => mode, //ignore xml error
+            match xml.read_event() {
+                Err(_) => (), //ignore xml error
                 Ok(Event::Eof) => break,
-                Ok(Event::Start(e)) => {
-                    let this_tag = e.local_name();
-                    match mode {
-                        XMode::Start => match this_tag.as_ref() {
-                            b"body" => {
-                                // -> <div class="body" id="..">
-                                let mut attrs = vec![Attribute::from(("class", "body"))];
-                                if let Some(id) = get_attr_raw(b"id", &mut e.attributes()) {
-                                    let id = id.value.to_vec();
-                                    attrs.push(Attribute {
-                                        key: QName(b"id"),
-                                        value: Cow::Owned(id),
-                                    });
+                Ok(Event::Start(e))
+                    if e.local_name().as_ref() == b"binary"
+                        && get_attr_string("id", &mut e.attributes(), &xml).as_deref()
+                            == Some(id) =>
+                {
+                    let ct = get_attr_string("content-type", &mut e.attributes(), &xml);
+                    content_type = Some(ct.unwrap_or_default());
+                }
+                Ok(Event::Text(e)) => {
+                    if let Some(ct) = content_type.take() {
+                        return try_decode_base64(&e)
+                            .ok()
+                            .map(|(data, _warning)| (ct, data));
+                    }
+                }
+                Ok(Event::End(_)) => content_type = None,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    fn text_blocks(&self, raw: &[u8]) -> TextResult {
+        let book = render_book(raw, Some(""), true)?;
+        let mut res = Vec::new();
+        for (chapter, html) in book.content.iter().enumerate() {
+            let mut blocks = html_text_blocks(html);
+            for b in blocks.iter_mut() {
+                b.chapter = chapter;
+            }
+            res.append(&mut blocks);
+        }
+        Ok(res)
+    }
+}
+
+/// render FB2 to HTML: whole book as one chapter or split by top-level sections;
+/// images are inlined as data URI or referenced by URL `{image_url}{id}`
+#[allow(clippy::cognitive_complexity, clippy::single_match)]
+fn render_book(raw: &[u8], image_url: Option<&str>, split: bool) -> ChaptersResult {
+    let (encoding, _) = detect_xml_encoding(raw);
+    let (xml_str, _enc, _malformed) = encoding.decode(raw);
+    let mut xml = quick_xml::Reader::from_str(xml_str.as_ref());
+    xml.config_mut().expand_empty_elements = true; //for compatibility with HTML4 <tag/> -> <tag></tag>
+    let mut res = Vec::<Event>::new(); //generaged sequence of xhtml events
+    let mut mode = XMode::Start;
+    let mut in_book_title: bool = false;
+    let mut title = String::new();
+    let mut img = HashMap::<Vec<u8>, (Vec<u8>, Vec<u8>)>::new(); //image-id -> (content-type,base64-data)
+    let mut description_start: usize = 0;
+    let mut description_end: usize = 0;
+
+    //phase 1: collect XML events from [ title-info (annotation+cover), bodies, binaries ]
+    //map XML tag to HTML tag
+    //extract book title
+    loop {
+        mode = match xml.read_event() {
+            Err(_) => mode, //ignore xml error
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(e)) => {
+                let this_tag = e.local_name();
+                match mode {
+                    XMode::Start => match this_tag.as_ref() {
+                        b"body" => {
+                            // -> <div class="body" id="..">, <body name="notes"> -> <div class="body notes">
+                            let class = match get_attr_raw(b"name", &mut e.attributes()) {
+                                Some(a) if is_notes_body(&a.value) => "body notes",
+                                _ => "body",
+                            };
+                            let mut attrs = vec![Attribute::from(("class", class))];
+                            if let Some(id) = get_attr_raw(b"id", &mut e.attributes()) {
+                                let id = id.value.to_vec();
+                                attrs.push(Attribute {
+                                    key: QName(b"id"),
+                                    value: Cow::Owned(id),
+                                });
+                            }
+                            let tag = Event::Start(BytesStart::new("div").with_attributes(attrs));
+                            res.push(tag);
+                            XMode::Body(ParentNode::Start)
+                        }
+                        b"description" => {
+                            description_start = xml.buffer_position();
+                            mode
+                        }
+                        b"title-info" => XMode::TitleInfo,
+                        b"binary" => {
+                            if let Some(id) = get_attr_raw(b"id", &mut e.attributes()) {
+                                let id = id.value.to_vec();
+                                let ct = get_attr_raw(b"content-type", &mut e.attributes())
+                                    .map(|a| a.value.to_vec())
+                                    .unwrap_or_else(|| b"".to_vec());
+                                XMode::Binary(id, ct)
+                            } else {
+                                mode
+                            }
+                        }
+                        _ => mode,
+                    },
+                    XMode::TitleInfo => match this_tag.as_ref() {
+                        b"annotation" => XMode::Annotation(ParentNode::TitleInfo),
+                        b"image" => {
+                            if let Some(a) = get_attr_raw(b"href", &mut e.attributes()) {
+                                let mut href = a.value.to_vec();
+                                if !href.is_empty() && href[0] == b'#' {
+                                    href.remove(0); // "#link" -> "link"
                                 }
+                                let attrs = vec![Attribute {
+                                    key: QName(b"href"),
+                                    value: Cow::Owned(href),
+                                }];
                                 let tag =
-                                    Event::Start(BytesStart::new("div").with_attributes(attrs));
+                                    Event::Start(BytesStart::new("image").with_attributes(attrs));
                                 res.push(tag);
-                                XMode::Body(ParentNode::Start)
-                            }
-                            b"description" => {
-                                description_start = xml.buffer_position();
+                                mode
+                            } else {
                                 mode
                             }
-                            b"title-info" => XMode::TitleInfo,
-                            b"binary" => {
-                                if let Some(id) = get_attr_raw(b"id", &mut e.attributes()) {
-                                    let id = id.value.to_vec();
-                                    let ct = get_attr_raw(b"content-type", &mut e.attributes())
-                                        .map(|a| a.value.to_vec())
-                                        .unwrap_or_else(|| b"".to_vec());
-                                    XMode::Binary(id, ct)
-                                } else {
-                                    mode
+                        }
+                        b"book-title" => {
+                            in_book_title = true;
+                            mode
+                        }
+                        _ => mode,
+                    },
+                    XMode::Annotation(_) | XMode::Body(_) => match this_tag.as_ref() {
+                        b"p" | b"strong" | b"sup" | b"sub" | b"table" | b"tr" | b"th" | b"td" => {
+                            res.push(Event::Start(e.to_owned())); //keep as is
+                            mode
+                        }
+                        b"emphasis" => {
+                            res.push(Event::Start(BytesStart::new("em")));
+                            mode
+                        }
+                        b"a" | b"image" => {
+                            //remove namespace from href="ns:xxx"
+                            if let Some(a) = get_attr_raw(b"href", &mut e.attributes()) {
+                                let mut href = a.value.to_vec();
+                                if this_tag.as_ref() == b"image"
+                                    && !href.is_empty()
+                                    && href[0] == b'#'
+                                {
+                                    href.remove(0); // "#link" -> "link"
                                 }
-                            }
-                            _ => mode,
-                        },
-                        XMode::TitleInfo => match this_tag.as_ref() {
-                            b"annotation" => XMode::Annotation(ParentNode::TitleInfo),
-                            b"image" => {
-                                if let Some(a) = get_attr_raw(b"href", &mut e.attributes()) {
-                                    let mut href = a.value.to_vec();
-                                    if !href.is_empty() && href[0] == b'#' {
-                                        href.remove(0); // "#link" -> "link"
+                                let mut attrs = vec![Attribute {
+                                    key: QName(b"href"),
+                                    value: Cow::Owned(href),
+                                }];
+                                //<a type="note"> -> <a class="note">
+                                if let Some(t) = get_attr_raw(b"type", &mut e.attributes()) {
+                                    if t.value.as_ref() == b"note" {
+                                        attrs.push(Attribute::from(("class", "note")));
                                     }
-                                    let attrs = vec![Attribute {
-                                        key: QName(b"href"),
-                                        value: Cow::Owned(href),
-                                    }];
-                                    let tag = Event::Start(
-                                        BytesStart::new("image").with_attributes(attrs),
-                                    );
-                                    res.push(tag);
-                                    mode
+                                }
+                                let new_tag = Event::Start(
+                                    BytesStart::new(
+                                        String::from_utf8_lossy(this_tag.as_ref()).into_owned(),
+                                    )
+                                    .with_attributes(attrs),
+                                );
+                                res.push(new_tag);
+                            } else if this_tag.as_ref() == b"a" {
+                                res.push(Event::Start(BytesStart::new("a"))); //keep balanced with </a>
+                            }
+                            mode
+                        }
+                        b"empty-line" => {
+                            res.push(Event::Start(BytesStart::new("br")));
+                            mode
+                        }
+                        tag => {
+                            let mut attrs = vec![Attribute {
+                                key: QName(b"class"),
+                                value: Cow::Owned(tag.to_vec()),
+                            }];
+                            if let Some(a) = get_attr_raw(b"id", &mut e.attributes()) {
+                                let id = a.value.to_vec();
+                                attrs.push(Attribute {
+                                    key: QName(b"id"),
+                                    value: Cow::Owned(id),
+                                });
+                            }
+                            let b = BytesStart::new("div").with_attributes(attrs);
+                            res.push(Event::Start(b));
+                            mode
+                        }
+                    },
+                    _ => mode,
+                }
+            }
+            Ok(Event::End(e)) => {
+                let tag = e.local_name();
+                in_book_title = false;
+                match mode {
+                    XMode::Start if tag.as_ref() == b"description" => {
+                        description_end = xml.buffer_position();
+                        mode
+                    }
+                    XMode::Binary(_, _) => XMode::Start,
+                    XMode::TitleInfo if tag.as_ref() == b"title-info" => XMode::Start,
+                    XMode::Annotation(ref parent) | XMode::Body(ref parent) => {
+                        match tag.as_ref() {
+                            b"annotation" => {
+                                if let ParentNode::TitleInfo = parent {
+                                    XMode::TitleInfo
                                 } else {
+                                    //<annotation> inside <body>
+                                    res.push(Event::End(BytesEnd::new("div")));
                                     mode
                                 }
                             }
-                            b"book-title" => {
-                                in_book_title = true;
-                                mode
+                            b"body" => {
+                                res.push(Event::End(BytesEnd::new("div")));
+                                XMode::Start
                             }
-                            _ => mode,
-                        },
-                        XMode::Annotation(_) | XMode::Body(_) => match this_tag.as_ref() {
-                            b"p" | b"strong" | b"sup" | b"sub" | b"table" | b"tr" | b"th"
-                            | b"td" => {
-                                res.push(Event::Start(e.to_owned())); //keep as is
+                            b"a" | b"p" | b"strong" | b"sup" | b"sub" | b"table" | b"tr"
+                            | b"th" | b"td" => {
+                                res.push(Event::End(e.to_owned())); //keep as is
                                 mode
                             }
                             b"emphasis" => {
-                                res.push(Event::Start(BytesStart::new("em")));
-                                mode
-                            }
-                            b"a" | b"image" => {
-                                //remove namespace from href="ns:xxx"
-                                if let Some(a) = get_attr_raw(b"href", &mut e.attributes()) {
-                                    let mut href = a.value.to_vec();
-                                    if this_tag.as_ref() == b"image"
-                                        && !href.is_empty()
-                                        && href[0] == b'#'
-                                    {
-                                        href.remove(0); // "#link" -> "link"
-                                    }
-                                    let attrs = vec![Attribute {
-                                        key: QName(b"href"),
-                                        value: Cow::Owned(href),
-                                    }];
-                                    let new_tag = Event::Start(
-                                        BytesStart::new(
-                                            String::from_utf8_lossy(this_tag.as_ref()).into_owned(),
-                                        )
-                                        .with_attributes(attrs),
-                                    );
-                                    res.push(new_tag);
-                                }
+                                res.push(Event::End(BytesEnd::new("em")));
                                 mode
                             }
-                            b"empty-line" => {
-                                res.push(Event::Start(BytesStart::new("br")));
+                            b"empty-line" | b"image" => mode,
+                            _ => {
+                                res.push(Event::End(BytesEnd::new("div")));
                                 mode
                             }
-                            tag => {
-                                let mut attrs = vec![Attribute {
-                                    key: QName(b"class"),
-                                    value: Cow::Owned(tag.to_vec()),
-                                }];
-                                if let Some(a) = get_attr_raw(b"id", &mut e.attributes()) {
-                                    let id = a.value.to_vec();
-                                    attrs.push(Attribute {
-                                        key: QName(b"id"),
-                                        value: Cow::Owned(id),
-                                    });
-                                }
-                                let b = BytesStart::new("div").with_attributes(attrs);
-                                res.push(Event::Start(b));
-                                mode
-                            }
-                        },
-                        _ => mode,
-                    }
-                }
-                Ok(Event::End(e)) => {
-                    let tag = e.local_name();
-                    in_book_title = false;
-                    match mode {
-                        XMode::Start if tag.as_ref() == b"description" => {
-                            description_end = xml.buffer_position();
-                            mode
                         }
-                        XMode::Binary(_, _) => XMode::Start,
-                        XMode::TitleInfo if tag.as_ref() == b"title-info" => XMode::Start,
-                        XMode::Annotation(ref parent) | XMode::Body(ref parent) => {
-                            match tag.as_ref() {
-                                b"annotation" => {
-                                    if let ParentNode::TitleInfo = parent {
-                                        XMode::TitleInfo
-                                    } else {
-                                        //<annotation> inside <body>
-                                        res.push(Event::End(BytesEnd::new("div")));
-                                        mode
-                                    }
-                                }
-                                b"body" => {
-                                    res.push(Event::End(BytesEnd::new("div")));
-                                    XMode::Start
-                                }
-                                b"a" | b"p" | b"strong" | b"sup" | b"sub" | b"table" | b"tr"
-                                | b"th" | b"td" => {
-                                    res.push(Event::End(e.to_owned())); //keep as is
-                                    mode
-                                }
-                                b"emphasis" => {
-                                    res.push(Event::End(BytesEnd::new("em")));
-                                    mode
-                                }
-                                b"empty-line" | b"image" => mode,
-                                _ => {
-                                    res.push(Event::End(BytesEnd::new("div")));
-                                    mode
-                                }
-                            }
-                        }
-                        _ => mode,
-                    }
-                }
-                Ok(Event::Text(e)) => match mode {
-                    XMode::TitleInfo if in_book_title => {
-                        title = e.unescape().unwrap().into_owned();
-                        mode
-                    }
-                    XMode::Binary(id, ct) => {
-                        let b64 = e.into_inner().into_owned();
-                        img.insert(id, (ct, b64));
-                        XMode::Start
-                    }
-                    XMode::Annotation(_) | XMode::Body(_) => {
-                        res.push(Event::Text(e.to_owned()));
-                        mode
                     }
                     _ => mode,
-                },
+                }
+            }
+            Ok(Event::Text(e)) => match mode {
+                XMode::TitleInfo if in_book_title => {
+                    title = e.unescape().map_err(|e| e.to_string())?.into_owned();
+                    mode
+                }
+                XMode::Binary(id, ct) => {
+                    let b64 = e.into_inner().into_owned();
+                    img.insert(id, (ct, b64));
+                    XMode::Start
+                }
+                XMode::Annotation(_) | XMode::Body(_) => {
+                    res.push(Event::Text(e.to_owned()));
+                    mode
+                }
                 _ => mode,
+            },
+            _ => mode,
+        }
+    }
+
+    //phase 2: parse <description> tag again, construct HTML tree with all technical information ("book imprint")
+    // <tag aaa="bbb">xxx</tag> -> <div><span class="name">tag</span><span class="value">aaa=bbb xxx</span><div>
+    let attrs = vec![Attribute::from(("class", "description"))];
+    res.push(Event::Start(BytesStart::new("div").with_attributes(attrs)));
+    let mut xml = quick_xml::Reader::from_str(&xml_str[description_start..description_end]);
+    xml.config_mut().expand_empty_elements = true;
+    loop {
+        match xml.read_event() {
+            Err(_) => (), //ignore xml error
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(ref e)) => {
+                res.push(Event::Start(BytesStart::new("div")));
+                res.push(Event::Start(
+                    BytesStart::new("span")
+                        .with_attributes(vec![Attribute::from(("class", "name"))]),
+                ));
+                let tag_name = String::from_utf8(e.name().as_ref().to_vec()).unwrap_or_default();
+                res.push(Event::Text(BytesText::from_escaped(tag_name)));
+                res.push(Event::End(BytesEnd::new("span")));
+                res.push(Event::Start(
+                    BytesStart::new("span")
+                        .with_attributes(vec![Attribute::from(("class", "value"))]),
+                ));
+                for a in e.attributes().flatten() {
+                    let v = a.decode_and_unescape_value(&xml).unwrap_or_default();
+                    let txt = format!("{}={} ", String::from_utf8_lossy(a.key.as_ref()), v,);
+                    res.push(Event::Text(BytesText::from_escaped(Cow::Owned(txt))));
+                }
+            }
+            Ok(Event::Text(text)) => res.push(Event::Text(text.to_owned())),
+            Ok(Event::End(_)) => {
+                res.push(Event::End(BytesEnd::new("span")));
+                res.push(Event::End(BytesEnd::new("div")));
             }
+            _ => (),
         }
+    }
+    res.push(Event::End(BytesEnd::new("div"))); //</description>
 
-        //phase 2: parse <description> tag again, construct HTML tree with all technical information ("book imprint")
-        // <tag aaa="bbb">xxx</tag> -> <div><span class="name">tag</span><span class="value">aaa=bbb xxx</span><div>
-        let attrs = vec![Attribute::from(("class", "description"))];
-        res.push(Event::Start(BytesStart::new("div").with_attributes(attrs)));
-        let mut xml = quick_xml::Reader::from_str(&xml_str[description_start..description_end]);
-        xml.config_mut().expand_empty_elements = true;
-        loop {
-            match xml.read_event() {
-                Err(_) => (), //ignore xml error
-                Ok(Event::Eof) => break,
-                Ok(Event::Start(ref e)) => {
-                    res.push(Event::Start(BytesStart::new("div")));
-                    res.push(Event::Start(
-                        BytesStart::new("span")
-                            .with_attributes(vec![Attribute::from(("class", "name"))]),
-                    ));
-                    let tag_name =
-                        String::from_utf8(e.name().as_ref().to_vec()).unwrap_or_default();
-                    res.push(Event::Text(BytesText::from_escaped(tag_name)));
-                    res.push(Event::End(BytesEnd::new("span")));
-                    res.push(Event::Start(
-                        BytesStart::new("span")
-                            .with_attributes(vec![Attribute::from(("class", "value"))]),
-                    ));
-                    for a in e.attributes().flatten() {
-                        let v = a.decode_and_unescape_value(&xml).unwrap_or_default();
-                        let txt = format!("{}={} ", String::from_utf8_lossy(a.key.as_ref()), v,);
-                        res.push(Event::Text(BytesText::from_escaped(Cow::Owned(txt))));
+    link_notes(&mut res);
+
+    //phase 3: split into chapters, find chapter of each link target
+    let (chapters, toc) = if split {
+        split_chapters(res)
+    } else {
+        (vec![res], vec![String::new()])
+    };
+    let mut targets = HashMap::<Vec<u8>, usize>::new(); //element id -> chapter
+    if chapters.len() > 1 {
+        for (n, events) in chapters.iter().enumerate() {
+            for event in events {
+                if let Event::Start(e) = event {
+                    if let Some(id) = get_attr_raw(b"id", &mut e.attributes()) {
+                        targets.insert(id.value.to_vec(), n);
                     }
                 }
-                Ok(Event::Text(text)) => res.push(Event::Text(text.to_owned())),
-                Ok(Event::End(_)) => {
-                    res.push(Event::End(BytesEnd::new("span")));
-                    res.push(Event::End(BytesEnd::new("div")));
-                }
-                _ => (),
             }
         }
-        res.push(Event::End(BytesEnd::new("div"))); //</description>
+    }
 
-        //phase 3: construct HTML, inline image content
+    //phase 4: construct HTML, inline image content, set id of text blocks
+    let mut content = Vec::new();
+    let mut para_num = 0;
+    let mut block_num = 0;
+    for (chapter_num, events) in chapters.into_iter().enumerate() {
         let mut writer = quick_xml::Writer::new(std::io::Cursor::new(Vec::new()));
-        for event in res {
+        for event in events {
             match event {
                 Event::Start(ref e) => {
-                    if e.local_name().as_ref() == b"image" {
+                    let has_id = get_attr_raw(b"id", &mut e.attributes()).is_some();
+                    if e.local_name().as_ref() == b"p" && !has_id {
+                        //same numbering as in render.js
+                        let mut b = e.to_owned();
+                        b.push_attribute(("id", format!("_p{para_num}").as_str()));
+                        para_num += 1;
+                        writer
+                            .write_event(Event::Start(b))
+                            .map_err(|e| e.to_string())?;
+                    } else if is_text_block(e) && !has_id {
+                        let mut b = e.to_owned();
+                        b.push_attribute(("id", format!("_b{block_num}").as_str()));
+                        block_num += 1;
+                        writer
+                            .write_event(Event::Start(b))
+                            .map_err(|e| e.to_string())?;
+                    } else if e.local_name().as_ref() == b"image" {
                         if let Some(href) = get_attr_raw(b"href", &mut e.attributes()) {
                             let id = href.value.to_vec();
                             if let Some((ct, data)) = img.get(&id) {
-                                let mut src = b"data:".to_vec();
-                                src.extend_from_slice(ct); //content-type
-                                src.extend_from_slice(b" ;base64, ");
-                                src.extend_from_slice(data); //image data
+                                let src = match image_url {
+                                    Some(url) => {
+                                        let id = String::from_utf8_lossy(&id);
+                                        let id = utf8_percent_encode(&id, NON_ALPHANUMERIC);
+                                        format!("{url}{id}").into_bytes()
+                                    }
+                                    None => {
+                                        let mut src = b"data:".to_vec();
+                                        src.extend_from_slice(ct); //content-type
+                                        src.extend_from_slice(b" ;base64, ");
+                                        src.extend_from_slice(data); //image data
+                                        src
+                                    }
+                                };
                                 let attrs = vec![Attribute {
                                     key: QName(b"src"),
                                     value: Cow::Owned(src),
                                 }];
                                 let b = BytesStart::new("img").with_attributes(attrs);
-                                writer.write_event(Event::Start(b)).unwrap();
+                                writer
+                                    .write_event(Event::Start(b))
+                                    .map_err(|e| e.to_string())?;
+                            }
+                        }
+                    } else if e.local_name().as_ref() == b"a" {
+                        //link to another chapter: "#id" -> "?chapter=N#id"
+                        let href = get_attr_raw(b"href", &mut e.attributes())
+                            .map(|a| a.value.to_vec())
+                            .unwrap_or_default();
+                        match href.strip_prefix(b"#").and_then(|id| targets.get(id)) {
+                            Some(&n) if n != chapter_num => {
+                                let mut link = format!("?chapter={n}").into_bytes();
+                                link.extend_from_slice(&href);
+                                let mut b = BytesStart::new("a");
+                                b.push_attribute(Attribute {
+                                    key: QName(b"href"),
+                                    value: Cow::Owned(link),
+                                });
+                                //keep class and id of note links
+                                b.extend_attributes(
+                                    e.attributes()
+                                        .flatten()
+                                        .filter(|a| a.key.as_ref() != b"href"),
+                                );
+                                writer
+                                    .write_event(Event::Start(b))
+                                    .map_err(|e| e.to_string())?;
                             }
+                            _ => writer.write_event(event).map_err(|e| e.to_string())?,
                         }
                     } else {
-                        writer.write_event(event).unwrap();
+                        writer.write_event(event).map_err(|e| e.to_string())?;
                     }
                 }
                 Event::End(_) => {
-                    writer.write_event(event).unwrap();
+                    writer.write_event(event).map_err(|e| e.to_string())?;
                 }
                 Event::Text(_) => {
-                    writer.write_event(event).unwrap();
+                    writer.write_event(event).map_err(|e| e.to_string())?;
                 }
                 _ => (),
             }
         }
+        let html = writer.into_inner().into_inner();
+        content.push(String::from_utf8(html).map_err(|e| e.to_string())?);
+    }
+    Ok(Chapters {
+        title,
+        toc,
+        content,
+    })
+}
+
+fn is_notes_body(name: &[u8]) -> bool {
+    name == b"notes" || name == b"comments"
+}
+
+fn event_class(e: &BytesStart) -> Option<Vec<u8>> {
+    get_attr_raw(b"class", &mut e.attributes()).map(|a| a.value.to_vec())
+}
+
+/// mark links to notes with class "note" and id "_rN", add back-link from note to its first reference
+fn link_notes(events: &mut Vec<Event>) {
+    //ids of elements inside notes bodies
+    let mut notes = HashSet::<Vec<u8>>::new();
+    let mut depth = 0; //nesting level of <div> inside notes body
+    for event in events.iter() {
+        match event {
+            Event::Start(e) if depth > 0 => {
+                if let Some(id) = get_attr_raw(b"id", &mut e.attributes()) {
+                    notes.insert(id.value.to_vec());
+                }
+                if e.local_name().as_ref() == b"div" {
+                    depth += 1;
+                }
+            }
+            Event::Start(e) if event_class(e).as_deref() == Some(b"body notes") => depth = 1,
+            Event::End(e) if depth > 0 && e.local_name().as_ref() == b"div" => depth -= 1,
+            _ => (),
+        }
+    }
+    //target id -> id of first link to it
+    let mut refs = HashMap::<Vec<u8>, String>::new();
+    let mut ref_num = 0;
+    for event in events.iter_mut() {
+        let (href, target) = match event {
+            Event::Start(e) if e.local_name().as_ref() == b"a" => {
+                let href = get_attr_raw(b"href", &mut e.attributes())
+                    .map(|a| a.value.to_vec())
+                    .unwrap_or_default();
+                match href.strip_prefix(b"#") {
+                    Some(t) if notes.contains(t) || event_class(e).as_deref() == Some(b"note") => {
+                        let t = t.to_vec();
+                        (href, t)
+                    }
+                    _ => continue,
+                }
+            }
+            _ => continue,
+        };
+        let id = format!("_r{ref_num}");
+        ref_num += 1;
+        let attrs = vec![
+            Attribute {
+                key: QName(b"href"),
+                value: Cow::Owned(href),
+            },
+            Attribute::from(("class", "note")),
+            Attribute::from(("id", id.as_str())),
+        ];
+        *event = Event::Start(BytesStart::new("a").with_attributes(attrs).into_owned());
+        refs.entry(target).or_insert(id);
+    }
+    if refs.is_empty() {
+        return;
+    }
+    //back-link at start of each referenced note
+    let mut res = Vec::with_capacity(events.len() + refs.len() * 3);
+    for event in events.drain(..) {
+        let back = match &event {
+            Event::Start(e) if e.local_name().as_ref() == b"div" => {
+                get_attr_raw(b"id", &mut e.attributes())
+                    .and_then(|id| refs.remove(id.value.as_ref()))
+            }
+            _ => None,
+        };
+        res.push(event);
+        if let Some(back) = back {
+            let href = format!("#{back}");
+            let attrs = vec![
+                Attribute::from(("href", href.as_str())),
+                Attribute::from(("class", "backlink")),
+            ];
+            res.push(Event::Start(
+                BytesStart::new("a").with_attributes(attrs).into_owned(),
+            ));
+            res.push(Event::Text(BytesText::new("\u{2191}"))); //up arrow
+            res.push(Event::End(BytesEnd::new("a")));
+        }
+    }
+    *events = res;
+}
+
+/// split rendered events by top-level sections of main body, other bodies (notes) go to separate chapters,
+/// book imprint goes to first chapter; returns events and title of each chapter
+fn split_chapters(events: Vec<Event>) -> (Vec<Vec<Event>>, Vec<String>) {
+    let mut chapters = vec![Vec::new()];
+    let mut toc = vec![String::new()];
+    let mut current = 0;
+    let mut depth = 0; //nesting level of <div>
+    let mut body_num = 0;
+    let mut title_depth: Option<usize> = None; //inside title of current chapter
+    for event in events {
+        match &event {
+            Event::Start(e) if e.local_name().as_ref() == b"div" => {
+                let class = get_attr_raw(b"class", &mut e.attributes()).map(|a| a.value.to_vec());
+                match (depth, class.as_deref()) {
+                    (0, Some(b"body" | b"body notes")) => {
+                        body_num += 1;
+                        if body_num > 1 {
+                            chapters.push(Vec::new());
+                            toc.push(String::new());
+                            current = chapters.len() - 1;
+                        }
+                    }
+                    (0, Some(b"description")) => current = 0,
+                    (1, Some(b"section")) if body_num == 1 => {
+                        //close <div class="body"> of previous chapter and open it again in new one
+                        chapters[current].push(Event::End(BytesEnd::new("div")));
+                        let attrs = vec![Attribute::from(("class", "body"))];
+                        chapters.push(vec![Event::Start(
+                            BytesStart::new("div").with_attributes(attrs),
+                        )]);
+                        toc.push(String::new());
+                        current = chapters.len() - 1;
+                    }
+                    (_, Some(b"title")) if title_depth.is_none() && toc[current].is_empty() => {
+                        title_depth = Some(depth)
+                    }
+                    _ => (),
+                }
+                depth += 1;
+            }
+            Event::End(e) if e.local_name().as_ref() == b"div" => {
+                depth = depth.saturating_sub(1);
+                if title_depth == Some(depth) {
+                    title_depth = None;
+                }
+            }
+            Event::Text(e) if title_depth.is_some() => {
+                let text = e.unescape().unwrap_or_default();
+                let t = &mut toc[current];
+                if !t.is_empty() && !text.trim().is_empty() {
+                    t.push(' ');
+                }
+                t.push_str(text.trim());
+            }
+            _ => (),
+        }
+        chapters[current].push(event);
+    }
+    (chapters, toc)
+}
 
-        let content = writer.into_inner().into_inner();
-        let content = String::from_utf8(content).unwrap();
-        Ok((title, content))
+/// classes of <div> rendered from FB2 paragraph-like tags (besides <p>)
+const TEXT_BLOCK_CLASSES: [&[u8]; 3] = [b"v", b"subtitle", b"text-author"];
+
+fn is_text_block(e: &BytesStart) -> bool {
+    e.local_name().as_ref() == b"div"
+        && match get_attr_raw(b"class", &mut e.attributes()) {
+            Some(a) => TEXT_BLOCK_CLASSES.contains(&a.value.as_ref()),
+            None => false,
+        }
+}
+
+/// extract text of paragraphs from HTML produced by render_to_html()
+fn html_text_blocks(html: &str) -> Vec<TextBlock> {
+    struct Section {
+        id: Option<String>,
+        title: Option<String>,
+    }
+    #[derive(PartialEq)]
+    enum Node {
+        Section,
+        Title,
+        Block,
+        Other,
+    }
+    let mut xml = quick_xml::Reader::from_str(html);
+    xml.config_mut().check_end_names = false; //<br> and <img> are not closed
+    let mut res = Vec::new();
+    let mut stack = Vec::<Node>::new();
+    let mut sections = Vec::<Section>::new();
+    let mut block: Option<TextBlock> = None;
+    let mut title: Option<String> = None;
+    loop {
+        match xml.read_event() {
+            Err(_) => (), //ignore xml error
+            Ok(Event::Eof) => break,
+            Ok(Event::Start(ref e)) => {
+                let name = e.local_name();
+                if name.as_ref() == b"br" || name.as_ref() == b"img" {
+                    continue; //void element
+                }
+                let id = get_attr_string("id", &mut e.attributes(), &xml);
+                let class = get_attr_raw(b"class", &mut e.attributes()).map(|a| a.value.to_vec());
+                let node = if block.is_some() {
+                    Node::Other //nested tags inside paragraph
+                } else if name.as_ref() == b"p" || is_text_block(e) {
+                    block = Some(TextBlock {
+                        id: id.unwrap_or_default(),
+                        chapter: 0,
+                        section: sections.last().and_then(|s| s.id.clone()),
+                        title: sections.last().and_then(|s| s.title.clone()),
+                        text: String::new(),
+                    });
+                    Node::Block
+                } else if class.as_deref() == Some(b"section") {
+                    sections.push(Section { id, title: None });
+                    Node::Section
+                } else if class.as_deref() == Some(b"title") && title.is_none() {
+                    title = Some(String::new());
+                    Node::Title
+                } else {
+                    Node::Other
+                };
+                stack.push(node);
+            }
+            Ok(Event::Text(e)) => {
+                let text = e.unescape().unwrap_or_default();
+                if let Some(b) = &mut block {
+                    b.text.push_str(&text);
+                }
+                if let Some(t) = &mut title {
+                    if !t.is_empty() {
+                        t.push(' ');
+                    }
+                    t.push_str(text.trim());
+                }
+            }
+            Ok(Event::End(_)) => match stack.pop() {
+                Some(Node::Block) => {
+                    if let Some(b) = block.take() {
+                        if !b.id.is_empty() && !b.text.trim().is_empty() {
+                            res.push(b);
+                        }
+                    }
+                }
+                Some(Node::Title) => {
+                    if let (Some(s), Some(t)) = (sections.last_mut(), title.take()) {
+                        if s.title.is_none() {
+                            s.title = Some(t);
+                        }
+                    }
+                }
+                Some(Node::Section) => {
+                    sections.pop();
+                }
+                _ => (),
+            },
+            _ => (),
+        }
     }
+    res
+}
+
+#[test]
+fn test_html_text_blocks() {
+    let html = r#"<p id="_p0">annotation</p><div class="body"><div class="section" id="ch1"><div class="title"><p id="_p1">Chapter &amp; one</p></div><p id="_p2">some <em>text</em><br>here</p><div class="poem"><div class="v" id="_b0">verse</div></div></div></div>"#;
+    let b = html_text_blocks(html);
+    assert_eq!(b.len(), 4);
+    assert_eq!((b[0].id.as_str(), b[0].section.as_deref()), ("_p0", None));
+    assert_eq!(b[1].text, "Chapter & one");
+    assert_eq!(b[2].id, "_p2");
+    assert_eq!(b[2].text, "some texthere");
+    assert_eq!(b[2].section.as_deref(), Some("ch1"));
+    assert_eq!(b[2].title.as_deref(), Some("Chapter & one"));
+    assert_eq!((b[3].id.as_str(), b[3].text.as_str()), ("_b0", "verse"));
+}
+
+#[test]
+fn test_encoding_fallback() {
+    let fb2 = r#"<?xml version="1.0" encoding="koi9"?><FictionBook><description><title-info><book-title>Book</book-title></title-info></description><body><p>text</p></body></FictionBook>"#;
+    let book = Fb2BookFormat
+        .parse(fb2.as_bytes(), true, false, false)
+        .unwrap();
+    assert_eq!(book.encoding, "UTF-8");
+    assert_eq!(
+        book.warning,
+        vec![format!("{WARNING_ENCODING_FALLBACK}: koi9")]
+    );
+}
+
+#[test]
+fn test_encoding_by_content() {
+    let fb2 = r#"<FictionBook><description><title-info><book-title>Война и мир</book-title></title-info></description><body><p>Ну, князь, Генуя и Лукка стали не больше как поместья фамилии Бонапарте.</p></body></FictionBook>"#;
+    let check = |raw: &[u8], encoding: &str, declared: &str| {
+        let book = Fb2BookFormat.parse(raw, true, false, false).unwrap();
+        assert_eq!(book.title, vec!["Война и мир"]);
+        assert_eq!(book.encoding, encoding);
+        assert_eq!(
+            book.warning,
+            vec![format!(
+                "{WARNING_ENCODING_DETECTED}: {encoding} (declared: {declared})"
+            )]
+        );
+    };
+    let (cp1251, _, _) = encoding_rs::WINDOWS_1251.encode(fb2);
+    check(&cp1251, "windows-1251", "-");
+    let wrong = [br#"<?xml version="1.0" encoding="utf-8"?>"#, &cp1251[..]].concat();
+    check(&wrong, "windows-1251", "utf-8");
+    let wrong = [
+        br#"<?xml version="1.0" encoding="koi8-r"?>"#,
+        fb2.as_bytes(),
+    ]
+    .concat();
+    check(&wrong, "UTF-8", "koi8-r");
+}
+
+#[test]
+fn test_render_chapters() {
+    let fb2 = r##"<?xml version="1.0" encoding="utf-8"?><FictionBook xmlns:l="http://www.w3.org/1999/xlink"><description><title-info><book-title>Book</book-title></title-info></description>
+<body><title><p>Book</p></title><section id="c1"><title><p>One</p></title><p>see <a l:href="#n1">1</a></p><image l:href="#i1"/></section><section><title><p>Two</p></title><p>text</p></section></body>
+<body name="notes"><title><p>Notes</p></title><section id="n1"><p>note</p></section></body><binary id="i1" content-type="image/png">AAAA</binary></FictionBook>"##;
+    let book = Fb2BookFormat
+        .render_chapters(fb2.as_bytes(), "image/")
+        .unwrap();
+    assert_eq!(book.toc, vec!["Book", "One", "Two", "Notes"]);
+    assert!(book.content[1].contains(r#"<a href="?chapter=3#n1" class="note" id="_r0">"#));
+    assert!(book.content[3].contains(r#"<div class="body notes">"#));
+    assert!(book.content[3].contains(r#"<a href="?chapter=1#_r0" class="backlink">"#));
+    assert!(book.content[1].contains(r#"<img src="image/i1">"#));
+    assert!(book.content[0].contains(r#"class="description""#));
+    assert_eq!(
+        Fb2BookFormat.image(fb2.as_bytes(), "i1"),
+        Some(("image/png".to_string(), vec![0, 0, 0]))
+    );
 }
 
 fn is_base64(x: u8) -> bool {
@@ -823,25 +1314,44 @@ fn find_raw(needle: &[u8], haystack: &[u8]) -> Option<usize> {
         .position(|window| window == needle)
 }
 
-fn detect_xml_encoding(head: &[u8]) -> &'static Encoding {
-    let mut enc: &Encoding = UTF_8;
+/// encoding name from XML declaration
+fn declared_encoding(head: &[u8]) -> Option<&[u8]> {
+    let upto = if head.len() < 128 { head.len() } else { 128 };
+    let s = find_raw(b"encoding=\"", &head[0..upto])?;
+    let e = find_raw(b"\"", &head[s + 10..upto])?;
+    Some(&head[s + 10..s + 10 + e])
+}
+
+/// UTF-8 check tolerant to sample cut in the middle of char
+fn is_utf8(sample: &[u8]) -> bool {
+    match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// encoding by BOM, XML declaration and content; true if content contradicts declaration
+fn detect_xml_encoding(head: &[u8]) -> (&'static Encoding, bool) {
     if head.len() > 3 {
         match (head[0], head[1], head[2]) {
-            (0xEF, 0xBB, 0xBF) => enc = UTF_8, //BOM
-            (0xFF, 0xFE, _) => enc = UTF_16LE, //BOM
-            (0xFE, 0xFF, _) => enc = UTF_16BE, //BOM
-            (0x3C, 0x00, _) => enc = UTF_16LE, //<
-            (0x00, 0x3C, _) => enc = UTF_16BE, //<
-            _ => {
-                let upto = if head.len() < 128 { head.len() } else { 128 };
-                if let Some(s) = find_raw(b"encoding=\"", &head[0..upto]) {
-                    if let Some(e) = find_raw(b"\"", &head[s + 10..upto]) {
-                        let name = &head[s + 10..s + 10 + e];
-                        enc = Encoding::for_label(name).unwrap_or(UTF_8);
-                    }
-                }
-            }
+            (0xEF, 0xBB, 0xBF) => return (UTF_8, false), //BOM
+            (0xFF, 0xFE, _) => return (UTF_16LE, false), //BOM
+            (0x3C, 0x00, _) => return (UTF_16LE, false), //<
+            (0xFE, 0xFF, _) => return (UTF_16BE, false), //BOM
+            (0x00, 0x3C, _) => return (UTF_16BE, false), //<
+            _ => {}
+        }
+    }
+    let declared = declared_encoding(head).and_then(Encoding::for_label);
+    let sample = &head[..head.len().min(DETECT_SAMPLE_SIZE)];
+    let declared_utf8 = declared.is_none_or(|x| x == UTF_8);
+    if declared_utf8 && !is_utf8(sample) {
+        if let Some(enc) = charset::guess_cyrillic(sample) {
+            return (enc, true);
         }
+    } else if declared.is_some_and(|x| x.is_single_byte()) && !sample.is_ascii() && is_utf8(sample)
+    {
+        return (UTF_8, true);
     }
-    enc
+    (declared.unwrap_or(UTF_8), false)
 }
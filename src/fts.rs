@@ -4,10 +4,11 @@ use log::{debug, error};
 use rand::Rng;
 use regex::Regex;
 //use serde::Serialize;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::Path;
 use std::sync::Mutex;
-use tantivy::collector::{Count, FacetCollector, TopDocs};
+use std::time::SystemTime;
+use tantivy::collector::{Count, DocSetCollector, FacetCollector, TopDocs};
 use tantivy::query::{
     AllQuery, BooleanQuery, FuzzyTermQuery, Occur, Query, QueryParser, RegexQuery, TermQuery,
 };
@@ -16,17 +17,20 @@ use tantivy::schema::{
     Term, TextFieldIndexing, TextOptions, FAST, INDEXED, STORED, STRING,
 };
 use tantivy::tokenizer;
-use tantivy::{DocAddress, Order};
+use tantivy::{DocAddress, Order, Searcher};
 use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyError};
 
+use crate::container::ContainerKind;
 use crate::letter_replacer::LetterReplacer;
 use crate::mtime_checker::MtimeChecker;
 use crate::sort::LocalString;
 
 const MAX_MATCHES_BEFORE_ORDERING: usize = 10000;
+const FOUND_TEXT_CONTEXT: usize = 60; //chars around found text
 const SIMPLE_TOKENIZER_NAME: &str = "p_simple";
 const STEMMED_TOKENIZER_NAME: &str = "p_stemmed";
 pub const WHOLE_MARKER: &str = "WHOLE";
+pub const SCHEMA_VERSION: u32 = 4; //increment on every change in Fields::build or facet paths
 
 type Result<T> = tantivy::Result<T>;
 
@@ -86,6 +90,18 @@ pub enum OrderBy {
     Sequence,
 }
 
+/// phrase found in text of single book
+#[derive(Debug, serde::Serialize)]
+pub struct FoundText {
+    pub id: String, //paragraph id in rendered book
+    pub chapter: usize,
+    pub section: Option<String>,
+    pub title: Option<String>,
+    pub before: String,
+    pub text: String,
+    pub after: String,
+}
+
 pub enum IndexListDetails {
     Full,
     Compact,
@@ -99,6 +115,32 @@ pub enum IndexedFiles {
 
 pub type IndexedBooks = HashMap<String, IndexedFiles>; //zipfile->{filenames}
 
+/// documents copied by `BookWriter::copy_from`
+#[derive(Default, Debug)]
+pub struct CopyStats {
+    pub books: u64,
+    pub markers: u64,
+}
+
+/// index content and size summary
+#[derive(Default, Clone, serde::Serialize)]
+pub struct IndexStats {
+    pub book_count: u64,
+    pub text_length: u64, //sum of book lengths, chars
+    pub lang: BTreeMap<String, u64>,
+    pub genre: BTreeMap<String, u64>, //by category
+    pub archive: BTreeMap<String, u64>,
+    pub authors: usize,
+    pub sequences: usize,
+    pub with_cover: u64,
+    pub without_cover: u64,
+    pub with_annotation: u64,
+    pub without_annotation: u64,
+    pub segments: usize,
+    pub disk_size: BTreeMap<String, u64>, //index component -> bytes
+    pub field_size: BTreeMap<String, u64>, //field -> bytes of terms, postings, positions, norms
+}
+
 #[allow(dead_code)]
 pub struct BookWriter {
     schema: Schema,
@@ -117,6 +159,8 @@ pub struct BookReader {
     def_fields_stem: Vec<Field>,
     stemmed_field_for: HashMap<String, String>, //non-stemmed-field name -> stemmed-field name
     commit_checker: Mutex<MtimeChecker>,        //for index reload
+    last_reload: Mutex<SystemTime>,
+    stats_cache: Mutex<Option<(u64, IndexStats)>>, //index generation -> stats
 }
 
 impl Fields {
@@ -195,9 +239,72 @@ impl Fields {
     }
 }
 
+/// differences between schema of existing index and current schema
+pub fn check_schema<P: AsRef<Path>>(index_dir: P) -> Result<Vec<String>> {
+    let schema = Index::open_in_dir(index_dir.as_ref())?.schema();
+    let mut schema_builder = Schema::builder();
+    Fields::build(&mut schema_builder);
+    let expected = schema_builder.build();
+    let mut res = vec![];
+    for (_, entry) in expected.fields() {
+        let name = entry.name();
+        match schema.get_field(name) {
+            Ok(field) if schema.get_field_entry(field) == entry => (),
+            Ok(_) => res.push(format!("field '{name}' has different type or options")),
+            Err(_) => res.push(format!("field '{name}' not found")),
+        }
+    }
+    if let Err(e) = Fields::load(&schema) {
+        res.push(e.to_string());
+    }
+    Ok(res)
+}
+
+/// container kind by archive extension
+fn container_kind(zipfile: &str) -> String {
+    ContainerKind::from_filename(zipfile).map_or("unknown".into(), |x| x.to_string())
+}
+
+/// /file/zip/a.zip
+fn zip_facet(zipfile: &str) -> Facet {
+    Facet::from_path(["file", &container_kind(zipfile), zipfile])
+}
+
+/// filename is single facet segment even with '/': book in directory or in nested archive
 fn file_facet(zipfile: &str, filename: &str) -> Facet {
-    let path: String = format!("/file/{zipfile}/{filename}");
-    Facet::from_text(&path).unwrap()
+    Facet::from_path(["file", &container_kind(zipfile), zipfile, filename])
+}
+
+/// next level facets of all parents with document counts
+fn child_facets(searcher: &Searcher, parents: &[Facet]) -> Result<Vec<(Facet, u64)>> {
+    let mut facet_collector = FacetCollector::for_field("facet");
+    for f in parents {
+        facet_collector.add_facet(f.clone());
+    }
+    let counts = searcher.search(&AllQuery, &facet_collector)?;
+    let mut res = vec![];
+    for f in parents {
+        res.extend(counts.get(f.clone()).map(|(x, count)| (x.clone(), count)));
+    }
+    Ok(res)
+}
+
+/// all /file/{kind}/{zipfile} facets of index
+fn get_zip_facets(searcher: &Searcher) -> Result<Vec<Facet>> {
+    let kinds: Vec<Facet> = child_facets(searcher, &[Facet::from_path(["file"])])?
+        .into_iter()
+        .map(|(f, _)| f)
+        .collect();
+    Ok(child_facets(searcher, &kinds)?
+        .into_iter()
+        .map(|(f, _)| f)
+        .collect())
+}
+
+/// first letter/last name in proper case/full name
+fn author_facet(last_name: &str, full_name: &str) -> Facet {
+    let first = last_name.chars().take(1).collect::<String>();
+    Facet::from_path(["author", &first, last_name, full_name])
 }
 
 fn get_simple_tokenizer() -> tokenizer::TextAnalyzer {
@@ -293,6 +400,133 @@ impl BookWriter {
         Ok(())
     }
 
+    /// delete all books of archive and its WHOLE marker, commit is required
+    pub fn delete_zipfile(&mut self, zipfile: &str) -> Result<()> {
+        for facet in [
+            zip_facet(zipfile),
+            Facet::from_path([WHOLE_MARKER, zipfile]),
+        ] {
+            self.writer
+                .delete_term(Term::from_facet(self.fields.facet, &facet));
+        }
+        Ok(())
+    }
+
+    /// copy stored fields matched by name and type from index of other schema, derive sort keys, stems and facets again
+    pub fn copy_from<P: AsRef<Path>>(
+        &self,
+        old_index_dir: P,
+        genre_map: &crate::genre_map::GenreMap,
+    ) -> Result<CopyStats> {
+        let old = Index::open_in_dir(old_index_dir)?;
+        let old_schema = old.schema();
+        let mut mapping = vec![]; //old field -> new field
+        for (old_field, old_entry) in old_schema.fields() {
+            if !old_entry.is_stored() {
+                continue;
+            }
+            if let Ok(field) = self.schema.get_field(old_entry.name()) {
+                let entry = self.schema.get_field_entry(field);
+                if entry.is_stored()
+                    && entry.field_type().value_type() == old_entry.field_type().value_type()
+                {
+                    mapping.push((old_field, field));
+                }
+            }
+        }
+        let reader = old
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        let searcher = reader.searcher();
+        let mut res = CopyStats::default();
+        for segment in searcher.segment_readers() {
+            let store = segment.get_store_reader(1)?;
+            for old_doc in store.iter::<TantivyDocument>(segment.alive_bitset()) {
+                let old_doc = old_doc?;
+                let mut doc = TantivyDocument::default();
+                let mut file = None;
+                let mut genres = vec![];
+                for (old_field, field) in &mapping {
+                    for v in old_doc.get_all(*old_field) {
+                        match v {
+                            OwnedValue::Facet(f) if *field == self.fields.facet => {
+                                //keep marker, other facets are derived again
+                                match f.to_path().as_slice() {
+                                    [WHOLE_MARKER, ..] => doc.add_facet(*field, f.clone()),
+                                    //before schema 4 without container kind, before 3 nested names were split by '/'
+                                    ["file", zipfile, filename @ ..]
+                                        if ContainerKind::from_filename(zipfile).is_some() =>
+                                    {
+                                        file = Some(file_facet(zipfile, &filename.join("/")))
+                                    }
+                                    ["file", _kind, zipfile, filename] => {
+                                        file = Some(file_facet(zipfile, filename))
+                                    }
+                                    ["genre", _, code] => genres.push(code.to_string()),
+                                    _ => (),
+                                }
+                            }
+                            _ => doc.add_field_value(*field, v.clone()),
+                        }
+                    }
+                }
+                match file {
+                    Some(f) => {
+                        doc.add_facet(self.fields.facet, f);
+                        self.add_derived_fields(&mut doc);
+                        let authors = self.stored_author_names(&doc);
+                        self.add_facets(&mut doc, &genres, &authors, genre_map);
+                        res.books += 1;
+                    }
+                    None => res.markers += 1,
+                }
+                self.writer.add_document(doc)?;
+            }
+        }
+        Ok(res)
+    }
+
+    /// non-stored fields which add_book fills from stored ones
+    fn add_derived_fields(&self, doc: &mut TantivyDocument) {
+        let sort_keys = [
+            (self.fields.title, self.fields.sort_title),
+            (self.fields.author, self.fields.sort_author),
+            (self.fields.translator, self.fields.sort_translator),
+            (self.fields.sequence, self.fields.sort_sequence),
+        ];
+        for (field, sort_field) in sort_keys {
+            if let Some(v) = first_string(doc, field) {
+                doc.add_u64(sort_field, crate::sort::hash_desc(&v));
+            }
+        }
+        if self.use_stemmer {
+            for (field, stemmed_field) in [
+                (self.fields.title, self.fields.xtitle),
+                (self.fields.annotation, self.fields.xannotation),
+            ] {
+                let values: Vec<String> = doc
+                    .get_all(field)
+                    .filter_map(|v| match v {
+                        OwnedValue::Str(s) => Some(s.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                for v in values {
+                    doc.add_text(stemmed_field, v);
+                }
+            }
+        }
+    }
+
+    pub fn delete_book(&mut self, zipfile: &str, filename: &str) -> Result<()> {
+        self.writer.delete_term(Term::from_facet(
+            self.fields.facet,
+            &file_facet(zipfile, filename),
+        ));
+        Ok(())
+    }
+
     pub fn mark_zipfile_as_indexed(&self, zipfile: &str, count: u64) -> Result<()> {
         let mut doc = TantivyDocument::default();
         let facet = Facet::from_path(vec![WHOLE_MARKER, zipfile]);
@@ -302,16 +536,25 @@ impl BookWriter {
         Ok(())
     }
 
-    #[allow(clippy::cognitive_complexity)]
+    #[allow(clippy::too_many_arguments)]
     pub fn add_book(
         &self,
         zipfile: &str,
         filename: &str,
-        book: crate::types::Book,
+        mut book: crate::types::Book,
         genre_map: &crate::genre_map::GenreMap,
+        author_map: &crate::author_map::AuthorMap,
         body: bool,
         xbody: bool,
     ) -> Result<()> {
+        author_map.apply(&mut book);
+        //facet only for authors with last name
+        let authors: Vec<(String, String)> = book
+            .author
+            .iter()
+            .chain(&book.src_author)
+            .filter_map(|p| Some((p.last_name_normalized()?, p.to_string())))
+            .collect();
         let mut doc = TantivyDocument::default();
         doc.add_facet(self.fields.facet, file_facet(zipfile, filename)); //facet field is mandatory
         doc.add_text(self.fields.encoding, &book.encoding);
@@ -324,15 +567,9 @@ impl BookWriter {
                 doc.add_text(self.fields.lang, v)
             }
         }
-        for (i, v) in book.title.iter().enumerate() {
+        for v in &book.title {
             if !v.is_empty() {
                 doc.add_text(self.fields.title, v);
-                if self.use_stemmer {
-                    doc.add_text(self.fields.xtitle, v);
-                }
-                if i == 0 {
-                    doc.add_u64(self.fields.sort_title, crate::sort::hash_desc(v));
-                }
             }
         }
         for v in &book.date {
@@ -340,11 +577,9 @@ impl BookWriter {
                 doc.add_text(self.fields.date, v)
             }
         }
-        let mut genre_facet = vec![];
         let mut keyword = book.keyword.clone();
         for v in &book.genre {
             if !v.is_empty() {
-                genre_facet.push(format!("/genre/{}", genre_map.path_for(v)));
                 //if genre looks like word -> add it to keywords
                 if !v.contains('_') {
                     keyword.push(v.to_lowercase());
@@ -353,63 +588,28 @@ impl BookWriter {
                 keyword.push(genre_map.translate(v).to_lowercase());
             }
         }
-        if genre_facet.is_empty() {
-            genre_facet.push("/genre/misc/unknown".to_string());
-        }
-        genre_facet.sort();
-        genre_facet.dedup();
-        for v in genre_facet {
-            doc.add_facet(self.fields.facet, &v);
-        }
         keyword.sort();
         keyword.dedup();
         for v in keyword {
             if !v.is_empty() {
-                let path = format!("/kw/{v}");
-                doc.add_facet(self.fields.facet, &path);
                 doc.add_text(self.fields.keyword, &v);
             }
         }
-        for (i, v) in book.author.iter().enumerate() {
-            let t = &v.to_string();
-            if !t.is_empty() {
-                doc.add_text(self.fields.author, t);
-                if let Some(name) = &v.last_name_normalized() {
-                    let first = name.chars().take(1).collect::<String>();
-                    let path = format!("/author/{}/{}", &first, name); //first letter/last name in proper case
-                    doc.add_facet(self.fields.facet, &path);
-                }
-                if i == 0 {
-                    doc.add_u64(self.fields.sort_author, crate::sort::hash_desc(t));
-                }
-            }
-        }
-        for v in &book.src_author {
-            let t = &v.to_string();
-            if !t.is_empty() {
-                doc.add_text(self.fields.src_author, t);
-                if let Some(name) = &v.last_name_normalized() {
-                    let first = name.chars().next().unwrap_or('?');
-                    let path = format!("/author/{}/{}", &first, name); //first letter/last name in proper case
-                    doc.add_facet(self.fields.facet, &path);
-                }
-            }
-        }
-        for (i, v) in book.translator.iter().enumerate() {
-            let v = v.to_string();
-            if !v.is_empty() {
-                doc.add_text(self.fields.translator, &v);
-                if i == 0 {
-                    doc.add_u64(self.fields.sort_translator, crate::sort::hash_desc(&v));
+        for (field, persons) in [
+            (self.fields.author, &book.author),
+            (self.fields.src_author, &book.src_author),
+            (self.fields.translator, &book.translator),
+        ] {
+            for v in persons {
+                let t = v.to_string();
+                if !t.is_empty() {
+                    doc.add_text(field, &t);
                 }
             }
         }
-        for (i, v) in book.sequence.iter().enumerate() {
+        for v in &book.sequence {
             if !v.is_empty() {
                 doc.add_text(self.fields.sequence, v);
-                if i == 0 {
-                    doc.add_u64(self.fields.sort_sequence, crate::sort::hash_desc(v));
-                }
             }
         }
         for v in &book.seqnum {
@@ -418,9 +618,6 @@ impl BookWriter {
         if let Some(v) = &book.annotation {
             if !v.is_empty() {
                 doc.add_text(self.fields.annotation, v);
-                if self.use_stemmer {
-                    doc.add_text(self.fields.xannotation, v);
-                }
             }
         }
         if let Some(text) = &book.body {
@@ -435,10 +632,69 @@ impl BookWriter {
         if let Some(raw) = book.cover_image {
             doc.add_text(self.fields.cover_image, base64engine.encode(raw));
         }
+        self.add_derived_fields(&mut doc);
+        self.add_facets(&mut doc, &book.genre, &authors, genre_map);
         self.writer.add_document(doc)?;
         Ok(())
     }
 
+    /// genre, keyword and author facets: from genre codes, stored keywords and (last name, full name) of authors
+    fn add_facets(
+        &self,
+        doc: &mut TantivyDocument,
+        genres: &[String],
+        authors: &[(String, String)],
+        genre_map: &crate::genre_map::GenreMap,
+    ) {
+        let mut facets: Vec<String> = genres
+            .iter()
+            .filter(|v| !v.is_empty())
+            .map(|v| format!("/genre/{}", genre_map.path_for(v)))
+            .collect();
+        if facets.is_empty() {
+            facets.push("/genre/misc/unknown".to_string());
+        }
+        facets.sort();
+        facets.dedup();
+        for v in doc.get_all(self.fields.keyword) {
+            if let OwnedValue::Str(s) = v {
+                facets.push(format!("/kw/{s}"));
+            }
+        }
+        for v in facets {
+            doc.add_facet(self.fields.facet, &v);
+        }
+        let mut facets = vec![];
+        for (last_name, full_name) in authors {
+            let facet = author_facet(last_name, full_name);
+            if !facets.contains(&facet) {
+                facets.push(facet);
+            }
+        }
+        for f in facets {
+            doc.add_facet(self.fields.facet, f);
+        }
+    }
+
+    /// (last name, full name) of stored authors: parsed names are not stored, full name starts with last name
+    fn stored_author_names(&self, doc: &TantivyDocument) -> Vec<(String, String)> {
+        let mut res = vec![];
+        for field in [self.fields.author, self.fields.src_author] {
+            for v in doc.get_all(field) {
+                if let OwnedValue::Str(full_name) = v {
+                    let person = crate::types::Person {
+                        last_name: Some(full_name.to_string()),
+                        ..Default::default()
+                    };
+                    if let Some(name) = person.last_name_normalized() {
+                        res.push((name, full_name.to_string()));
+                    }
+                }
+            }
+        }
+        res
+    }
+
     pub fn commit(&mut self) -> Result<()> {
         let res = self.writer.commit().map(|_| ());
         #[cfg(not(target_os = "windows"))]
@@ -517,6 +773,15 @@ fn first_u64_value(doc: &TantivyDocument, field: Field) -> u64 {
         .unwrap_or(0)
 }
 
+fn text_head(s: &str, chars: usize) -> String {
+    s.chars().take(chars).collect()
+}
+
+fn text_tail(s: &str, chars: usize) -> String {
+    let n = s.chars().count();
+    s.chars().skip(n.saturating_sub(chars)).collect()
+}
+
 fn parse_fuzzy_pattern(pat: &str) -> (String, u8) {
     let distance = pat.matches('~').count();
     let word = pat.replace('~', "");
@@ -571,6 +836,8 @@ impl BookReader {
             def_fields_stem,
             stemmed_field_for,
             commit_checker,
+            last_reload: Mutex::new(SystemTime::now()),
+            stats_cache: Mutex::new(None),
         })
     }
 
@@ -579,7 +846,11 @@ impl BookReader {
     fn check_for_commit(&self) -> Result<()> {
         if let Ok(mut cc) = self.commit_checker.lock() {
             if cc.is_modified() {
-                self.reader.reload()
+                self.reader.reload()?;
+                if let Ok(mut t) = self.last_reload.lock() {
+                    *t = SystemTime::now();
+                }
+                Ok(())
             } else {
                 Ok(())
             }
@@ -625,16 +896,8 @@ impl BookReader {
             }
         }
         //collect files
-        let mut facet_collector = FacetCollector::for_field("facet");
-        let root_facet = Facet::from_path(vec!["file"]);
-        facet_collector.add_facet(root_facet.clone());
-        let facet_counts = searcher.search(&AllQuery, &facet_collector)?;
-        for (zip_facet, _) in facet_counts.get(root_facet) {
-            let path = zip_facet.to_path(); //['file',zipfile]
-            if path.len() < 2 {
-                continue;
-            }
-            let zipfile = path[1].to_string();
+        for zip_facet in &get_zip_facets(&searcher)? {
+            let zipfile = zip_facet.to_path()[2].to_string(); //['file',kind,zipfile]
             if res.contains_key(&zipfile) {
                 continue; //skip WHOLE marked zipfiles
             }
@@ -645,17 +908,64 @@ impl BookReader {
             facet_collector.add_facet(zip_facet.clone());
             let facet_counts = searcher.search(&query, &facet_collector)?;
             for (file_facet, _) in facet_counts.get(zip_facet.clone()) {
-                let path = file_facet.to_path(); //0='file',1=zipfile,2=filename
-                if path.len() < 3 {
+                let path = file_facet.to_path(); //0='file',1=kind,2=zipfile,3=filename
+                if path.len() < 4 {
                     continue;
                 }
-                hs.insert(path[2].to_owned());
+                hs.insert(path[3].to_owned());
             }
             res.insert(zipfile, IndexedFiles::List(hs));
         }
         Ok(res)
     }
 
+    /// WHOLE markers as (zipfile, books count in archive), including duplicates
+    pub fn get_whole_markers(&self) -> Result<Vec<(String, u64)>> {
+        self.check_for_commit()?;
+        let searcher = self.reader.searcher();
+        let term = Term::from_facet(self.fields.facet, &Facet::from_path(vec![WHOLE_MARKER]));
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+        let mut res = vec![];
+        for addr in searcher.search(&query, &DocSetCollector)? {
+            let doc: TantivyDocument = searcher.doc(addr)?;
+            for v in doc.get_all(self.fields.facet) {
+                if let OwnedValue::Facet(f) = v {
+                    if let [WHOLE_MARKER, zipfile] = f.to_path().as_slice() {
+                        res.push((
+                            zipfile.to_string(),
+                            first_u64_value(&doc, self.fields.length),
+                        ));
+                    }
+                }
+            }
+        }
+        res.sort();
+        Ok(res)
+    }
+
+    /// number of documents for every indexed file: zipfile->filename->count
+    pub fn get_file_counts(&self) -> Result<BTreeMap<String, BTreeMap<String, u64>>> {
+        self.check_for_commit()?;
+        let searcher = self.reader.searcher();
+        let mut res = BTreeMap::new();
+        for zip_facet in &get_zip_facets(&searcher)? {
+            let path = zip_facet.to_path(); //['file',kind,zipfile]
+            let term = Term::from_facet(self.fields.facet, zip_facet);
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            let mut facet_collector = FacetCollector::for_field("facet");
+            facet_collector.add_facet(zip_facet.clone());
+            let file_counts = searcher.search(&query, &facet_collector)?;
+            let files: &mut BTreeMap<String, u64> = res.entry(path[2].to_string()).or_default();
+            for (file_facet, count) in file_counts.get(zip_facet.clone()) {
+                let path = file_facet.to_path(); //0='file',1=kind,2=zipfile,3=filename
+                if path.len() > 3 {
+                    files.insert(path[3].to_string(), count);
+                }
+            }
+        }
+        Ok(res)
+    }
+
     pub fn count_all(&self) -> Result<usize> {
         self.check_for_commit()?;
         let searcher = self.reader.searcher();
@@ -663,6 +973,145 @@ impl BookReader {
         Ok(cnt)
     }
 
+    /// number of books by each author name, from /author/{letter}/{last name}/{full name} facets
+    pub fn get_author_counts(&self) -> Result<HashMap<String, usize>> {
+        self.check_for_commit()?;
+        let searcher = self.reader.searcher();
+        let mut facets = vec![Facet::from_path(["author"])];
+        for _ in 0..2 {
+            facets = child_facets(&searcher, &facets)?
+                .into_iter()
+                .map(|(f, _)| f)
+                .collect();
+        }
+        let mut res = HashMap::new();
+        for (f, count) in child_facets(&searcher, &facets)? {
+            if let Some(name) = f.to_path().last() {
+                *res.entry(name.to_string()).or_default() += count as usize;
+            }
+        }
+        Ok(res)
+    }
+
+    /// collect statistics reading all stored documents, cached until index reload
+    pub fn get_stats(&self) -> Result<IndexStats> {
+        self.check_for_commit()?;
+        let searcher = self.reader.searcher();
+        let generation = searcher.generation().generation_id();
+        //lock for whole collection, concurrent requests wait for result instead of reading docs again
+        let mut cache = self.stats_cache.lock().unwrap_or_else(|e| e.into_inner());
+        match cache.as_ref() {
+            Some((g, stats)) if *g == generation => Ok(stats.clone()),
+            _ => {
+                let stats = self.collect_stats(&searcher)?;
+                *cache = Some((generation, stats.clone()));
+                Ok(stats)
+            }
+        }
+    }
+
+    fn collect_stats(&self, searcher: &Searcher) -> Result<IndexStats> {
+        let mut res = IndexStats {
+            segments: searcher.segment_readers().len(),
+            ..Default::default()
+        };
+        let mut authors = BTreeSet::new();
+        let mut sequences = BTreeSet::new();
+        for segment in searcher.segment_readers() {
+            let store = segment.get_store_reader(1)?;
+            for doc in store.iter::<TantivyDocument>(segment.alive_bitset()) {
+                let doc = doc?;
+                let mut archive = None;
+                let mut genres = BTreeSet::new();
+                for v in doc.get_all(self.fields.facet) {
+                    if let OwnedValue::Facet(f) = v {
+                        let path = f.to_path();
+                        match path.as_slice() {
+                            ["file", _, zipfile, ..] => archive = Some(zipfile.to_string()),
+                            ["genre", category, ..] => {
+                                genres.insert(category.to_string());
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                let archive = match archive {
+                    Some(x) => x,
+                    None => continue, //not a book: WHOLE marker
+                };
+                res.book_count += 1;
+                *res.archive.entry(archive).or_default() += 1;
+                for g in genres {
+                    *res.genre.entry(g).or_default() += 1;
+                }
+                let lang = first_string(&doc, self.fields.lang).unwrap_or_default();
+                *res.lang.entry(lang).or_default() += 1;
+                res.text_length += first_u64_value(&doc, self.fields.length);
+                for v in doc.get_all(self.fields.author) {
+                    if let OwnedValue::Str(s) = v {
+                        authors.insert(s.to_string());
+                    }
+                }
+                if let Some(s) = first_string(&doc, self.fields.sequence) {
+                    sequences.insert(s);
+                }
+                if doc.get_first(self.fields.cover_image).is_some() {
+                    res.with_cover += 1;
+                } else {
+                    res.without_cover += 1;
+                }
+                if doc.get_first(self.fields.annotation).is_some() {
+                    res.with_annotation += 1;
+                } else {
+                    res.without_annotation += 1;
+                }
+            }
+        }
+        res.authors = authors.len();
+        res.sequences = sequences.len();
+        let space = searcher.space_usage()?;
+        for seg in space.segments() {
+            for (name, size) in [
+                ("termdict", seg.termdict().total()),
+                ("postings", seg.postings().total()),
+                ("positions", seg.positions().total()),
+                ("fast_fields", seg.fast_fields().total()),
+                ("fieldnorms", seg.fieldnorms().total()),
+                ("store", seg.store().total()),
+                ("deletes", seg.deletes()),
+            ] {
+                *res.disk_size.entry(name.to_string()).or_default() += size.get_bytes();
+            }
+            for usage in [
+                seg.termdict(),
+                seg.postings(),
+                seg.positions(),
+                seg.fieldnorms(),
+            ] {
+                for (field, x) in usage.fields() {
+                    let name = self.schema.get_field_name(*field).to_string();
+                    *res.field_size.entry(name).or_default() += x.total().get_bytes();
+                }
+            }
+        }
+        res.disk_size
+            .insert("total".to_string(), space.total().get_bytes());
+        Ok(res)
+    }
+
+    /// number of segments in currently loaded index
+    pub fn segment_count(&self) -> usize {
+        self.reader.searcher().segment_readers().len()
+    }
+
+    /// time of index opening or last reload after commit
+    pub fn last_reload(&self) -> SystemTime {
+        self.last_reload
+            .lock()
+            .map(|t| *t)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
     pub fn search_as_docs(
         &self,
         query: &dyn Query,
@@ -786,21 +1235,14 @@ impl BookReader {
             let mut genre = Vec::new();
             for i in doc.get_all(self.fields.facet) {
                 if let OwnedValue::Facet(f) = i {
-                    let mut path = f.to_path().into_iter();
-                    let p0 = path.next();
-                    let p1 = path.next();
-                    let p2 = path.next();
-                    match p0 {
-                        Some("file") => {
-                            zipfile = p1.map(|x| x.to_owned()).unwrap_or_default();
-                            filename = p2.map(|x| x.to_owned()).unwrap_or_default();
-                        }
-                        Some("genre") => {
-                            //skip level 1: "/genre/sf/sf_horror" -> "sf_horror"
-                            if let Some(x) = p2 {
-                                genre.push(x.to_owned())
-                            }
+                    match f.to_path().as_slice() {
+                        //level 1 is container kind: "/file/zip/a.zip/1.fb2"
+                        ["file", _, z, name] => {
+                            zipfile = z.to_string();
+                            filename = name.to_string();
                         }
+                        //skip level 1: "/genre/sf/sf_horror" -> "sf_horror"
+                        ["genre", _, code, ..] => genre.push(code.to_string()),
                         _ => (),
                     }
                 }
@@ -856,6 +1298,63 @@ impl BookReader {
         Ok(facets)
     }
 
+    /// find phrase in paragraphs of single book using index tokenizers
+    pub fn find_in_text(
+        &self,
+        blocks: &[crate::types::TextBlock],
+        phrase: &str,
+        stemming: bool,
+        limit: usize,
+    ) -> Result<(usize, Vec<FoundText>)> {
+        let name = if stemming {
+            STEMMED_TOKENIZER_NAME
+        } else {
+            SIMPLE_TOKENIZER_NAME
+        };
+        let mut analyzer =
+            self.index.tokenizers().get(name).ok_or_else(|| {
+                TantivyError::InternalError(format!("tokenizer {name} not found"))
+            })?;
+        let mut words = Vec::<String>::new();
+        analyzer
+            .token_stream(phrase)
+            .process(&mut |t| words.push(t.text.clone()));
+        let mut total = 0;
+        let mut res = Vec::new();
+        if words.is_empty() {
+            return Ok((total, res));
+        }
+        for b in blocks {
+            let mut tokens = Vec::<(String, usize, usize)>::new(); //(word,from,to)
+            analyzer
+                .token_stream(&b.text)
+                .process(&mut |t| tokens.push((t.text.clone(), t.offset_from, t.offset_to)));
+            let mut i = 0;
+            while i + words.len() <= tokens.len() {
+                if words.iter().zip(&tokens[i..]).all(|(w, t)| *w == t.0) {
+                    total += 1;
+                    if res.len() < limit {
+                        let from = tokens[i].1;
+                        let to = tokens[i + words.len() - 1].2;
+                        res.push(FoundText {
+                            id: b.id.clone(),
+                            chapter: b.chapter,
+                            section: b.section.clone(),
+                            title: b.title.clone(),
+                            before: text_tail(&b.text[..from], FOUND_TEXT_CONTEXT),
+                            text: b.text[from..to].to_string(),
+                            after: text_head(&b.text[to..], FOUND_TEXT_CONTEXT),
+                        });
+                    }
+                    i += words.len();
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        Ok((total, res))
+    }
+
     pub fn parse_query(
         &self,
         query: &str,
@@ -956,3 +1455,161 @@ impl BookReader {
         Ok(Box::new(query))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author_map::AuthorMap;
+    use crate::fb2_parser::Fb2BookFormat;
+    use crate::genre_map::GenreMap;
+    use crate::types::BookFormat;
+
+    const BOOK: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<FictionBook><description><title-info><author><last-name>Иванов</last-name></author><book-title>Книга</book-title><lang>ru</lang></title-info></description>
+<body><section><p>Текст</p></section></body></FictionBook>"#;
+
+    #[test]
+    fn test_markers_and_file_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = BookWriter::new(dir.path(), "off", Some(1), 50_000_000).unwrap();
+        let genre_map = GenreMap::default();
+        for (zipfile, filename) in [("a.zip", "1.fb2"), ("a.zip", "1.fb2"), ("b.tar", "2.fb2")] {
+            let book = Fb2BookFormat {}
+                .parse(BOOK.as_bytes(), true, true, true)
+                .unwrap();
+            writer
+                .add_book(
+                    zipfile,
+                    filename,
+                    book,
+                    &genre_map,
+                    &AuthorMap::default(),
+                    true,
+                    false,
+                )
+                .unwrap();
+        }
+        writer.mark_zipfile_as_indexed("a.zip", 1).unwrap();
+        writer.commit().unwrap();
+        let reader = BookReader::new(dir.path(), "ru").unwrap();
+        assert_eq!(
+            reader.get_whole_markers().unwrap(),
+            vec![("a.zip".to_string(), 1)]
+        );
+        let counts = reader.get_file_counts().unwrap();
+        assert_eq!(counts["a.zip"]["1.fb2"], 2);
+        assert_eq!(counts["b.tar"]["2.fb2"], 1);
+        //container kind is recorded in file facet
+        let facet = reader.get_facet("/file", None, false, false, None).unwrap();
+        assert_eq!(
+            facet,
+            HashMap::from([("/file/tar".to_string(), 1), ("/file/zip".to_string(), 2)])
+        );
+        assert!(check_schema(dir.path()).unwrap().is_empty());
+        assert_eq!(reader.get_stats().unwrap().book_count, 3);
+
+        writer.delete_zipfile("a.zip").unwrap();
+        writer.commit().unwrap();
+        reader.reader.reload().unwrap();
+        assert_eq!(reader.get_stats().unwrap().book_count, 1); //cached stats are dropped on reload
+        let reader = BookReader::new(dir.path(), "ru").unwrap();
+        assert!(reader.get_whole_markers().unwrap().is_empty());
+        let counts = reader.get_file_counts().unwrap();
+        assert_eq!(counts.keys().collect::<Vec<_>>(), vec!["b.tar"]);
+    }
+
+    #[test]
+    fn test_author_facets() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = BookWriter::new(dir.path(), "off", Some(1), 50_000_000).unwrap();
+        let homer = BOOK.replace(
+            "<last-name>Иванов</last-name>",
+            "<first-name>Homer</first-name>",
+        );
+        for xml in [BOOK, homer.as_str()] {
+            let book = Fb2BookFormat {}
+                .parse(xml.as_bytes(), true, true, true)
+                .unwrap();
+            writer
+                .add_book(
+                    "a.zip",
+                    "1.fb2",
+                    book,
+                    &GenreMap::default(),
+                    &AuthorMap::default(),
+                    true,
+                    false,
+                )
+                .unwrap();
+        }
+        writer.commit().unwrap();
+        let reader = BookReader::new(dir.path(), "ru").unwrap();
+        //author without last name has no facet
+        let facet = reader
+            .get_facet("/author", None, false, false, None)
+            .unwrap();
+        assert_eq!(facet, HashMap::from([("/author/И".to_string(), 1)]));
+    }
+
+    #[test]
+    fn test_copy_from() {
+        let old_dir = tempfile::tempdir().unwrap();
+        let mut writer = BookWriter::new(old_dir.path(), "off", Some(1), 50_000_000).unwrap();
+        let book = Fb2BookFormat {}
+            .parse(BOOK.as_bytes(), true, true, true)
+            .unwrap();
+        writer
+            .add_book(
+                "a.zip",
+                "1.fb2",
+                book,
+                &GenreMap::default(),
+                &AuthorMap::default(),
+                true,
+                false,
+            )
+            .unwrap();
+        //book indexed with older schema: author facet without full name (1), nested name split by '/' (2)
+        let mut doc = TantivyDocument::default();
+        doc.add_facet(writer.fields.facet, "/file/b.zip/dir/2.fb2");
+        doc.add_facet(writer.fields.facet, "/author/П/Петров");
+        doc.add_text(writer.fields.author, "Петров Пётр");
+        doc.add_text(writer.fields.title, "Роман");
+        writer.writer.add_document(doc).unwrap();
+        writer.mark_zipfile_as_indexed("a.zip", 1).unwrap();
+        writer.commit().unwrap();
+
+        let new_dir = tempfile::tempdir().unwrap();
+        let mut writer = BookWriter::new(new_dir.path(), "ru", Some(1), 50_000_000).unwrap();
+        let stats = writer
+            .copy_from(old_dir.path(), &GenreMap::default())
+            .unwrap();
+        assert_eq!((stats.books, stats.markers), (2, 1));
+        writer.commit().unwrap();
+        let reader = BookReader::new(new_dir.path(), "ru").unwrap();
+        assert_eq!(
+            reader.get_whole_markers().unwrap(),
+            vec![("a.zip".to_string(), 1)]
+        );
+        assert_eq!(reader.get_file_counts().unwrap()["a.zip"]["1.fb2"], 1);
+        let query = reader.parse_query("книги", true, false).unwrap();
+        let docs = reader
+            .search_as_docs(&query, OrderBy::Title, 10, 0)
+            .unwrap();
+        assert_eq!(docs.len(), 1); //stemmed title and sort key derived from stored title
+        for (path, name) in [
+            ("/author/И/Иванов", "/author/И/Иванов/Иванов"),
+            ("/author/П/Петров", "/author/П/Петров/Петров Пётр"),
+        ] {
+            let facet = reader.get_facet(path, None, false, false, None).unwrap();
+            assert_eq!(facet, HashMap::from([(name.to_string(), 1)]));
+        }
+        let counts = reader.get_author_counts().unwrap();
+        let expected = [("Иванов", 1), ("Петров Пётр", 1)];
+        assert_eq!(counts, expected.map(|(n, c)| (n.to_string(), c)).into());
+        //facets are derived again, not copied
+        assert_eq!(reader.get_file_counts().unwrap()["b.zip"]["dir/2.fb2"], 1);
+        let facet = reader.get_facet("/genre/misc", None, false, false, None);
+        assert_eq!(facet.unwrap()["/genre/misc/unknown"], 2);
+    }
+}
@@ -7,14 +7,20 @@ use log::{debug, info, LevelFilter};
 use std::collections::HashMap;
 use std::path::Path;
 
+mod access_log;
 mod assets;
+mod author_map;
+mod charset;
+mod container;
 mod fb2_parser;
 mod fts;
 mod genre_map;
 mod img_resizer;
 mod letter_replacer;
+mod metrics;
 mod mtime_checker;
 mod sort;
+mod translit;
 mod types;
 #[macro_use]
 pub mod cmd;
@@ -37,7 +43,18 @@ fn main() -> ProcessResult {
         .init();
     debug!("{:?}", args);
 
-    let index_path = Path::new(&args.index_dir).to_path_buf();
+    let config = match Config::load(&args) {
+        Ok(x) => x,
+        Err(e) => return ProcessResult::ConfigError(e),
+    };
+    if let Some(Command::Config(c)) = &args.command {
+        match c.command {
+            ConfigCommand::Show => print!("{}", config.show()),
+        }
+        return ProcessResult::Ok;
+    }
+
+    let index_path = Path::new(&config.index_dir).to_path_buf();
     //auto-create index directory when indexing
     if !index_path.exists() {
         if let Some(cmd::Command::Index(_)) = args.command {
@@ -75,10 +92,20 @@ fn main() -> ProcessResult {
         }
     };
 
-    let index_settings = match IndexSettings::load(&args) {
+    //resumed indexing keeps settings of interrupted run
+    let indexing = matches!(
+        args.command,
+        Some(Command::Index(IndexArgs { resume: false, .. }))
+    );
+    let index_settings = match IndexSettings::load(&config, indexing) {
         Ok(x) => x,
         Err(e) => return ProcessResult::IndexError(e),
     };
+    if !matches!(args.command, Some(Command::Migrate(_))) {
+        if let Err(e) = index_settings.check_schema_version() {
+            return ProcessResult::IndexError(e);
+        }
+    }
 
     let mut books_path = Path::new(&index_settings.books_dir).to_path_buf();
     books_path = match books_path.canonicalize() {
@@ -102,14 +129,20 @@ fn main() -> ProcessResult {
         books_path,
         index_path,
         book_formats,
+        config,
         debug: args.debug,
+        writer_lock: Default::default(),
     };
 
     match args.command {
-        None => run_server(&ServerArgs::default(), app),
-        Some(Command::Server(args)) => run_server(&args, app),
+        None | Some(Command::Server(_)) => run_server(app),
         Some(Command::Index(args)) => run_index(&args, app),
         Some(Command::Query(args)) => run_query(&args, app),
         Some(Command::Facet(args)) => run_facet(&args, app),
+        Some(Command::Stats) => run_stats(app),
+        Some(Command::Verify(args)) => run_verify(&args, app),
+        Some(Command::Remove(args)) => run_remove(&args, app),
+        Some(Command::Migrate(args)) => run_migrate(&args, app),
+        Some(Command::Config(_)) => ProcessResult::Ok, //handled above
     }
 }
@@ -0,0 +1,246 @@
+//runtime counters exposed in Prometheus text format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+//histogram bucket upper bounds, seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()], //non-cumulative
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut acc = 0;
+        for (b, c) in BUCKETS.iter().zip(self.counts.iter()) {
+            acc += c;
+            let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{b}\"}} {acc}");
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+#[derive(Default)]
+struct RouteStats {
+    by_status: BTreeMap<u16, u64>,
+    duration: Histogram,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    routes: Mutex<BTreeMap<&'static str, RouteStats>>,
+    search: Mutex<Histogram>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+/// index state to include in metrics output
+pub struct IndexGauges {
+    pub doc_count: Option<usize>,
+    pub segment_count: usize,
+    pub last_reload: Duration, //since unix epoch
+}
+
+impl Metrics {
+    pub fn observe_request(&self, route: &'static str, status: u16, elapsed: Duration) {
+        if let Ok(mut routes) = self.routes.lock() {
+            let r = routes.entry(route).or_default();
+            *r.by_status.entry(status).or_default() += 1;
+            r.duration.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    pub fn observe_search(&self, elapsed: Duration) {
+        if let Ok(mut h) = self.search.lock() {
+            h.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// result of conditional request check: hit = client copy is still valid
+    pub fn observe_cache(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, index: &IndexGauges) -> String {
+        let mut out = String::new();
+        if let Ok(routes) = self.routes.lock() {
+            out.push_str("# HELP porcula_http_requests_total HTTP requests by route and status\n");
+            out.push_str("# TYPE porcula_http_requests_total counter\n");
+            for (route, r) in routes.iter() {
+                for (status, n) in r.by_status.iter() {
+                    let _ = writeln!(
+                        out,
+                        "porcula_http_requests_total{{route=\"{route}\",status=\"{status}\"}} {n}"
+                    );
+                }
+            }
+            out.push_str(
+                "# HELP porcula_http_request_duration_seconds HTTP request latency by route\n",
+            );
+            out.push_str("# TYPE porcula_http_request_duration_seconds histogram\n");
+            for (route, r) in routes.iter() {
+                r.duration.write(
+                    &mut out,
+                    "porcula_http_request_duration_seconds",
+                    &format!("route=\"{route}\""),
+                );
+            }
+        }
+        if let Ok(h) = self.search.lock() {
+            out.push_str("# HELP porcula_search_duration_seconds Full-text search latency\n");
+            out.push_str("# TYPE porcula_search_duration_seconds histogram\n");
+            h.write(&mut out, "porcula_search_duration_seconds", "");
+        }
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let misses = self.cache_misses.load(Ordering::Relaxed);
+        out.push_str("# HELP porcula_cache_requests_total Book requests checked against client cache validators\n");
+        out.push_str("# TYPE porcula_cache_requests_total counter\n");
+        let _ = writeln!(out, "porcula_cache_requests_total{{result=\"hit\"}} {hits}");
+        let _ = writeln!(
+            out,
+            "porcula_cache_requests_total{{result=\"miss\"}} {misses}"
+        );
+        out.push_str("# HELP porcula_cache_hit_ratio Share of book requests answered with 304 Not Modified\n");
+        out.push_str("# TYPE porcula_cache_hit_ratio gauge\n");
+        let ratio = if hits + misses > 0 {
+            hits as f64 / (hits + misses) as f64
+        } else {
+            0.0
+        };
+        let _ = writeln!(out, "porcula_cache_hit_ratio {ratio}");
+        if let Some(n) = index.doc_count {
+            out.push_str("# HELP porcula_index_documents Number of documents in index\n");
+            out.push_str("# TYPE porcula_index_documents gauge\n");
+            let _ = writeln!(out, "porcula_index_documents {n}");
+        }
+        out.push_str("# HELP porcula_index_segments Number of segments in loaded index\n");
+        out.push_str("# TYPE porcula_index_segments gauge\n");
+        let _ = writeln!(out, "porcula_index_segments {}", index.segment_count);
+        out.push_str(
+            "# HELP porcula_index_last_reload_timestamp_seconds Time of last index reload\n",
+        );
+        out.push_str("# TYPE porcula_index_last_reload_timestamp_seconds gauge\n");
+        let _ = writeln!(
+            out,
+            "porcula_index_last_reload_timestamp_seconds {}",
+            index.last_reload.as_secs()
+        );
+        out
+    }
+}
+
+/// low-cardinality route name for request path without base url
+pub fn route_label(path: &str) -> &'static str {
+    let mut parts = path.trim_start_matches('/').split('/');
+    match parts.next().unwrap_or_default() {
+        "book" => {
+            //archive/file/action or archive/nested.zip/file/action
+            let parts: Vec<&str> = parts.collect();
+            let nested = parts
+                .get(1)
+                .is_some_and(|x| crate::container::is_nested_archive(x));
+            match parts.get(if nested { 3 } else { 2 }).copied() {
+                None => "book_file",
+                Some("render") => "book_render",
+                Some("find") => "book_find",
+                Some("image") => "book_image",
+                Some("zip") => "book_zip",
+                Some(_) => "book_file",
+            }
+        }
+        "search" => "search",
+        "facet" => "facet",
+        "opds" => "opds",
+        "index" => "index_info",
+        "about" => "about",
+        "genre" => "genre",
+        "authors" => "authors",
+        "opensearch" => "opensearch",
+        "file_list" => "file_list",
+        "metrics" => "metrics",
+        "health" => "health",
+        x if x.contains('.') => "static",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(route_label("/book/a.zip/1.fb2"), "book_file");
+        assert_eq!(route_label("/book/a.zip/1.fb2/render"), "book_render");
+        assert_eq!(route_label("/book/a.zip/1.fb2/image/x.jpg"), "book_image");
+        assert_eq!(route_label("/book/a.zip/1.fb2/1.fb2"), "book_file");
+        assert_eq!(
+            route_label("/book/a.zip/1.fb2.zip/1.fb2/render"),
+            "book_render"
+        );
+        assert_eq!(route_label("/opds/author/A"), "opds");
+        assert_eq!(route_label("/home.html"), "static");
+        assert_eq!(route_label("/"), "other");
+    }
+
+    #[test]
+    fn test_render() {
+        let m = Metrics::default();
+        m.observe_request("search", 200, Duration::from_millis(20));
+        m.observe_request("search", 400, Duration::from_secs(20));
+        m.observe_cache(true);
+        let s = m.render(&IndexGauges {
+            doc_count: Some(5),
+            segment_count: 1,
+            last_reload: Duration::from_secs(100),
+        });
+        assert!(s.contains("porcula_http_requests_total{route=\"search\",status=\"200\"} 1\n"));
+        assert!(s.contains(
+            "porcula_http_request_duration_seconds_bucket{route=\"search\",le=\"0.025\"} 1\n"
+        ));
+        assert!(s.contains(
+            "porcula_http_request_duration_seconds_bucket{route=\"search\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(s.contains("porcula_cache_hit_ratio 1\n"));
+        assert!(s.contains("porcula_index_documents 5\n"));
+        assert!(s.contains("porcula_index_last_reload_timestamp_seconds 100\n"));
+    }
+}
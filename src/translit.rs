@@ -0,0 +1,75 @@
+/// Cyrillic to Latin transliteration for ASCII-only file names
+pub fn transliterate(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii() {
+            res.push(c);
+            continue;
+        }
+        let lower = c.to_lowercase().next().unwrap_or(c);
+        let t = match lower {
+            'а' => "a",
+            'б' => "b",
+            'в' => "v",
+            'г' => "g",
+            'д' => "d",
+            'е' | 'ё' | 'э' => "e",
+            'ж' => "zh",
+            'з' => "z",
+            'и' | 'і' => "i",
+            'й' => "y",
+            'к' => "k",
+            'л' => "l",
+            'м' => "m",
+            'н' => "n",
+            'о' => "o",
+            'п' => "p",
+            'р' => "r",
+            'с' => "s",
+            'т' => "t",
+            'у' | 'ў' => "u",
+            'ф' => "f",
+            'х' => "kh",
+            'ц' => "ts",
+            'ч' => "ch",
+            'ш' => "sh",
+            'щ' => "shch",
+            'ъ' | 'ь' => "",
+            'ы' => "y",
+            'ю' => "yu",
+            'я' => "ya",
+            'є' => "ye",
+            'ї' => "yi",
+            'ґ' => "g",
+            '«' | '»' | '„' | '“' | '”' => "\"",
+            '–' | '—' => "-",
+            _ => "_",
+        };
+        if lower != c {
+            //keep capital letter: "Ж" -> "Zh"
+            let mut chars = t.chars();
+            if let Some(first) = chars.next() {
+                res.push(first.to_ascii_uppercase());
+                res.push_str(chars.as_str());
+            }
+        } else {
+            res.push_str(t);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transliterate() {
+        assert_eq!(
+            transliterate("Стругацкий - Понедельник начинается в субботу"),
+            "Strugatskiy - Ponedelnik nachinaetsya v subbotu"
+        );
+        assert_eq!(transliterate("Щука Ёж"), "Shchuka Ezh");
+        assert_eq!(transliterate("日本 abc"), "__ abc");
+    }
+}
@@ -1,6 +1,11 @@
 use deepsize::DeepSizeOf;
 use std::collections::HashMap;
 
+//book warnings about text decoding, reported separately by `index --dry-run`
+pub const WARNING_MALFORMED_CHARS: &str = "malformed characters replaced";
+pub const WARNING_ENCODING_FALLBACK: &str = "unknown encoding, decoded as UTF-8";
+pub const WARNING_ENCODING_DETECTED: &str = "encoding detected by content";
+
 #[derive(Default, Debug, DeepSizeOf)]
 pub struct Person {
     pub first_name: Option<String>,
@@ -47,8 +52,30 @@ impl std::fmt::Display for ParserError {
     }
 }
 
+/// text of paragraph-like element of rendered book
+#[derive(Debug, Default)]
+pub struct TextBlock {
+    pub id: String,              //element id in rendered HTML
+    pub chapter: usize,          //chapter containing element, see render_chapters()
+    pub section: Option<String>, //id of nearest enclosing section
+    pub title: Option<String>,   //title of nearest enclosing section
+    pub text: String,
+}
+
 pub type ParserResult = std::result::Result<Book, ParserError>;
 pub type RenderResult = std::result::Result<(String, String), String>; //(title,content)
+pub type TextResult = std::result::Result<Vec<TextBlock>, String>;
+
+/// book rendered as sequence of separate HTML pages
+#[derive(Debug, Default)]
+pub struct Chapters {
+    pub title: String,
+    pub toc: Vec<String>,     //title of each chapter
+    pub content: Vec<String>, //HTML of each chapter
+}
+
+pub type ChaptersResult = std::result::Result<Chapters, String>;
+pub type ImageResult = Option<(String, Vec<u8>)>; //(content-type,data)
 
 pub trait BookFormat: Send + Sync {
     fn file_extension(&self) -> &'static str;
@@ -63,6 +90,15 @@ pub trait BookFormat: Send + Sync {
     ) -> ParserResult;
 
     fn render_to_html(&self, raw: &[u8]) -> RenderResult;
+
+    /// split book into chapters by top-level sections, images are referenced as `{image_url}{id}`
+    fn render_chapters(&self, raw: &[u8], image_url: &str) -> ChaptersResult;
+
+    /// get embedded image by id
+    fn image(&self, raw: &[u8], id: &str) -> ImageResult;
+
+    /// split book into text blocks addressable in result of render_to_html()
+    fn text_blocks(&self, raw: &[u8]) -> TextResult;
 }
 
 pub type BookFormats = HashMap<&'static str, Box<dyn BookFormat + Send + Sync>>;
@@ -76,6 +112,22 @@ pub fn person_to_string(p: &[Person]) -> String {
         .join(", ")
 }
 
+/// "Last First Middle [nick]" -> "Last F. M. [nick]"
+pub fn name_with_initials(full_name: &str) -> String {
+    let (names, nick) = match full_name.find('[') {
+        Some(i) => (&full_name[..i], Some(&full_name[i..])),
+        None => (full_name, None),
+    };
+    let mut words = names.split_whitespace();
+    let mut res: Vec<String> = words.next().map(|x| x.to_string()).into_iter().collect();
+    for w in words {
+        let initial = w.chars().next().unwrap_or_default();
+        res.push(format!("{initial}."));
+    }
+    res.extend(nick.map(|x| x.to_string()));
+    res.join(" ")
+}
+
 impl Display for Book {
     fn fmt(&self, f: &mut Formatter) -> Result {
         let seq: Vec<String> = self
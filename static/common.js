@@ -24,6 +24,18 @@ var storage = window.localStorage ? window.localStorage : {
     clear       : function() { return this._data = {}; }
 };
 
+//reading state of book saved by render.js, null if book was not opened
+var max_book_stored = 10;
+function load_book_state(book_id) {
+    for (var i=0; i<max_book_stored; i++) {
+        var s = storage.getItem("book"+i);
+        if (!s || s=='') continue;
+        var b = JSON.parse(s);
+        if (b.id==book_id) return b;
+    }
+    return null;
+}
+
 function size_pretty(s) {
     if (s < 1024) { return s + ""; }
     s = Math.trunc(s / 1024);
@@ -1,6 +1,7 @@
 ﻿let words = []; // [{word:String, count:Number, pos:Number}]
 let last_word_index = -1;
 const params = new URLSearchParams(window.location.search);
+const chapter = params.has('chapter') ? Number(params.get('chapter')) : null; //book split into chapters on server
 
 //word search
 if (params.has('find')) {
@@ -194,7 +195,8 @@ $(".title", $("div.body").first()).each(function (i, n) {
     if (!n.id) n.id = "title-" + (num++);
     titles.push([n, L, T]);
 });
-if (titles.length > 1) { //do not show empty TOC or one-line TOC
+var chapters = $('.toc ul.chapters').length > 0; //TOC of chapters made by server
+if (!chapters && titles.length > 1) { //do not show empty TOC or one-line TOC
     var h = '';
     var p = -1;
     for (var i = 0; i < titles.length; i++) {
@@ -209,7 +211,7 @@ if (titles.length > 1) { //do not show empty TOC or one-line TOC
     for (var x = p; x > -1; x--) { h += '</ul>'; }
     $(h).appendTo($('.toc'));
 }
-else {
+else if (!chapters) {
     $(".toc").remove();
 }
 
@@ -218,6 +220,7 @@ function closest_title() {
     var y = window.pageYOffset + window.innerHeight/2;
     var a = 0;
     var b = titles.length;
+    if (b == 0) return null;
     while ((b-a)>1) {
         var i = a+Math.floor((b-a)/2);
         var yi = titles[i][0].offsetTop;
@@ -250,6 +253,11 @@ function closest_para() {
 function show_toc() {
     var id = closest_title();
     $(".toc").show();
+    if (chapters) {
+        var c = $('.toc li.current').get(0);
+        if (c) c.scrollIntoView({ "block": "center" });
+        return;
+    }
     $('.toc li').removeClass("current");
     //highlight closest title
     if (id) {
@@ -281,6 +289,67 @@ $(".body").click(function () {
     $(".toc").hide();
 });
 
+//footnote is shown in popup near link
+$("a.note").click(function (e) {
+    var href = $(this).attr("href");
+    if (href.charAt(0) != '#') return; //note in another chapter
+    var t = document.getElementById(href.substring(1));
+    if (!t) return;
+    e.preventDefault();
+    $(".note_popup").remove();
+    var p = $(t).clone();
+    p.find("[id]").addBack().removeAttr("id");
+    $('<div class="note_popup"></div>').append(p.contents())
+        .css({ top: $(this).offset().top + this.offsetHeight })
+        .appendTo("body");
+});
+$(document).click(function (e) {
+    if (!$(e.target).closest("a.note, .note_popup").length) $(".note_popup").remove();
+});
+
+//full-text search inside book on server, words in all forms
+function find_in_book(phrase) {
+    if (!phrase) return;
+    $(".found .phrase").val(phrase);
+    $(".found ul").html('<li>...</li>');
+    $(".found").show();
+    $.getJSON("find", { q: phrase }, function (data) {
+        var h = '';
+        for (var i in data.matches) {
+            var m = data.matches[i];
+            var t = m.title ? '<span class="title">' + esc(m.title) + '</span> ' : '';
+            var href = (chapter !== null && m.chapter != chapter) ? '?chapter=' + m.chapter + '#' : '#';
+            h += '<li><a href="' + href + esc(m.id) + '">' + t + esc(m.before) + '<b>' + esc(m.text) + '</b>' + esc(m.after) + '</a></li>';
+        }
+        if (data.total > data.matches.length) {
+            h += '<li>... ' + data.matches.length + ' / ' + data.total + '</li>';
+        }
+        if (data.total == 0) {
+            h = '<li>-</li>';
+        }
+        $(".found ul").html(h);
+    });
+}
+$(".found").on("click", "a", function (e) {
+    var href = $(this).attr("href");
+    if (href.charAt(0) != '#') return; //found in another chapter
+    e.preventDefault();
+    var id = href.substring(1);
+    var t = document.getElementById(id);
+    if (!t) return;
+    set_auto_bookmark();
+    $('.found_hl').removeClass('found_hl');
+    $(t).addClass('found_hl');
+    t.scrollIntoView({ "block": "center" });
+    $(".found").hide();
+});
+$(".found .phrase").change(function () {
+    find_in_book($(this).val());
+});
+$(".found .hide").click(function () {
+    $(".found").hide();
+});
+
 //read progress
 var read_position = "";
 var last_top = 0;
@@ -293,13 +362,13 @@ setInterval(function () {
 }, 10000);
 
 //stored reading state: { book-id, last-read-date, position, bookmarks, current-bookmark, auto-bookmark }
-var max_book_stored = 10;
+//with chapters of each position: pc, mc, abc
 var book_stored = 0;
 var book_idx = null;
 var min_idx = 0;
 var min_d = '9999';
-var book_id = window.location.pathname.replace('/porcula/book/','').replace('/render','');
-var state = { id: book_id, p: "", m:[], c:0 };
+var book_id = window.location.pathname.replace(/^.*?\/book\//,'').replace('/render','');
+var state = { id: book_id, p: "", m:[], mc:[], c:0 };
 
 for (var i=0; i<max_book_stored; i++) { //LRU cache
   var s =  storage.getItem("book"+i);
@@ -320,7 +389,9 @@ if (book_idx==null) {
         book_idx = book_stored+1;
     }
 }
-if (state.p) {
+state.mc ||= [];
+//link to paragraph in chapter is not overridden by saved position
+if (state.p && (chapter === null || (state.pc == chapter && !window.location.hash))) {
     var c = $('#'+state.p).get();
     if (c.length>0) {
         c[0].scrollIntoView({ "block": "center" });
@@ -338,6 +409,11 @@ function save_state() {
     window.history.replaceState(null, "", "#" + id);
     state.d = (new Date()).toISOString();
     state.p = id;
+    state.pc = chapter;
+    store_state();
+}
+
+function store_state() {
     storage.setItem('book'+book_idx, JSON.stringify(state));
 }
 
@@ -345,15 +421,29 @@ function set_auto_bookmark() {
     var id = closest_para();
     if (!id) return;
     state.ab = id;
+    state.abc = chapter;
     var hash = '#'+id;
     window.history.pushState(null, '', hash);
 }
 
 function goto_auto_bookmark() {
     if (!state.ab) return;
-    var e = $('#'+state.ab).get(0);
-    if (!e) return;
+    goto_position(state.ab, state.abc);
+}
+
+//paragraph in other chapter is opened by URL, false if there is no such paragraph
+function goto_position(id, ch) {
+    if (chapter !== null && ch != null && ch != chapter) {
+        store_state();
+        var q = new URLSearchParams(params);
+        q.set('chapter', ch);
+        window.location.href = '?' + q + '#' + id;
+        return true;
+    }
+    var e = document.getElementById(id);
+    if (!e) return false;
     e.scrollIntoView({ "block": "center" });
+    return true;
 }
 
 function toggle_bookmark() {
@@ -385,14 +475,17 @@ function toggle_bookmark() {
         } else {
             state.m[i] = id;
         }
+        state.mc[i] = chapter;
         state.c = i;
         $('#'+id).addClass('bookmark bm'+i);
     }
     else { //remove
         state.m[i] = null;
+        state.mc[i] = null;
         var j = state.m.length;
         while (j>0 && state.m[j-1]==null) j--;
         state.m.length = j; //trim array
+        state.mc.length = j;
         state.c = (i>0) ? i-1 : 0;
         $('#'+id).removeClass('bookmark bm'+i);
     }
@@ -415,10 +508,8 @@ function prev_bookmark() {
     }
     if (!state.m[i]) return;
     state.c = i;
-    var e = $('#'+state.m[i]).get(0);
-    if (!e) return;
     set_auto_bookmark();
-    e.scrollIntoView({ "block": "center" });
+    if (!goto_position(state.m[i], state.mc[i])) return;
     save_state();  
 }
 
@@ -437,25 +528,22 @@ function next_bookmark() {
     }
     if (!state.m[i]) return;
     state.c = i;
-    var e = $('#'+state.m[i]).get(0);
-    if (!e) return;
     set_auto_bookmark();
-    e.scrollIntoView({ "block": "center" });
+    if (!goto_position(state.m[i], state.mc[i])) return;
     save_state();  
 }
 
 function goto_bookmark(n) {
     if (n<0 || n>(state.m.length-1)) return;
-    var e = $('#'+state.m[n]).get(0);
-    if (!e) return;
-    set_auto_bookmark();
-    e.scrollIntoView({ "block": "center" });
     state.c = n;
+    set_auto_bookmark();
+    if (!goto_position(state.m[n], state.mc[n])) return;
     save_state();  
 }
 
 window.addEventListener('keydown', function (e) {
     if (!e) e = window.event;
+    if ($(e.target).is('input') && e.code != 'Escape') return; //typing in search field
     switch (e.code) {
         case 'KeyT': case 84:
             if ($(".toc:visible").length) {
@@ -487,6 +575,15 @@ window.addEventListener('keydown', function (e) {
             break;
         case 'Escape': case 27:
             $(".toc").hide(); 
+            $(".found").hide();
+            $(".note_popup").remove();
+            break;
+        case 'KeyF': case 70:
+            if (!e.ctrlKey && !e.altKey) {
+                e.preventDefault();
+                $(".found").show();
+                $(".found .phrase").focus();
+            }
             break;
         case 'KeyB': case 66:
             if (!e.ctrlKey && !e.altKey) toggle_bookmark();